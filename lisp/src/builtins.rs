@@ -0,0 +1,668 @@
+use std::{cell::RefCell, cmp::Ordering, fmt, path::Path, rc::Rc};
+
+use crate::{condition::{self, Condition}, evaluator::Evaluator, number::Number, pair, parser::{CompilerError, SExpression}, printer, table::HashTable};
+
+// number of arguments accepted by a procedure
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Arity {
+    Exact(usize),
+    AtLeast(usize),
+    // optional arguments, from min to max
+    Between(usize, usize),
+}
+
+impl Arity {
+    pub fn accepts(&self, n: usize) -> bool {
+        match *self {
+            Arity::Exact(e) => n == e,
+            Arity::AtLeast(min) => n >= min,
+            Arity::Between(min, max) => (min..=max).contains(&n),
+        }
+    }
+}
+
+impl fmt::Display for Arity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Arity::Exact(n) => write!(f, "{n}"),
+            Arity::AtLeast(n) => write!(f, "at least {n}"),
+            Arity::Between(min, max) => write!(f, "{min} to {max}"),
+        }
+    }
+}
+
+// native function receives already evaluated arguments, with arity already checked
+pub type NativeFn = dyn Fn(&mut Evaluator, Vec<SExpression>) -> Result<SExpression, CompilerError>;
+
+#[derive(Clone)]
+pub struct Builtin {
+    pub name: String,
+    pub arity: Arity,
+    fun: Rc<NativeFn>,
+}
+
+impl Builtin {
+    pub fn new(name: &str, arity: Arity, fun: impl Fn(&mut Evaluator, Vec<SExpression>) -> Result<SExpression, CompilerError> + 'static) -> Self {
+        Self { name: name.to_string(), arity, fun: Rc::new(fun) }
+    }
+
+    pub fn call(&self, evaluator: &mut Evaluator, args: Vec<SExpression>) -> Result<SExpression, CompilerError> {
+        if !self.arity.accepts(args.len()) {
+            return Err(arity_mismatch(&self.name, self.arity, args.len()));
+        }
+        (self.fun)(evaluator, args)
+    }
+}
+
+impl fmt::Debug for Builtin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Builtin")
+            .field("name", &self.name)
+            .field("arity", &self.arity)
+            .finish()
+    }
+}
+
+impl PartialEq for Builtin {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name && Rc::ptr_eq(&self.fun, &other.fun)
+    }
+}
+
+pub fn arity_mismatch(name: &str, expected: Arity, got: usize) -> CompilerError {
+    CompilerError::ArityMismatch { name: name.to_string(), expected, got }
+}
+
+// only false is false, every other value counts as true
+pub fn is_truthy(e: &SExpression) -> bool {
+    !matches!(e, SExpression::Boolean(false))
+}
+
+pub fn std_lib() -> Vec<Builtin> {
+    vec![
+        Builtin::new("+", Arity::AtLeast(0), add),
+        Builtin::new("-", Arity::AtLeast(1), subtract),
+        Builtin::new("*", Arity::AtLeast(0), multiply),
+        Builtin::new("/", Arity::AtLeast(1), divide),
+        Builtin::new("quotient", Arity::Exact(2), |_, args| integer_division(args, Number::quotient)),
+        Builtin::new("modulo", Arity::Exact(2), |_, args| integer_division(args, Number::modulo)),
+        Builtin::new("remainder", Arity::Exact(2), |_, args| integer_division(args, Number::remainder)),
+        Builtin::new("abs", Arity::Exact(1), abs),
+        Builtin::new("min", Arity::AtLeast(1), |_, args| extremum(args, Ordering::Less)),
+        Builtin::new("max", Arity::AtLeast(1), |_, args| extremum(args, Ordering::Greater)),
+        Builtin::new("<", Arity::AtLeast(1), |_, args| compare(args, Ordering::is_lt)),
+        Builtin::new("<=", Arity::AtLeast(1), |_, args| compare(args, Ordering::is_le)),
+        Builtin::new(">", Arity::AtLeast(1), |_, args| compare(args, Ordering::is_gt)),
+        Builtin::new(">=", Arity::AtLeast(1), |_, args| compare(args, Ordering::is_ge)),
+        Builtin::new("=", Arity::AtLeast(1), equal),
+        Builtin::new("!=", Arity::Exact(2), not_equal),
+        Builtin::new("not", Arity::Exact(1), not),
+        Builtin::new("eq?", Arity::Exact(2), |_, args| Ok(SExpression::Boolean(is_eqv(&args[0], &args[1])))),
+        Builtin::new("eqv?", Arity::Exact(2), |_, args| Ok(SExpression::Boolean(is_eqv(&args[0], &args[1])))),
+        Builtin::new("equal?", Arity::Exact(2), |_, args| Ok(SExpression::Boolean(is_equal(&args[0], &args[1])))),
+        Builtin::new("number?", Arity::Exact(1), |_, args| number_predicate(args, |_| true)),
+        Builtin::new("integer?", Arity::Exact(1), |_, args| number_predicate(args, Number::is_integer)),
+        Builtin::new("rational?", Arity::Exact(1), |_, args| number_predicate(args, |n| n.is_exact() || n.to_f64().is_finite())),
+        Builtin::new("real?", Arity::Exact(1), |_, args| number_predicate(args, |_| true)),
+        Builtin::new("exact?", Arity::Exact(1), |_, args| Ok(SExpression::Boolean(number(args)?.is_exact()))),
+        Builtin::new("inexact?", Arity::Exact(1), |_, args| Ok(SExpression::Boolean(!number(args)?.is_exact()))),
+        Builtin::new("nan?", Arity::Exact(1), |_, args| Ok(SExpression::Boolean(number(args)?.to_f64().is_nan()))),
+        Builtin::new("zero?", Arity::Exact(1), |_, args| Ok(SExpression::Boolean(number(args)?.is_zero()))),
+        Builtin::new("positive?", Arity::Exact(1), |_, args| Ok(SExpression::Boolean(number(args)? > Number::Int(0)))),
+        Builtin::new("negative?", Arity::Exact(1), |_, args| Ok(SExpression::Boolean(number(args)? < Number::Int(0)))),
+        Builtin::new("odd?", Arity::Exact(1), |_, args| integer_predicate(args, true)),
+        Builtin::new("even?", Arity::Exact(1), |_, args| integer_predicate(args, false)),
+        Builtin::new("exact->inexact", Arity::Exact(1), |_, args| number_function(args, Number::to_inexact)),
+        Builtin::new("inexact", Arity::Exact(1), |_, args| number_function(args, Number::to_inexact)),
+        Builtin::new("inexact->exact", Arity::Exact(1), inexact_to_exact),
+        Builtin::new("exact", Arity::Exact(1), inexact_to_exact),
+        Builtin::new("floor", Arity::Exact(1), |_, args| number_function(args, Number::floor)),
+        Builtin::new("ceiling", Arity::Exact(1), |_, args| number_function(args, Number::ceiling)),
+        Builtin::new("round", Arity::Exact(1), |_, args| number_function(args, Number::round)),
+        Builtin::new("truncate", Arity::Exact(1), |_, args| number_function(args, Number::truncate)),
+        Builtin::new("numerator", Arity::Exact(1), |_, args| number_function(args, Number::numerator)),
+        Builtin::new("denominator", Arity::Exact(1), |_, args| number_function(args, Number::denominator)),
+        Builtin::new("sqrt", Arity::Exact(1), |_, args| number_function(args, Number::sqrt)),
+        Builtin::new("expt", Arity::Exact(2), allocating(power, expt)),
+        Builtin::new("cons", Arity::Exact(2), allocating(|_| 1, cons)),
+        Builtin::new("car", Arity::Exact(1), car),
+        Builtin::new("cdr", Arity::Exact(1), cdr),
+        Builtin::new("list", Arity::AtLeast(0), allocating(|args| args.len(), |_, args| Ok(pair::list(args)))),
+        Builtin::new("null?", Arity::Exact(1), |_, args| Ok(SExpression::Boolean(matches!(&args[0], SExpression::List(v) if v.is_empty())))),
+        Builtin::new("pair?", Arity::Exact(1), |_, args| Ok(SExpression::Boolean(pair::is_pair(&args[0])))),
+        Builtin::new("length", Arity::Exact(1), length),
+        Builtin::new("append", Arity::AtLeast(0), allocating(appended, append)),
+        Builtin::new("reverse", Arity::Exact(1), allocating(|args| extent(&args[0]), reverse)),
+        Builtin::new("string-append", Arity::AtLeast(0), allocating(|args| args.iter().map(extent).sum(), |_, args| Ok(SExpression::String(strings(args)?.concat().into())))),
+        Builtin::new("string-length", Arity::Exact(1), |_, args| Ok(SExpression::Number(Number::Int(strings(args)?[0].chars().count() as i64)))),
+        Builtin::new("substring", Arity::Exact(3), allocating(|args| extent(&args[0]), substring)),
+        Builtin::new("string->number", Arity::Exact(1), |_, args| Ok(match Number::parse(&strings(args)?[0]) {
+            Some(n) => SExpression::Number(n),
+            None => SExpression::Boolean(false),
+        })),
+        Builtin::new("number->string", Arity::Exact(1), allocating(digits, |_, args| Ok(SExpression::String(number(args)?.to_string().into())))),
+        Builtin::new("string-split", Arity::Exact(2), allocating(|args| 2 * extent(&args[0]) + 1, string_split)),
+        Builtin::new("string=?", Arity::AtLeast(1), |_, args| Ok(SExpression::Boolean(strings(args)?.windows(2).all(|w| w[0] == w[1])))),
+        Builtin::new("string-ref", Arity::Exact(2), string_ref),
+        Builtin::new("string->list", Arity::Exact(1), allocating(|args| extent(&args[0]), |_, args| Ok(pair::list(strings(args)?[0].chars().map(SExpression::Char).collect::<Vec<_>>())))),
+        Builtin::new("list->string", Arity::Exact(1), allocating(|args| extent(&args[0]), list_to_string)),
+        Builtin::new("char?", Arity::Exact(1), |_, args| Ok(SExpression::Boolean(matches!(&args[0], SExpression::Char(_))))),
+        Builtin::new("char->integer", Arity::Exact(1), |_, args| Ok(SExpression::Number(Number::Int(chars(args)?[0] as i64)))),
+        Builtin::new("integer->char", Arity::Exact(1), integer_to_char),
+        Builtin::new("char=?", Arity::AtLeast(1), |_, args| Ok(SExpression::Boolean(chars(args)?.windows(2).all(|w| w[0] == w[1])))),
+        Builtin::new("char<?", Arity::AtLeast(1), |_, args| Ok(SExpression::Boolean(chars(args)?.windows(2).all(|w| w[0] < w[1])))),
+        Builtin::new("char>?", Arity::AtLeast(1), |_, args| Ok(SExpression::Boolean(chars(args)?.windows(2).all(|w| w[0] > w[1])))),
+        Builtin::new("char-upcase", Arity::Exact(1), |_, args| Ok(SExpression::Char(chars(args)?[0].to_uppercase().next().unwrap()))),
+        Builtin::new("char-downcase", Arity::Exact(1), |_, args| Ok(SExpression::Char(chars(args)?[0].to_lowercase().next().unwrap()))),
+        Builtin::new("char-alphabetic?", Arity::Exact(1), |_, args| Ok(SExpression::Boolean(chars(args)?[0].is_alphabetic()))),
+        Builtin::new("char-numeric?", Arity::Exact(1), |_, args| Ok(SExpression::Boolean(chars(args)?[0].is_numeric()))),
+        Builtin::new("char-whitespace?", Arity::Exact(1), |_, args| Ok(SExpression::Boolean(chars(args)?[0].is_whitespace()))),
+        Builtin::new("vector", Arity::AtLeast(0), allocating(|args| args.len(), |evaluator, args| Ok(evaluator.heap.vector(args)))),
+        Builtin::new("make-vector", Arity::Between(1, 2), allocating(|args| match args[0] {
+            SExpression::Number(Number::Int(n)) => usize::try_from(n).unwrap_or(0),
+            _ => 0,
+        }, make_vector)),
+        Builtin::new("vector?", Arity::Exact(1), |_, args| Ok(SExpression::Boolean(matches!(&args[0], SExpression::Vector(_))))),
+        Builtin::new("vector-length", Arity::Exact(1), |_, args| Ok(SExpression::Number(Number::Int(vector_arg(&args[0])?.borrow().len() as i64)))),
+        Builtin::new("vector-ref", Arity::Exact(2), vector_ref),
+        Builtin::new("vector-set!", Arity::Exact(3), vector_set),
+        Builtin::new("vector-fill!", Arity::Exact(2), vector_fill),
+        Builtin::new("vector->list", Arity::Exact(1), allocating(|args| extent(&args[0]), |_, args| Ok(pair::list(vector_arg(&args[0])?.borrow().clone())))),
+        Builtin::new("list->vector", Arity::Exact(1), allocating(|args| extent(&args[0]), |evaluator, mut args| Ok(evaluator.heap.vector(proper_list(args.pop().unwrap())?)))),
+        Builtin::new("make-hash-table", Arity::Exact(0), |evaluator, _| Ok(evaluator.heap.table())),
+        Builtin::new("hash-table?", Arity::Exact(1), |_, args| Ok(SExpression::Boolean(matches!(&args[0], SExpression::HashTable(_))))),
+        Builtin::new("hash-set!", Arity::Exact(3), hash_set),
+        Builtin::new("hash-ref", Arity::Between(2, 3), hash_ref),
+        Builtin::new("hash-contains?", Arity::Exact(2), |_, args| Ok(SExpression::Boolean(table(&args[0])?.borrow().get(&args[1])?.is_some()))),
+        Builtin::new("hash-remove!", Arity::Exact(2), |_, args| {
+            table(&args[0])?.borrow_mut().remove(&args[1])?;
+            Ok(SExpression::Void)
+        }),
+        Builtin::new("hash-count", Arity::Exact(1), |_, args| Ok(SExpression::Number(Number::Int(table(&args[0])?.borrow().len() as i64)))),
+        Builtin::new("hash-keys", Arity::Exact(1), allocating(|args| extent(&args[0]), |_, args| Ok(pair::list(table(&args[0])?.borrow().entries().iter().map(|(k, _)| k.clone()).collect::<Vec<_>>())))),
+        Builtin::new("hash-values", Arity::Exact(1), allocating(|args| extent(&args[0]), |_, args| Ok(pair::list(table(&args[0])?.borrow().entries().iter().map(|(_, v)| v.clone()).collect::<Vec<_>>())))),
+        Builtin::new("hash->list", Arity::Exact(1), allocating(|args| 2 * extent(&args[0]), hash_to_list)),
+        Builtin::new("hash-for-each", Arity::Exact(2), hash_for_each),
+        Builtin::new("error", Arity::AtLeast(1), error),
+        Builtin::new("raise", Arity::Exact(1), |_, mut args| Err(condition::raise(args.pop().unwrap()))),
+        Builtin::new("catch", Arity::Exact(2), catch),
+        Builtin::new("error-object?", Arity::Exact(1), |_, args| Ok(SExpression::Boolean(matches!(&args[0], SExpression::Condition(_))))),
+        Builtin::new("error-object-message", Arity::Exact(1), |_, args| Ok(SExpression::String(error_object(args)?.message.as_str().into()))),
+        Builtin::new("error-object-irritants", Arity::Exact(1), |_, args| Ok(pair::list(error_object(args)?.irritants.clone()))),
+        Builtin::new("gc-stats", Arity::Exact(0), gc_stats),
+    ]
+}
+
+// builtins that touch the world outside of the evaluator, they can be disabled for untrusted code
+pub fn io_lib() -> Vec<Builtin> {
+    vec![
+        Builtin::new("display", Arity::Exact(1), display),
+        Builtin::new("write", Arity::Exact(1), write),
+        Builtin::new("newline", Arity::Exact(0), newline),
+        Builtin::new("load", Arity::Exact(1), |evaluator, args| {
+            evaluator.load(Path::new(&*strings(args)?[0]))?;
+            Ok(SExpression::Void)
+        }),
+    ]
+}
+
+type Native = fn(&mut Evaluator, Vec<SExpression>) -> Result<SExpression, CompilerError>;
+
+// what a builtin creates counts against the allocation limit: list cells, vector slots and string bytes, not the
+// values they hold. It is charged before the value is built, so a value over the limit is never allocated
+fn allocating(cost: fn(&[SExpression]) -> usize, fun: Native) -> impl Fn(&mut Evaluator, Vec<SExpression>) -> Result<SExpression, CompilerError> {
+    move |evaluator, args| {
+        evaluator.allocate(cost(&args))?;
+        fun(evaluator, args)
+    }
+}
+
+// elements or bytes of a value, at most what is built from it
+fn extent(e: &SExpression) -> usize {
+    match e {
+        SExpression::String(s) => s.len(),
+        SExpression::List(_) | SExpression::DottedList(..) | SExpression::Pair(_) => pair::iter(e).count(),
+        SExpression::Vector(v) => v.borrow().len(),
+        SExpression::HashTable(t) => t.borrow().len(),
+        _ => 0,
+    }
+}
+
+// the last list is shared, the others are copied
+fn appended(args: &[SExpression]) -> usize {
+    args.split_last().map_or(0, |(_, lists)| lists.iter().map(extent).sum())
+}
+
+fn digits(args: &[SExpression]) -> usize {
+    match &args[0] {
+        SExpression::Number(n) => n.bits() as usize / 3 + 2,
+        _ => 0,
+    }
+}
+
+// bytes of an exact power, every bit of the base past the first one is repeated for the exponent
+fn power(args: &[SExpression]) -> usize {
+    match (&args[0], &args[1]) {
+        (SExpression::Number(base), SExpression::Number(Number::Int(e))) => {
+            usize::try_from(base.bits().saturating_sub(1).saturating_mul(e.unsigned_abs()) / 8).unwrap_or(usize::MAX)
+        },
+        _ => 0,
+    }
+}
+
+fn numbers(args: Vec<SExpression>) -> Result<Vec<Number>, CompilerError> {
+    args.into_iter()
+        .map(|a| match a {
+            SExpression::Number(n) => Ok(n),
+            other => Err(CompilerError::InvalidList(other)),
+        })
+        .collect()
+}
+
+fn number(args: Vec<SExpression>) -> Result<Number, CompilerError> {
+    Ok(numbers(args)?.swap_remove(0))
+}
+
+fn integers(args: Vec<SExpression>) -> Result<Vec<Number>, CompilerError> {
+    numbers(args)?
+        .into_iter()
+        .map(|n| if n.is_integer() { Ok(n) } else { Err(CompilerError::InvalidList(SExpression::Number(n))) })
+        .collect()
+}
+
+fn checked_div(a: &Number, b: &Number) -> Result<Number, CompilerError> {
+    a.div(b).ok_or(CompilerError::DivisionByZero)
+}
+
+fn add(_: &mut Evaluator, args: Vec<SExpression>) -> Result<SExpression, CompilerError> {
+    Ok(SExpression::Number(numbers(args)?.iter().fold(Number::Int(0), |acc, n| acc.add(n))))
+}
+
+fn multiply(_: &mut Evaluator, args: Vec<SExpression>) -> Result<SExpression, CompilerError> {
+    Ok(SExpression::Number(numbers(args)?.iter().fold(Number::Int(1), |acc, n| acc.mul(n))))
+}
+
+// (- x) is a negation, otherwise subtracts the rest from the first one
+fn subtract(_: &mut Evaluator, args: Vec<SExpression>) -> Result<SExpression, CompilerError> {
+    let nums = numbers(args)?;
+    match nums.as_slice() {
+        [n] => Ok(SExpression::Number(n.neg())),
+        [first, rest @ ..] => Ok(SExpression::Number(rest.iter().fold(first.clone(), |acc, n| acc.sub(n)))),
+        [] => unreachable!("arity is checked before the call"),
+    }
+}
+
+// (/ x) is an inverse, otherwise divides the first one by the rest. Exact numbers give exact results
+fn divide(_: &mut Evaluator, args: Vec<SExpression>) -> Result<SExpression, CompilerError> {
+    let nums = numbers(args)?;
+    match nums.as_slice() {
+        [n] => Ok(SExpression::Number(checked_div(&Number::Int(1), n)?)),
+        [first, rest @ ..] => Ok(SExpression::Number(rest.iter().try_fold(first.clone(), |acc, n| checked_div(&acc, n))?)),
+        [] => unreachable!("arity is checked before the call"),
+    }
+}
+
+// integer division, the arguments are checked before calling op
+fn integer_division(args: Vec<SExpression>, op: fn(&Number, &Number) -> Option<Number>) -> Result<SExpression, CompilerError> {
+    let nums = integers(args)?;
+    op(&nums[0], &nums[1]).map(SExpression::Number).ok_or(CompilerError::DivisionByZero)
+}
+
+fn abs(_: &mut Evaluator, args: Vec<SExpression>) -> Result<SExpression, CompilerError> {
+    Ok(SExpression::Number(number(args)?.abs()))
+}
+
+// the result is inexact if any of the arguments is
+fn extremum(args: Vec<SExpression>, pick: Ordering) -> Result<SExpression, CompilerError> {
+    let nums = numbers(args)?;
+    let exact = nums.iter().all(Number::is_exact);
+    let mut result = nums[0].clone();
+    for n in &nums[1..] {
+        if n.partial_cmp(&result) == Some(pick) {
+            result = n.clone();
+        }
+    }
+    Ok(SExpression::Number(if exact { result } else { result.to_inexact() }))
+}
+
+// (< a b c) holds if every adjacent pair holds
+fn compare(args: Vec<SExpression>, op: fn(Ordering) -> bool) -> Result<SExpression, CompilerError> {
+    let nums = numbers(args)?;
+    Ok(SExpression::Boolean(nums.windows(2).all(|w| w[0].partial_cmp(&w[1]).is_some_and(op))))
+}
+
+fn number_predicate(args: Vec<SExpression>, test: fn(&Number) -> bool) -> Result<SExpression, CompilerError> {
+    Ok(SExpression::Boolean(matches!(&args[0], SExpression::Number(n) if test(n))))
+}
+
+fn number_function(args: Vec<SExpression>, f: fn(&Number) -> Number) -> Result<SExpression, CompilerError> {
+    Ok(SExpression::Number(f(&number(args)?)))
+}
+
+fn integer_predicate(args: Vec<SExpression>, odd: bool) -> Result<SExpression, CompilerError> {
+    let n = integers(args)?.swap_remove(0);
+    let is_odd = n.remainder(&Number::Int(2)).is_some_and(|r| !r.is_zero());
+    Ok(SExpression::Boolean(is_odd == odd))
+}
+
+fn inexact_to_exact(_: &mut Evaluator, args: Vec<SExpression>) -> Result<SExpression, CompilerError> {
+    let n = number(args)?;
+    n.to_exact().map(SExpression::Number).ok_or(CompilerError::InvalidList(SExpression::Number(n)))
+}
+
+fn expt(_: &mut Evaluator, args: Vec<SExpression>) -> Result<SExpression, CompilerError> {
+    let nums = numbers(args)?;
+    nums[0].expt(&nums[1]).map(SExpression::Number).ok_or(CompilerError::DivisionByZero)
+}
+
+// (= a b c) holds if every adjacent pair is equal. Booleans and strings can be compared too
+fn equal(_: &mut Evaluator, args: Vec<SExpression>) -> Result<SExpression, CompilerError> {
+    let invalid = || CompilerError::InvalidList(SExpression::List(args.clone()));
+    if !args.iter().all(|a| matches!(a, SExpression::Number(_) | SExpression::Boolean(_) | SExpression::String(_))) {
+        return Err(invalid());
+    }
+    let mut all = true;
+    for w in args.windows(2) {
+        all &= match (&w[0], &w[1]) {
+            (SExpression::Number(a), SExpression::Number(b)) => a.partial_cmp(b) == Some(Ordering::Equal),
+            (SExpression::Boolean(a), SExpression::Boolean(b)) => a == b,
+            (SExpression::String(a), SExpression::String(b)) => a == b,
+            _ => return Err(invalid()),
+        };
+    }
+    Ok(SExpression::Boolean(all))
+}
+
+fn not_equal(evaluator: &mut Evaluator, args: Vec<SExpression>) -> Result<SExpression, CompilerError> {
+    match equal(evaluator, args)? {
+        SExpression::Boolean(a) => Ok(SExpression::Boolean(!a)),
+        other => Err(CompilerError::InvalidList(other)),
+    }
+}
+
+fn not(_: &mut Evaluator, args: Vec<SExpression>) -> Result<SExpression, CompilerError> {
+    Ok(SExpression::Boolean(!is_truthy(&args[0])))
+}
+
+// eq? and eqv? are the same. Pairs, vectors, hash tables, conditions and procedures are the same object or not,
+// numbers have to be both exact or both inexact and strings are compared by their characters
+fn is_eqv(a: &SExpression, b: &SExpression) -> bool {
+    match (a, b) {
+        (SExpression::Pair(x), SExpression::Pair(y)) => Rc::ptr_eq(x, y),
+        (x, y) if pair::is_pair(x) || pair::is_pair(y) => false,
+        (SExpression::Vector(x), SExpression::Vector(y)) => Rc::ptr_eq(x, y),
+        (SExpression::HashTable(x), SExpression::HashTable(y)) => Rc::ptr_eq(x, y),
+        (SExpression::Condition(x), SExpression::Condition(y)) => Rc::ptr_eq(x, y),
+        _ => a == b,
+    }
+}
+
+// lists and vectors with equal elements are equal, everything else is compared like eqv?
+fn is_equal(a: &SExpression, b: &SExpression) -> bool {
+    match (a, b) {
+        (x, y) if pair::is_pair(x) && pair::is_pair(y) => pair::equal(x, y, is_equal),
+        (SExpression::Vector(x), SExpression::Vector(y)) => {
+            Rc::ptr_eq(x, y) || x.borrow().len() == y.borrow().len() && x.borrow().iter().zip(y.borrow().iter()).all(|(a, b)| is_equal(a, b))
+        },
+        _ => is_eqv(a, b),
+    }
+}
+
+fn proper_list(e: SExpression) -> Result<Vec<SExpression>, CompilerError> {
+    pair::elements(&e).ok_or(CompilerError::InvalidList(e))
+}
+
+// a new pair, the tail is shared with the second argument
+fn cons(_: &mut Evaluator, mut args: Vec<SExpression>) -> Result<SExpression, CompilerError> {
+    let tail = args.pop().unwrap();
+    let head = args.pop().unwrap();
+    Ok(pair::cons(head, tail))
+}
+
+fn car(_: &mut Evaluator, mut args: Vec<SExpression>) -> Result<SExpression, CompilerError> {
+    let e = args.pop().unwrap();
+    pair::split(&e).map(|(car, _)| car).ok_or(CompilerError::InvalidList(e))
+}
+
+fn cdr(_: &mut Evaluator, mut args: Vec<SExpression>) -> Result<SExpression, CompilerError> {
+    let e = args.pop().unwrap();
+    pair::split(&e).map(|(_, cdr)| cdr).ok_or(CompilerError::InvalidList(e))
+}
+
+fn length(_: &mut Evaluator, mut args: Vec<SExpression>) -> Result<SExpression, CompilerError> {
+    let v = proper_list(args.pop().unwrap())?;
+    Ok(SExpression::Number(Number::Int(v.len() as i64)))
+}
+
+// all arguments but the last one have to be proper lists, the last one becomes the tail
+fn append(_: &mut Evaluator, mut args: Vec<SExpression>) -> Result<SExpression, CompilerError> {
+    let mut result = args.pop().unwrap_or(SExpression::List(vec![]));
+    for list in args.into_iter().rev() {
+        for e in proper_list(list)?.into_iter().rev() {
+            result = pair::cons(e, result);
+        }
+    }
+    Ok(result)
+}
+
+fn reverse(_: &mut Evaluator, mut args: Vec<SExpression>) -> Result<SExpression, CompilerError> {
+    let v = proper_list(args.pop().unwrap())?;
+    Ok(v.into_iter().fold(SExpression::List(vec![]), |tail, e| pair::cons(e, tail)))
+}
+
+fn strings(args: Vec<SExpression>) -> Result<Vec<Rc<str>>, CompilerError> {
+    args.into_iter()
+        .map(|a| match a {
+            SExpression::String(s) => Ok(s),
+            other => Err(CompilerError::InvalidList(other)),
+        })
+        .collect()
+}
+
+// character index that fits in a string of the given length
+fn index(e: SExpression, len: usize) -> Result<usize, CompilerError> {
+    match &e {
+        SExpression::Number(Number::Int(i)) if (0..=len as i64).contains(i) => Ok(*i as usize),
+        _ => Err(CompilerError::InvalidList(e)),
+    }
+}
+
+// (substring s start end) - characters from start up to, but not including, end
+fn substring(_: &mut Evaluator, mut args: Vec<SExpression>) -> Result<SExpression, CompilerError> {
+    let (end, start) = (args.pop().unwrap(), args.pop().unwrap());
+    let s = strings(args)?.swap_remove(0);
+    let len = s.chars().count();
+    let (start, end) = (index(start, len)?, index(end, len)?);
+    if start > end {
+        return Err(CompilerError::InvalidList(SExpression::Number(Number::Int(start as i64))));
+    }
+    Ok(SExpression::String(s.chars().skip(start).take(end - start).collect::<String>().into()))
+}
+
+// (string-split "a,b" ",") is ("a" "b"), an empty separator splits into characters
+fn string_split(_: &mut Evaluator, args: Vec<SExpression>) -> Result<SExpression, CompilerError> {
+    let v = strings(args)?;
+    let parts: Vec<_> = if v[1].is_empty() {
+        v[0].chars().map(|c| SExpression::String(c.to_string().into())).collect()
+    } else {
+        v[0].split(&*v[1]).map(|s| SExpression::String(s.into())).collect()
+    };
+    Ok(pair::list(parts))
+}
+
+fn chars(args: Vec<SExpression>) -> Result<Vec<char>, CompilerError> {
+    args.into_iter()
+        .map(|a| match a {
+            SExpression::Char(c) => Ok(c),
+            other => Err(CompilerError::InvalidList(other)),
+        })
+        .collect()
+}
+
+// index of an element of a sequence of the given length
+fn element(e: SExpression, len: usize) -> Result<usize, CompilerError> {
+    match &e {
+        SExpression::Number(Number::Int(i)) if (0..len as i64).contains(i) => Ok(*i as usize),
+        _ => Err(CompilerError::InvalidList(e)),
+    }
+}
+
+fn string_ref(_: &mut Evaluator, mut args: Vec<SExpression>) -> Result<SExpression, CompilerError> {
+    let k = args.pop().unwrap();
+    let s = strings(args)?.swap_remove(0);
+    let i = element(k, s.chars().count())?;
+    Ok(SExpression::Char(s.chars().nth(i).unwrap()))
+}
+
+fn list_to_string(_: &mut Evaluator, mut args: Vec<SExpression>) -> Result<SExpression, CompilerError> {
+    Ok(SExpression::String(chars(proper_list(args.pop().unwrap())?)?.into_iter().collect::<String>().into()))
+}
+
+// only unicode scalar values are characters
+fn integer_to_char(_: &mut Evaluator, args: Vec<SExpression>) -> Result<SExpression, CompilerError> {
+    match &args[0] {
+        SExpression::Number(Number::Int(i)) => u32::try_from(*i).ok()
+            .and_then(char::from_u32)
+            .map(SExpression::Char)
+            .ok_or_else(|| CompilerError::InvalidList(args[0].clone())),
+        other => Err(CompilerError::InvalidList(other.clone())),
+    }
+}
+
+fn vector_arg(e: &SExpression) -> Result<&Rc<RefCell<Vec<SExpression>>>, CompilerError> {
+    match e {
+        SExpression::Vector(v) => Ok(v),
+        other => Err(CompilerError::InvalidList(other.clone())),
+    }
+}
+
+// (make-vector n fill) - the fill defaults to 0
+fn make_vector(evaluator: &mut Evaluator, mut args: Vec<SExpression>) -> Result<SExpression, CompilerError> {
+    let fill = if args.len() == 2 { args.pop().unwrap() } else { SExpression::Number(Number::Int(0)) };
+    match args.pop().unwrap() {
+        SExpression::Number(Number::Int(n)) if n >= 0 => Ok(evaluator.heap.vector(vec![fill; n as usize])),
+        other => Err(CompilerError::InvalidList(other)),
+    }
+}
+
+fn vector_ref(_: &mut Evaluator, mut args: Vec<SExpression>) -> Result<SExpression, CompilerError> {
+    let k = args.pop().unwrap();
+    let v = vector_arg(&args[0])?.borrow();
+    let i = element(k, v.len())?;
+    Ok(v[i].clone())
+}
+
+fn vector_set(_: &mut Evaluator, mut args: Vec<SExpression>) -> Result<SExpression, CompilerError> {
+    let (value, k) = (args.pop().unwrap(), args.pop().unwrap());
+    let mut v = vector_arg(&args[0])?.borrow_mut();
+    let i = element(k, v.len())?;
+    v[i] = value;
+    Ok(SExpression::Void)
+}
+
+fn vector_fill(_: &mut Evaluator, mut args: Vec<SExpression>) -> Result<SExpression, CompilerError> {
+    let fill = args.pop().unwrap();
+    vector_arg(&args[0])?.borrow_mut().fill(fill);
+    Ok(SExpression::Void)
+}
+
+fn table(e: &SExpression) -> Result<&Rc<RefCell<HashTable>>, CompilerError> {
+    match e {
+        SExpression::HashTable(t) => Ok(t),
+        other => Err(CompilerError::InvalidList(other.clone())),
+    }
+}
+
+// every new entry counts as one allocation
+fn hash_set(evaluator: &mut Evaluator, mut args: Vec<SExpression>) -> Result<SExpression, CompilerError> {
+    let (value, key) = (args.pop().unwrap(), args.pop().unwrap());
+    if table(&args[0])?.borrow_mut().insert(key, value)? {
+        evaluator.allocate(1)?;
+    }
+    Ok(SExpression::Void)
+}
+
+// (hash-ref table key default) - a missing key without a default is an error
+fn hash_ref(_: &mut Evaluator, mut args: Vec<SExpression>) -> Result<SExpression, CompilerError> {
+    let default = if args.len() == 3 { args.pop() } else { None };
+    let key = args.pop().unwrap();
+    let found = table(&args[0])?.borrow().get(&key)?.cloned();
+    found.or(default).ok_or(CompilerError::InvalidList(key))
+}
+
+// ((key . value)...)
+fn hash_to_list(_: &mut Evaluator, args: Vec<SExpression>) -> Result<SExpression, CompilerError> {
+    let entries = table(&args[0])?.borrow().entries().to_vec();
+    Ok(pair::list(entries.into_iter().map(|(k, v)| pair::cons(k, v)).collect::<Vec<_>>()))
+}
+
+// (hash-for-each table (lambda (key value) ...)) - the procedure may change the table, it sees the entries as they were
+fn hash_for_each(evaluator: &mut Evaluator, mut args: Vec<SExpression>) -> Result<SExpression, CompilerError> {
+    let procedure = args.pop().unwrap();
+    let entries = table(&args[0])?.borrow().entries().to_vec();
+    for (k, v) in entries {
+        evaluator.apply_procedure(procedure.clone(), vec![k, v])?;
+    }
+    Ok(SExpression::Void)
+}
+
+// (error "message" irritant...) raises a new condition
+fn error(_: &mut Evaluator, mut args: Vec<SExpression>) -> Result<SExpression, CompilerError> {
+    let irritants = args.split_off(1);
+    let message = strings(args)?.swap_remove(0);
+    Err(CompilerError::Raised(SExpression::Condition(Rc::new(Condition::new(&message, irritants)))))
+}
+
+// (catch thunk handler) calls the thunk, if it raises the handler is called with the raised value
+fn catch(evaluator: &mut Evaluator, mut args: Vec<SExpression>) -> Result<SExpression, CompilerError> {
+    let (handler, thunk) = (args.pop().unwrap(), args.pop().unwrap());
+    match evaluator.apply_procedure(thunk, vec![]) {
+        Ok(result) => Ok(result),
+        Err(error) => {
+            let value = condition::catch(error)?;
+            // the error was handled, later ones get their own context
+            evaluator.error_context = None;
+            evaluator.apply_procedure(handler, vec![value])
+        },
+    }
+}
+
+fn error_object(args: Vec<SExpression>) -> Result<Rc<Condition>, CompilerError> {
+    match args.into_iter().next().unwrap() {
+        SExpression::Condition(c) => Ok(c),
+        other => Err(CompilerError::InvalidList(other)),
+    }
+}
+
+// strings and characters are written as they are, everything else the same way as in the source
+// hash table with the number of collections, the objects in use and the ones freed by collections
+fn gc_stats(evaluator: &mut Evaluator, _: Vec<SExpression>) -> Result<SExpression, CompilerError> {
+    let stats = evaluator.gc_stats();
+    let result = evaluator.heap.table();
+    let t = table(&result)?;
+    for (key, n) in [("collections", stats.collections), ("live", stats.live), ("collected", stats.collected)] {
+        t.borrow_mut().insert(SExpression::Identifier(key.to_string()), SExpression::Number(Number::Int(n as i64)))?;
+    }
+    Ok(result)
+}
+
+// strings and characters without quotes, also inside lists and vectors
+fn display(evaluator: &mut Evaluator, args: Vec<SExpression>) -> Result<SExpression, CompilerError> {
+    output(evaluator, &printer::display(&args[0]))
+}
+
+// the value the way it's written in the source
+fn write(evaluator: &mut Evaluator, args: Vec<SExpression>) -> Result<SExpression, CompilerError> {
+    output(evaluator, &printer::write(&args[0]))
+}
+
+fn output(evaluator: &mut Evaluator, text: &str) -> Result<SExpression, CompilerError> {
+    // output errors (e.g. a closed pipe) are not errors of the program
+    let _ = write!(evaluator.output(), "{text}");
+    Ok(SExpression::Void)
+}
+
+fn newline(evaluator: &mut Evaluator, _: Vec<SExpression>) -> Result<SExpression, CompilerError> {
+    let _ = writeln!(evaluator.output());
+    Ok(SExpression::Void)
+}
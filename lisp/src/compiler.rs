@@ -0,0 +1,530 @@
+use std::{cell::OnceCell, rc::Rc};
+
+use crate::{evaluator::{split_do, split_let}, pair, parser::SExpression};
+
+// instructions of the stack machine. Indexes point into the tables of the chunk
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Op {
+    // push constants[i]
+    Const(usize),
+    // push the value of names[i]
+    Get(usize),
+    // pop a value and bind names[i] to it in the current scope
+    Define(usize),
+    // pop a value and assign it to the innermost binding of names[i]
+    Set(usize),
+    Pop,
+    Jump(usize),
+    // pop the condition, jump if it's false
+    JumpIfFalse(usize),
+    // keep the value and jump if it's false, otherwise pop it. Used by and
+    JumpIfFalseOrPop(usize),
+    // keep the value and jump if it's true, otherwise pop it. Used by or and cond
+    JumpIfTrueOrPop(usize),
+    // push whether the value on top of the stack is one of the datums in the list constants[i]. Used by case
+    Match(usize),
+    // push a lambda made from lambdas[i], closing over the current scope
+    Closure(usize),
+    // fail if the value on top of the stack can't be called
+    CheckCallable,
+    // pop n arguments and the procedure below them, push the result
+    Call(usize),
+    // like Call, but the current frame is replaced instead of growing the stack
+    TailCall(usize),
+    Return,
+    // new scope inside the current one, used by the let family
+    EnterScope,
+    // drop n scopes entered by EnterScope
+    LeaveScope(usize),
+    // malformed special form, fails with constants[i] as the invalid expression
+    Invalid(usize),
+}
+
+// compiled body of a lambda expression, shared by every closure created from it
+#[derive(Debug)]
+pub struct Template {
+    pub params: Vec<String>,
+    pub body: Rc<[SExpression]>,
+    pub code: Rc<OnceCell<Rc<Chunk>>>,
+}
+
+#[derive(Debug, Default)]
+pub struct Chunk {
+    pub ops: Vec<Op>,
+    pub constants: Vec<SExpression>,
+    pub names: Vec<String>,
+    pub lambdas: Vec<Template>,
+    // innermost list expression every op was compiled from, reported as the error context
+    pub sources: Vec<Option<usize>>,
+    pub lists: Vec<SExpression>,
+}
+
+impl Chunk {
+    pub fn source(&self, pc: usize) -> Option<&SExpression> {
+        self.sources.get(pc).copied().flatten().map(|i| &self.lists[i])
+    }
+}
+
+// compiles a top level expression
+pub fn compile(e: &SExpression) -> Chunk {
+    let mut compiler = Compiler::default();
+    compiler.expr(e, true);
+    compiler.emit(Op::Return);
+    compiler.chunk
+}
+
+// compiles the body of a lambda, the last expression is in tail position
+pub fn compile_body(body: &[SExpression]) -> Chunk {
+    let mut compiler = Compiler::default();
+    compiler.body(body, true);
+    compiler.emit(Op::Return);
+    compiler.chunk
+}
+
+// special forms are checked the same way the tree walker does it, but a malformed one
+// is only reported when it's reached, so both backends fail at the same point
+#[derive(Default)]
+struct Compiler {
+    chunk: Chunk,
+    source: Option<usize>,
+}
+
+impl Compiler {
+    fn emit(&mut self, op: Op) -> usize {
+        self.chunk.ops.push(op);
+        self.chunk.sources.push(self.source);
+        self.chunk.ops.len() - 1
+    }
+
+    // jump to the next op to be emitted
+    fn patch(&mut self, at: usize) {
+        let target = self.chunk.ops.len();
+        match &mut self.chunk.ops[at] {
+            Op::Jump(t) | Op::JumpIfFalse(t) | Op::JumpIfFalseOrPop(t) | Op::JumpIfTrueOrPop(t) => *t = target,
+            op => unreachable!("{op:?} is not a jump"),
+        }
+    }
+
+    fn constant(&mut self, e: SExpression) {
+        self.chunk.constants.push(e);
+        let i = self.chunk.constants.len() - 1;
+        self.emit(Op::Const(i));
+    }
+
+    fn name(&mut self, name: &str) -> usize {
+        match self.chunk.names.iter().position(|n| n == name) {
+            Some(i) => i,
+            None => {
+                self.chunk.names.push(name.to_string());
+                self.chunk.names.len() - 1
+            }
+        }
+    }
+
+    fn invalid(&mut self, e: &SExpression) {
+        self.chunk.constants.push(e.clone());
+        let i = self.chunk.constants.len() - 1;
+        self.emit(Op::Invalid(i));
+    }
+
+    fn expr(&mut self, e: &SExpression, tail: bool) {
+        match e {
+            SExpression::Identifier(id) => {
+                let i = self.name(id);
+                self.emit(Op::Get(i));
+            },
+            SExpression::DottedList(..) | SExpression::Pair(_) => self.invalid(e),
+            SExpression::List(_) => {
+                self.chunk.lists.push(e.clone());
+                let outer = self.source.replace(self.chunk.lists.len() - 1);
+                self.list(e, tail);
+                self.source = outer;
+            },
+            _ => self.constant(e.clone()),
+        }
+    }
+
+    fn list(&mut self, e: &SExpression, tail: bool) {
+        let v = match e {
+            SExpression::List(v) => v,
+            _ => return self.invalid(e),
+        };
+        let first = match v.first() {
+            Some(s) => s,
+            None => return self.constant(SExpression::Void),
+        };
+
+        match first {
+            SExpression::Identifier(id) if id == "quote" => match v.as_slice() {
+                [_, datum] => self.constant(pair::datum(datum)),
+                _ => self.invalid(e),
+            },
+            SExpression::Identifier(id) if id == "and" => self.and_or(&v[1..], true, tail),
+            SExpression::Identifier(id) if id == "or" => self.and_or(&v[1..], false, tail),
+            SExpression::Identifier(id) if id == "if" => self.if_expression(e, tail),
+            SExpression::Identifier(id) if id == "cond" => self.cond_expression(e, &v[1..], tail),
+            SExpression::Identifier(id) if id == "case" => self.case_expression(e, tail),
+            SExpression::Identifier(id) if id == "begin" => self.body(&v[1..], tail),
+            SExpression::Identifier(id) if id == "do" => self.do_expression(e, tail),
+            SExpression::Identifier(id) if id == "define" => self.store_expression(e),
+            SExpression::Identifier(id) if id == "set!" => self.set_expression(e),
+            SExpression::Identifier(id) if id == "lambda" => match v.as_slice() {
+                [_, SExpression::List(params), body @ ..] if !body.is_empty() => self.lambda(e, params, body),
+                _ => self.invalid(e),
+            },
+            SExpression::Identifier(id) if id == "let" => self.let_expression(e, tail),
+            SExpression::Identifier(id) if id == "letrec" => self.letrec_expression(e, tail),
+            // macros of the prelude call procedures they refer to directly
+            SExpression::Identifier(_) | SExpression::List(_) | SExpression::Lambda(_) | SExpression::Builtin(_) => {
+                self.expr(first, false);
+                self.emit(Op::CheckCallable);
+                for arg in &v[1..] {
+                    self.expr(arg, false);
+                }
+                self.emit(if tail { Op::TailCall(v.len() - 1) } else { Op::Call(v.len() - 1) });
+            },
+            _ => self.invalid(e),
+        }
+    }
+
+    // every expression but the last one is evaluated for its side effects
+    fn body(&mut self, body: &[SExpression], tail: bool) {
+        let (last, init) = match body.split_last() {
+            Some(s) => s,
+            None => return self.constant(SExpression::Void),
+        };
+        for e in init {
+            self.expr(e, false);
+            self.emit(Op::Pop);
+        }
+        self.expr(last, tail);
+    }
+
+    fn and_or(&mut self, args: &[SExpression], is_and: bool, tail: bool) {
+        let (last, init) = match args.split_last() {
+            Some(s) => s,
+            None => return self.constant(SExpression::Boolean(is_and)),
+        };
+
+        let mut jumps = vec![];
+        for a in init {
+            self.expr(a, false);
+            jumps.push(self.emit(if is_and { Op::JumpIfFalseOrPop(0) } else { Op::JumpIfTrueOrPop(0) }));
+        }
+        self.expr(last, tail);
+        for j in jumps {
+            self.patch(j);
+        }
+    }
+
+    fn if_expression(&mut self, e: &SExpression, tail: bool) {
+        let v = match e {
+            SExpression::List(v) if v.len() == 4 => v,
+            _ => return self.invalid(e),
+        };
+
+        self.expr(&v[1], false);
+        let otherwise = self.emit(Op::JumpIfFalse(0));
+        self.expr(&v[2], tail);
+        let end = self.emit(Op::Jump(0));
+        self.patch(otherwise);
+        self.expr(&v[3], tail);
+        self.patch(end);
+    }
+
+    fn cond_expression(&mut self, e: &SExpression, clauses: &[SExpression], tail: bool) {
+        let mut ends = vec![];
+        for (i, clause) in clauses.iter().enumerate() {
+            let (test, body) = match clause {
+                SExpression::List(v) if !v.is_empty() => (&v[0], &v[1..]),
+                _ => {
+                    self.invalid(e);
+                    break;
+                },
+            };
+
+            if matches!(test, SExpression::Identifier(id) if id == "else") {
+                if i != clauses.len() - 1 || body.is_empty() {
+                    self.invalid(e);
+                } else {
+                    self.body(body, tail);
+                    ends.push(self.emit(Op::Jump(0)));
+                }
+                break;
+            }
+
+            self.expr(test, false);
+            if body.is_empty() {
+                ends.push(self.emit(Op::JumpIfTrueOrPop(0)));
+            } else {
+                let next = self.emit(Op::JumpIfFalse(0));
+                self.body(body, tail);
+                ends.push(self.emit(Op::Jump(0)));
+                self.patch(next);
+            }
+        }
+        // no clause matched
+        self.constant(SExpression::Void);
+        for end in ends {
+            self.patch(end);
+        }
+    }
+
+    // the key stays on the stack until a clause is chosen
+    fn case_expression(&mut self, e: &SExpression, tail: bool) {
+        let (key, clauses) = match e {
+            SExpression::List(v) if v.len() >= 2 => (&v[1], &v[2..]),
+            _ => return self.invalid(e),
+        };
+
+        self.expr(key, false);
+        let mut ends = vec![];
+        for (i, clause) in clauses.iter().enumerate() {
+            match clause {
+                SExpression::List(v) if v.len() >= 2 => match &v[0] {
+                    datums @ SExpression::List(_) => {
+                        self.chunk.constants.push(datums.clone());
+                        self.emit(Op::Match(self.chunk.constants.len() - 1));
+                        let next = self.emit(Op::JumpIfFalse(0));
+                        self.emit(Op::Pop);
+                        self.body(&v[1..], tail);
+                        ends.push(self.emit(Op::Jump(0)));
+                        self.patch(next);
+                    },
+                    SExpression::Identifier(id) if id == "else" && i == clauses.len() - 1 => {
+                        self.emit(Op::Pop);
+                        self.body(&v[1..], tail);
+                        ends.push(self.emit(Op::Jump(0)));
+                    },
+                    _ => {
+                        self.invalid(e);
+                        break;
+                    },
+                },
+                _ => {
+                    self.invalid(e);
+                    break;
+                },
+            }
+        }
+        // no clause matched
+        self.emit(Op::Pop);
+        self.constant(SExpression::Void);
+        for end in ends {
+            self.patch(end);
+        }
+    }
+
+    // (do ((name init step)...) (test result...) body...) - the scope is replaced on every iteration
+    fn do_expression(&mut self, e: &SExpression, tail: bool) {
+        let (bindings, test, result, body) = match split_do(e) {
+            Some(s) => s,
+            None => return self.invalid(e),
+        };
+
+        for (_, init, _) in &bindings {
+            self.expr(init, false);
+        }
+        self.emit(Op::EnterScope);
+        for (name, _, _) in bindings.iter().rev() {
+            let i = self.name(name);
+            self.emit(Op::Define(i));
+        }
+
+        let start = self.chunk.ops.len();
+        self.expr(test, false);
+        let next = self.emit(Op::JumpIfFalse(0));
+        self.body(result, tail);
+        let end = self.emit(Op::Jump(0));
+
+        self.patch(next);
+        for expr in body {
+            self.expr(expr, false);
+            self.emit(Op::Pop);
+        }
+        for (name, _, step) in &bindings {
+            match step {
+                Some(step) => self.expr(step, false),
+                None => {
+                    let i = self.name(name);
+                    self.emit(Op::Get(i));
+                },
+            }
+        }
+        self.emit(Op::LeaveScope(1));
+        self.emit(Op::EnterScope);
+        for (name, _, _) in bindings.iter().rev() {
+            let i = self.name(name);
+            self.emit(Op::Define(i));
+        }
+        self.emit(Op::Jump(start));
+
+        self.patch(end);
+        self.emit(Op::LeaveScope(1));
+    }
+
+    fn store_expression(&mut self, e: &SExpression) {
+        match e {
+            // (define (name args...) body...) is a sugar for (define name (lambda (args...) body...))
+            SExpression::List(v) if v.len() >= 3 && matches!(v[1], SExpression::List(_)) => match &v[1] {
+                SExpression::List(signature) => match signature.split_first() {
+                    Some((SExpression::Identifier(symbol), params)) => {
+                        self.lambda(e, params, &v[2..]);
+                        let i = self.name(symbol);
+                        self.emit(Op::Define(i));
+                        self.constant(SExpression::Void);
+                    },
+                    _ => self.invalid(e),
+                },
+                _ => self.invalid(e),
+            },
+            // rest parameters are not supported, see Evaluator::store_expression
+            SExpression::List(v) if v.len() >= 3 && matches!(v[1], SExpression::DottedList(..)) => self.invalid(&v[1]),
+            SExpression::List(v) if v.len() == 3 => {
+                self.expr(&v[2], false);
+                match &v[1] {
+                    SExpression::Identifier(symbol) => {
+                        let i = self.name(symbol);
+                        self.emit(Op::Define(i));
+                        self.constant(SExpression::Void);
+                    },
+                    _ => self.invalid(e),
+                }
+            },
+            _ => self.invalid(e),
+        }
+    }
+
+    fn set_expression(&mut self, e: &SExpression) {
+        match e {
+            SExpression::List(v) if v.len() == 3 => {
+                self.expr(&v[2], false);
+                match &v[1] {
+                    SExpression::Identifier(symbol) => {
+                        let i = self.name(symbol);
+                        self.emit(Op::Set(i));
+                        self.constant(SExpression::Void);
+                    },
+                    _ => self.invalid(e),
+                }
+            },
+            _ => self.invalid(e),
+        }
+    }
+
+    // the body is compiled when the lambda is called for the first time
+    fn lambda(&mut self, e: &SExpression, params: &[SExpression], body: &[SExpression]) {
+        let params = params.iter()
+            .map(|p| match p {
+                SExpression::Identifier(id) => Some(id.clone()),
+                _ => None,
+            })
+            .collect::<Option<Vec<_>>>();
+
+        match params {
+            Some(params) => {
+                self.chunk.lambdas.push(Template { params, body: body.into(), code: Rc::default() });
+                let i = self.chunk.lambdas.len() - 1;
+                self.emit(Op::Closure(i));
+            },
+            None => self.invalid(e),
+        }
+    }
+
+    // (let ((name value)...) body...) - values are evaluated in the outer scope. A named let binds its
+    // name to a procedure in a scope of its own, the bindings are in a scope inside that one
+    fn let_expression(&mut self, e: &SExpression, tail: bool) {
+        let (name, bindings, body) = match split_let(e) {
+            Some(s) => s,
+            None => return self.invalid(e),
+        };
+
+        for (_, value) in &bindings {
+            self.expr(value, false);
+        }
+        self.emit(Op::EnterScope);
+        if let Some(name) = name {
+            let params = bindings.iter().map(|(param, _)| SExpression::Identifier(param.to_string())).collect::<Vec<_>>();
+            self.lambda(e, &params, body);
+            let i = self.name(name);
+            self.emit(Op::Define(i));
+            self.emit(Op::EnterScope);
+        }
+        for (name, _) in bindings.iter().rev() {
+            let i = self.name(name);
+            self.emit(Op::Define(i));
+        }
+        self.body(body, tail);
+        self.emit(Op::LeaveScope(if name.is_some() { 2 } else { 1 }));
+    }
+
+    // (letrec ((name value)...) body...) - every value sees all the bindings
+    fn letrec_expression(&mut self, e: &SExpression, tail: bool) {
+        let (bindings, body) = match split_let(e) {
+            Some((None, bindings, body)) => (bindings, body),
+            _ => return self.invalid(e),
+        };
+
+        self.emit(Op::EnterScope);
+        for (name, _) in &bindings {
+            self.constant(SExpression::Void);
+            let i = self.name(name);
+            self.emit(Op::Define(i));
+        }
+        for (name, value) in &bindings {
+            self.expr(value, false);
+            let i = self.name(name);
+            self.emit(Op::Define(i));
+        }
+        self.body(body, tail);
+        self.emit(Op::LeaveScope(1));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{lexer::lex, parser::parse};
+
+    use super::*;
+
+    fn compile_str(input: &str) -> Chunk {
+        compile(&parse(lex(input)).unwrap().remove(0))
+    }
+
+    #[test]
+    fn compile_call() {
+        let chunk = compile_str("(+ x 1)");
+        assert_eq!(chunk.ops, vec![
+            Op::Get(0),
+            Op::CheckCallable,
+            Op::Get(1),
+            Op::Const(0),
+            Op::TailCall(2),
+            Op::Return,
+        ]);
+        assert_eq!(chunk.names, vec!["+", "x"]);
+    }
+
+    #[test]
+    fn compile_if() {
+        let chunk = compile_str("(if c 1 (f))");
+        assert_eq!(chunk.ops, vec![
+            Op::Get(0),
+            Op::JumpIfFalse(4),
+            Op::Const(0),
+            Op::Jump(7),
+            Op::Get(1),
+            Op::CheckCallable,
+            Op::TailCall(0),
+            Op::Return,
+        ]);
+    }
+
+    #[test]
+    fn malformed_form_fails_when_reached() {
+        let chunk = compile_str("(if c (if) 2)");
+        assert_eq!(chunk.ops[2], Op::Invalid(0));
+        assert_eq!(chunk.constants[0].to_string(), "(if)");
+        assert_eq!(chunk.source(2).map(|e| e.to_string()), Some("(if)".to_string()));
+    }
+}
@@ -0,0 +1,52 @@
+use std::{fmt, rc::Rc};
+
+use crate::{expander, parser::{CompilerError, SExpression}};
+
+// error object made by `error`, or by catching an error of the evaluator
+#[derive(Debug, Clone, PartialEq)]
+pub struct Condition {
+    pub message: String,
+    pub irritants: Vec<SExpression>,
+    // the error this condition was caught as, raising the condition raises the error again
+    pub(crate) error: Option<Box<CompilerError>>,
+}
+
+impl Condition {
+    pub fn new(message: &str, irritants: Vec<SExpression>) -> Self {
+        Self { message: message.to_string(), irritants, error: None }
+    }
+}
+
+// bad value: 42
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)?;
+        for irritant in &self.irritants {
+            write!(f, " {irritant}")?;
+        }
+        Ok(())
+    }
+}
+
+// error that stops evaluation when `value` is raised
+pub(crate) fn raise(value: SExpression) -> CompilerError {
+    if let SExpression::Condition(c) = &value {
+        if let Some(error) = &c.error {
+            return error.as_ref().clone();
+        }
+    }
+    CompilerError::Raised(value)
+}
+
+// value a handler sees for an error. Limits can't be caught, so untrusted code still stops
+pub(crate) fn catch(error: CompilerError) -> Result<SExpression, CompilerError> {
+    match expander::original_names(error) {
+        error @ CompilerError::LimitExceeded(_) => Err(error),
+        CompilerError::Raised(value) => Ok(value),
+        error => Ok(SExpression::Condition(Rc::new(Condition {
+            message: error.to_string(),
+            irritants: vec![],
+            error: Some(Box::new(error)),
+        }))),
+    }
+}
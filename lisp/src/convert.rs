@@ -0,0 +1,134 @@
+// conversions between rust values and lisp values, used to pass arguments and read results
+use crate::{number::Number, pair, parser::{CompilerError, SExpression}};
+
+impl From<i64> for SExpression {
+    fn from(n: i64) -> Self {
+        SExpression::Number(Number::Int(n))
+    }
+}
+
+impl From<i32> for SExpression {
+    fn from(n: i32) -> Self {
+        SExpression::Number(Number::Int(n.into()))
+    }
+}
+
+impl From<f64> for SExpression {
+    fn from(n: f64) -> Self {
+        SExpression::Number(Number::Float(n))
+    }
+}
+
+impl From<Number> for SExpression {
+    fn from(n: Number) -> Self {
+        SExpression::Number(n)
+    }
+}
+
+impl From<bool> for SExpression {
+    fn from(b: bool) -> Self {
+        SExpression::Boolean(b)
+    }
+}
+
+impl From<&str> for SExpression {
+    fn from(s: &str) -> Self {
+        SExpression::String(s.into())
+    }
+}
+
+impl From<String> for SExpression {
+    fn from(s: String) -> Self {
+        SExpression::String(s.into())
+    }
+}
+
+impl From<char> for SExpression {
+    fn from(c: char) -> Self {
+        SExpression::Char(c)
+    }
+}
+
+impl From<()> for SExpression {
+    fn from(_: ()) -> Self {
+        SExpression::Void
+    }
+}
+
+impl<T: Into<SExpression>> From<Vec<T>> for SExpression {
+    fn from(v: Vec<T>) -> Self {
+        pair::list(v.into_iter().map(Into::into).collect::<Vec<_>>())
+    }
+}
+
+// integers that don't fit are rejected like any other value of the wrong type
+impl TryFrom<SExpression> for i64 {
+    type Error = CompilerError;
+
+    fn try_from(e: SExpression) -> Result<Self, Self::Error> {
+        match e {
+            SExpression::Number(Number::Int(n)) => Ok(n),
+            other => Err(CompilerError::InvalidList(other)),
+        }
+    }
+}
+
+// exact numbers are converted to the nearest float
+impl TryFrom<SExpression> for f64 {
+    type Error = CompilerError;
+
+    fn try_from(e: SExpression) -> Result<Self, Self::Error> {
+        match e {
+            SExpression::Number(n) => Ok(n.to_f64()),
+            other => Err(CompilerError::InvalidList(other)),
+        }
+    }
+}
+
+impl TryFrom<SExpression> for bool {
+    type Error = CompilerError;
+
+    fn try_from(e: SExpression) -> Result<Self, Self::Error> {
+        match e {
+            SExpression::Boolean(b) => Ok(b),
+            other => Err(CompilerError::InvalidList(other)),
+        }
+    }
+}
+
+impl TryFrom<SExpression> for String {
+    type Error = CompilerError;
+
+    fn try_from(e: SExpression) -> Result<Self, Self::Error> {
+        match e {
+            SExpression::String(s) => Ok(s.to_string()),
+            other => Err(CompilerError::InvalidList(other)),
+        }
+    }
+}
+
+impl TryFrom<SExpression> for char {
+    type Error = CompilerError;
+
+    fn try_from(e: SExpression) -> Result<Self, Self::Error> {
+        match e {
+            SExpression::Char(c) => Ok(c),
+            other => Err(CompilerError::InvalidList(other)),
+        }
+    }
+}
+
+// vectors are converted too, with the elements they have at the time
+impl<T: TryFrom<SExpression, Error = CompilerError>> TryFrom<SExpression> for Vec<T> {
+    type Error = CompilerError;
+
+    fn try_from(e: SExpression) -> Result<Self, Self::Error> {
+        match e {
+            SExpression::Vector(v) => v.borrow().iter().cloned().map(T::try_from).collect(),
+            other => match pair::elements(&other) {
+                Some(v) => v.into_iter().map(T::try_from).collect(),
+                None => Err(CompilerError::InvalidList(other)),
+            },
+        }
+    }
+}
@@ -0,0 +1,154 @@
+use std::io::Write;
+
+use crate::{evaluator::EnvRef, parser::{CompilerError, SExpression}, printer};
+
+// sees every expression the tree walker evaluates, see Evaluator::set_observer. The vm doesn't report to it
+pub trait Observer {
+    // before e is evaluated in scope. depth counts the evaluations in progress, an expression in
+    // tail position replaces the one it ends and is entered at the same depth
+    fn enter(&mut self, e: &SExpression, scope: &Scope, depth: usize);
+
+    // when the call e entered at depth is about to run procedure. For a lambda scope is the new frame with
+    // the arguments bound to the parameters, for a builtin the frame of the call
+    fn call(&mut self, _e: &SExpression, _procedure: &SExpression, _scope: &Scope, _depth: usize) {}
+
+    // the value of an expression entered at depth, expressions in tail position don't have one of their own
+    fn leave(&mut self, e: &SExpression, result: &Result<SExpression, CompilerError>, depth: usize);
+}
+
+// the innermost frame an expression is evaluated in
+pub struct Scope<'a> {
+    env: &'a EnvRef,
+}
+
+impl<'a> Scope<'a> {
+    pub(crate) fn new(env: &'a EnvRef) -> Self {
+        Self { env }
+    }
+
+    // bindings of this frame sorted by name, the ones of outer frames are left out
+    pub fn bindings(&self) -> Vec<(String, SExpression)> {
+        let mut bindings = self.env.borrow().env.iter().map(|(k, v)| (k.clone(), v.clone())).collect::<Vec<_>>();
+        bindings.sort_by(|a, b| a.0.cmp(&b.0));
+        bindings
+    }
+
+    // value of name in this frame or an outer one
+    pub fn lookup(&self, name: &str) -> Option<SExpression> {
+        self.env.borrow().get(name)
+    }
+
+    pub fn is_global(&self) -> bool {
+        self.env.borrow().outer.is_none()
+    }
+}
+
+// writes every expression and its value, indented by depth
+pub struct Tracer {
+    out: Box<dyn Write>,
+}
+
+impl Tracer {
+    pub fn new(out: impl Write + 'static) -> Self {
+        Self { out: Box::new(out) }
+    }
+}
+
+fn indent(depth: usize) -> String {
+    "  ".repeat(depth.saturating_sub(1))
+}
+
+// (f 2)
+//   f
+//   => #<lambda (x)>
+impl Observer for Tracer {
+    fn enter(&mut self, e: &SExpression, _: &Scope, depth: usize) {
+        let _ = writeln!(self.out, "{}{}", indent(depth), printer::write(e));
+    }
+
+    fn leave(&mut self, _: &SExpression, result: &Result<SExpression, CompilerError>, depth: usize) {
+        let _ = match result {
+            Ok(value) => writeln!(self.out, "{}=> {}", indent(depth), printer::write(value)),
+            Err(error) => writeln!(self.out, "{}!! {error}", indent(depth)),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use crate::{evaluator::{Backend, Evaluator}, interpreter::Captured, lexer::lex, parser::parse};
+
+    use super::*;
+
+    fn trace(input: &str) -> String {
+        let captured = Captured::default();
+        let mut evaluator = Evaluator::with_backend(Backend::TreeWalker);
+        evaluator.set_observer(Tracer::new(captured.clone()));
+        for e in parse(lex(input)).unwrap() {
+            let _ = evaluator.eval(e);
+        }
+        captured.text()
+    }
+
+    #[test]
+    fn trace_is_indented_by_depth() {
+        let out = trace("(define (sq x) (* x x)) (sq 3)");
+        let expected = "
+            (define (sq x) (* x x))
+            => #<void>
+            (sq 3)
+              sq
+              => #<lambda (x)>
+              3
+              => 3
+            (* x x)
+              *
+              => #<builtin *>
+              x
+              => 3
+              x
+              => 3
+            => 9";
+        assert_eq!(out.lines().collect::<Vec<_>>(), expected.lines().skip(1).map(|l| &l[12..]).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn errors_are_traced() {
+        let out = trace("(car 1)");
+        assert_eq!(out.lines().last(), Some("!! invalid expression: 1"));
+    }
+
+    struct Frames(Rc<RefCell<Vec<Vec<String>>>>);
+
+    impl Observer for Frames {
+        fn enter(&mut self, e: &SExpression, scope: &Scope, _: usize) {
+            if matches!(e, SExpression::Identifier(id) if id == "here") {
+                self.0.borrow_mut().push(scope.bindings().iter().map(|(k, v)| format!("{k}={v}")).collect());
+            }
+        }
+
+        fn leave(&mut self, _: &SExpression, _: &Result<SExpression, CompilerError>, _: usize) {}
+    }
+
+    #[test]
+    fn scopes_show_the_innermost_frame() {
+        let frames = Rc::new(RefCell::new(vec![]));
+        let mut evaluator = Evaluator::with_backend(Backend::TreeWalker);
+        evaluator.set_observer(Frames(frames.clone()));
+        for e in parse(lex("(define here 0) (define (f a b) (let ((c 3)) here)) (f 1 2)")).unwrap() {
+            evaluator.eval(e).unwrap();
+        }
+        assert_eq!(*frames.borrow(), vec![vec!["c=3".to_string()]]);
+
+        // observers are not called by the vm
+        frames.borrow_mut().clear();
+        let mut evaluator = Evaluator::with_backend(Backend::Vm);
+        evaluator.set_observer(Frames(frames.clone()));
+        for e in parse(lex("(define here 0) here")).unwrap() {
+            evaluator.eval(e).unwrap();
+        }
+        assert!(frames.borrow().is_empty());
+    }
+}
@@ -0,0 +1,246 @@
+use std::{cell::RefCell, collections::BTreeSet, io::{self, Write}, rc::Rc};
+
+use lisp::{debug::{Observer, Scope, Tracer}, printer, CompilerError, SExpression};
+
+const HELP: &str = "s, step: evaluate the next expression
+n, next: skip to the next expression at this depth or above
+c, continue: run until the next breakpoint
+f, frame: bindings of the innermost frame
+b, break <name>: stop when <name> is called
+d, delete <name>: remove a breakpoint";
+
+// reads a line after showing the prompt, None at the end of input
+type Input = Box<dyn FnMut(&str) -> Option<String>>;
+
+// stops evaluation at breakpoints and while stepping, and asks what to do next
+pub struct Debugger {
+    breakpoints: BTreeSet<String>,
+    mode: Mode,
+    tracer: Option<Tracer>,
+    input: Input,
+    out: Box<dyn Write>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Mode {
+    Continue,
+    Step,
+    // stop at the next expression entered at this depth or above
+    Next(usize),
+}
+
+impl Debugger {
+    // reads commands from stdin and writes to stdout
+    pub fn new() -> Self {
+        Self::with_io(Box::new(read_line), io::stdout())
+    }
+
+    fn with_io(input: Input, out: impl Write + 'static) -> Self {
+        Self { breakpoints: BTreeSet::new(), mode: Mode::Continue, tracer: None, input, out: Box::new(out) }
+    }
+
+    // false if there is nothing to stop at or trace, then it doesn't need to see any expression
+    pub fn is_active(&self) -> bool {
+        !self.breakpoints.is_empty() || self.mode != Mode::Continue || self.tracer.is_some()
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = &String> {
+        self.breakpoints.iter()
+    }
+
+    // true if it wasn't set yet
+    pub fn add_breakpoint(&mut self, name: &str) -> bool {
+        self.breakpoints.insert(name.to_string())
+    }
+
+    pub fn remove_breakpoint(&mut self, name: &str) -> bool {
+        self.breakpoints.remove(name)
+    }
+
+    // stops at the next expression
+    pub fn step(&mut self) {
+        self.mode = Mode::Step;
+    }
+
+    // runs until the next breakpoint
+    pub fn resume(&mut self) {
+        self.mode = Mode::Continue;
+    }
+
+    // traces to stderr, returns whether tracing is on now
+    pub fn toggle_trace(&mut self) -> bool {
+        self.tracer = match self.tracer {
+            Some(_) => None,
+            None => Some(Tracer::new(io::stderr())),
+        };
+        self.tracer.is_some()
+    }
+
+    // stepping stops at every expression it gets to
+    fn should_stop(&self, depth: usize) -> bool {
+        match self.mode {
+            Mode::Step => true,
+            Mode::Next(max) => depth <= max,
+            Mode::Continue => false,
+        }
+    }
+
+    // a call written with the name of a breakpoint, or of the procedure bound to one under another name
+    fn calls_breakpoint(&self, e: &SExpression, procedure: &SExpression, scope: &Scope) -> bool {
+        let written = matches!(e, SExpression::List(v) if matches!(v.first(), Some(SExpression::Identifier(name)) if self.breakpoints.contains(name)));
+        written || self.breakpoints.iter().any(|b| scope.lookup(b).as_ref() == Some(procedure))
+    }
+
+    // asks for commands until one of them resumes evaluation
+    fn pause(&mut self, e: &SExpression, scope: &Scope, depth: usize) {
+        let _ = writeln!(self.out, "[{depth}] {}", printer::write(e));
+        loop {
+            let _ = self.out.flush();
+            let Some(line) = (self.input)("debug> ") else {
+                self.mode = Mode::Continue;
+                return;
+            };
+            let line = line.trim();
+            let (command, arg) = match line.split_once(char::is_whitespace) {
+                Some((command, arg)) => (command, arg.trim()),
+                None => (line, ""),
+            };
+            match (command, arg) {
+                ("s" | "step", "") => {
+                    self.mode = Mode::Step;
+                    return;
+                },
+                ("n" | "next", "") => {
+                    self.mode = Mode::Next(depth);
+                    return;
+                },
+                ("c" | "continue", "") => {
+                    self.mode = Mode::Continue;
+                    return;
+                },
+                ("f" | "frame", "") if scope.is_global() => {
+                    let _ = writeln!(self.out, "global frame, see :env");
+                },
+                ("f" | "frame", "") => for (name, value) in scope.bindings() {
+                    let _ = writeln!(self.out, "{name} = {}", printer::write(&value));
+                },
+                ("b" | "break", name) if !name.is_empty() => {
+                    self.add_breakpoint(name);
+                },
+                ("d" | "delete", name) if !name.is_empty() => if !self.remove_breakpoint(name) {
+                    let _ = writeln!(self.out, "no breakpoint on {name}");
+                },
+                ("h" | "help", "") => {
+                    let _ = writeln!(self.out, "{HELP}");
+                },
+                _ => {
+                    let _ = writeln!(self.out, "unknown command {line}, h for help");
+                },
+            }
+        }
+    }
+}
+
+fn read_line(prompt: &str) -> Option<String> {
+    print!("{prompt}");
+    let _ = io::stdout().flush();
+    let mut line = String::new();
+    match io::stdin().read_line(&mut line) {
+        Ok(0) | Err(_) => None,
+        Ok(_) => Some(line),
+    }
+}
+
+// lets an evaluator report to a debugger the repl keeps using
+pub struct Hook(pub Rc<RefCell<Debugger>>);
+
+impl Observer for Hook {
+    fn enter(&mut self, e: &SExpression, scope: &Scope, depth: usize) {
+        let mut debugger = self.0.borrow_mut();
+        if let Some(tracer) = &mut debugger.tracer {
+            tracer.enter(e, scope, depth);
+        }
+        if debugger.should_stop(depth) {
+            debugger.pause(e, scope, depth);
+        }
+    }
+
+    // breakpoints stop once the arguments are bound, so the frame shows them. When stepping, enter stopped already
+    fn call(&mut self, e: &SExpression, procedure: &SExpression, scope: &Scope, depth: usize) {
+        let mut debugger = self.0.borrow_mut();
+        if !debugger.should_stop(depth) && debugger.calls_breakpoint(e, procedure, scope) {
+            debugger.pause(e, scope, depth);
+        }
+    }
+
+    fn leave(&mut self, e: &SExpression, result: &Result<SExpression, CompilerError>, depth: usize) {
+        if let Some(tracer) = &mut self.0.borrow_mut().tracer {
+            tracer.leave(e, result, depth);
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::collections::VecDeque;
+
+    use lisp::{lexer::lex, parser::parse, Backend, Captured, Evaluator};
+
+    use super::*;
+
+    // a debugger answering with the commands, and what it wrote
+    pub(crate) fn scripted(commands: &[&str]) -> (Debugger, Captured) {
+        let mut commands = commands.iter().map(|c| c.to_string()).collect::<VecDeque<_>>();
+        let out = Captured::default();
+        (Debugger::with_io(Box::new(move |_| commands.pop_front()), out.clone()), out)
+    }
+
+    fn debug(debugger: Debugger, input: &str) -> Vec<String> {
+        let mut evaluator = Evaluator::with_backend(Backend::TreeWalker);
+        evaluator.set_observer(Hook(Rc::new(RefCell::new(debugger))));
+        parse(lex(input)).unwrap().into_iter().map(|e| evaluator.eval(e).unwrap().to_string()).collect()
+    }
+
+    const SQUARES: &str = "(define (sq x) (* x x)) (define (sum a b) (+ (sq a) (sq b))) (sum 2 3)";
+
+    #[test]
+    fn breakpoints_stop_calls() {
+        let (mut debugger, out) = scripted(&["frame", "c", "f", "c"]);
+        debugger.add_breakpoint("sq");
+        assert_eq!(debug(debugger, SQUARES).last().unwrap(), "13");
+        assert_eq!(out.text(), "[2] (sq a)\nx = 2\n[2] (sq b)\nx = 3\n");
+    }
+
+    #[test]
+    fn breakpoints_stop_calls_under_other_names() {
+        let (mut debugger, out) = scripted(&[]);
+        debugger.add_breakpoint("sq");
+        debugger.add_breakpoint("car");
+        debug(debugger, "(define (sq x) (* x x)) (define g sq) (define first car) (list (g 2) (first '(1)))");
+        assert_eq!(out.text(), "[2] (g 2)\n[2] (first (quote (1)))\n");
+    }
+
+    #[test]
+    fn step_and_next() {
+        let (mut debugger, out) = scripted(&["s", "s", "n", "n", "n", "c"]);
+        debugger.step();
+        debug(debugger, "(define (sq x) (* x x)) (+ (sq 2) 1)");
+        assert_eq!(out.text(), "[1] (define (sq x) (* x x))\n[1] (+ (sq 2) 1)\n[2] +\n[2] (sq 2)\n[2] (* x x)\n[2] 1\n");
+    }
+
+    #[test]
+    fn breakpoints_can_be_changed_while_stopped() {
+        let (mut debugger, out) = scripted(&["b sq", "d sum", "d sum", "x", "c"]);
+        debugger.add_breakpoint("sum");
+        assert_eq!(debug(debugger, SQUARES).last().unwrap(), "13");
+        assert_eq!(out.text(), "[1] (sum 2 3)\nno breakpoint on sum\nunknown command x, h for help\n[2] (sq a)\n[2] (sq b)\n");
+    }
+
+    #[test]
+    fn end_of_input_continues() {
+        let (mut debugger, out) = scripted(&[]);
+        debugger.add_breakpoint("sq");
+        assert_eq!(debug(debugger, SQUARES).last().unwrap(), "13");
+        assert_eq!(out.text(), "[2] (sq a)\n[2] (sq b)\n");
+    }
+}
@@ -0,0 +1,339 @@
+use std::{fs::File, io::{self, BufRead, BufReader, Read}, path::Path};
+
+use crate::{
+    evaluator::Evaluator,
+    lexer::{lex_with_spans, Lexer, Span},
+    parser::{forms, parse_with_spans, CompilerError, Form, SExpression},
+};
+
+// error together with the place in the source it refers to
+#[derive(Debug, PartialEq)]
+pub struct Diagnostic {
+    pub error: CompilerError,
+    pub span: Option<Span>,
+}
+
+impl Diagnostic {
+    // error: unknown symbol: y
+    //  --> script.scm:2:8
+    //   |
+    // 2 | (+ x y)
+    //   |      ^
+    pub fn render(&self, file_name: &str, source: &str) -> String {
+        let line = self.span.and_then(|span| source.lines().nth(span.start.line - 1));
+        self.render_line(file_name, line.unwrap_or(""))
+    }
+
+    // takes only the line the error starts on, for sources that are not kept in memory
+    pub fn render_line(&self, file_name: &str, line: &str) -> String {
+        let span = match self.span {
+            Some(s) => s,
+            None => return format!("error: {}\n --> {file_name}", self.error),
+        };
+
+        let line_number = span.start.line.to_string();
+        let pad = " ".repeat(line_number.len());
+
+        // keep tabs, so the carets line up with the source line
+        let indent = line.chars()
+            .take(span.start.col - 1)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect::<String>();
+        let width = if span.end.line == span.start.line {
+            span.end.col.saturating_sub(span.start.col)
+        } else {
+            line.chars().count().saturating_sub(span.start.col - 1)
+        };
+
+        format!(
+            "error: {}\n{pad}--> {file_name}:{}:{}\n{pad} |\n{line_number} | {line}\n{pad} | {indent}{}",
+            self.error,
+            span.start.line,
+            span.start.col,
+            "^".repeat(width.max(1)),
+        )
+    }
+}
+
+// evaluation errors don't carry a location, so the failing expression is looked up in the program.
+// The current form is searched first, the failing expression may also come from a function defined in another one
+pub fn locate(error: &CompilerError, context: Option<&SExpression>, forms: &[Form], current: usize) -> Option<Span> {
+    let order = forms.get(current).into_iter().chain(forms.iter());
+
+    // most precise element of the failing expression
+    let target = match error {
+        CompilerError::UnknownSymbol(name) => Some(SExpression::Identifier(name.clone())),
+        CompilerError::InvalidList(e) => Some(e.clone()),
+        _ => None,
+    };
+
+    if let Some(context) = context {
+        for form in order.clone() {
+            if let Some(tree) = form.spans.find_tree(&form.expr, context) {
+                let precise = target.as_ref().and_then(|t| tree.find(context, t));
+                return Some(precise.unwrap_or(tree.span));
+            }
+        }
+    }
+
+    let form = forms.get(current)?;
+    let precise = target.as_ref().and_then(|t| form.spans.find(&form.expr, t));
+    Some(precise.unwrap_or(form.spans.span))
+}
+
+// results of the expressions evaluated before the first error, and rendered errors if there were any
+pub struct Report {
+    pub results: Vec<SExpression>,
+    pub errors: Vec<String>,
+}
+
+pub fn run_source(evaluator: &mut Evaluator, file_name: &str, source: &str) -> Report {
+    let (results, errors) = eval_source(evaluator, source);
+    Report { results, errors: errors.iter().map(|d| d.render(file_name, source)).collect() }
+}
+
+// evaluates the forms of the source until one fails. Returns the results so far and the errors,
+// every parse error is reported but evaluation stops at the first failing form
+pub fn eval_source(evaluator: &mut Evaluator, source: &str) -> (Vec<SExpression>, Vec<Diagnostic>) {
+    let forms = match parse_with_spans(lex_with_spans(source)) {
+        Ok(forms) => forms,
+        Err(diagnostics) => return (vec![], diagnostics),
+    };
+
+    let mut results = vec![];
+    for (i, form) in forms.iter().enumerate() {
+        match evaluator.eval(form.expr.clone()) {
+            Ok(v) => results.push(v),
+            Err(error) => {
+                let span = locate(&error, evaluator.error_context(), &forms, i);
+                return (results, vec![Diagnostic { error, span }]);
+            }
+        }
+    }
+    (results, vec![])
+}
+
+// evaluates every form as soon as it's read, the input is never in memory as a whole. Forms read before a parse
+// error have run already, after the first error the rest is only parsed to report its parse errors
+pub fn eval_reader(evaluator: &mut Evaluator, file_name: &str, reader: impl BufRead) -> Vec<Diagnostic> {
+    let mut lexer = Lexer::from_reader(reader);
+    // forms read so far, the failing expression is looked up in them
+    let mut read = vec![];
+    let mut errors = vec![];
+    for parsed in forms(&mut lexer) {
+        match parsed {
+            Ok(form) if errors.is_empty() => {
+                let expr = form.expr.clone();
+                read.push(form);
+                if let Err(error) = evaluator.eval(expr) {
+                    let span = locate(&error, evaluator.error_context(), &read, read.len() - 1);
+                    errors.push(Diagnostic { error, span });
+                    break;
+                }
+            },
+            Ok(_) => {},
+            Err(diagnostics) => errors.extend(diagnostics),
+        }
+    }
+    if let Some(error) = lexer.read_error() {
+        errors.push(Diagnostic { error: CompilerError::LoadFailed { path: file_name.to_string(), reason: error.to_string() }, span: None });
+    }
+    errors
+}
+
+// runs the file as it's read and renders the errors, the lines they point at are read from the file again
+pub fn run_file(evaluator: &mut Evaluator, path: &Path) -> io::Result<Vec<String>> {
+    let file_name = path.display().to_string();
+    let errors = eval_reader(evaluator, &file_name, BufReader::new(File::open(path)?));
+    Ok(errors.iter().map(|d| d.render_line(&file_name, &read_line(path, d.span).unwrap_or_default())).collect())
+}
+
+fn read_line(path: &Path, span: Option<Span>) -> Option<String> {
+    let mut lines = BufReader::new(File::open(path).ok()?).lines();
+    lines.nth(span?.start.line - 1)?.ok()
+}
+
+// runs input that can't be read twice, like stdin. What was read is kept to show the lines errors point at
+pub fn run_reader(evaluator: &mut Evaluator, file_name: &str, reader: impl BufRead) -> Vec<String> {
+    let mut kept = Kept { reader, read: vec![] };
+    let errors = eval_reader(evaluator, file_name, &mut kept);
+    let source = String::from_utf8_lossy(&kept.read);
+    errors.iter().map(|d| d.render(file_name, &source)).collect()
+}
+
+// a reader that keeps a copy of everything taken from it
+struct Kept<R> {
+    reader: R,
+    read: Vec<u8>,
+}
+
+impl<R: BufRead> Read for Kept<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        let n = self.fill_buf()?.read(out)?;
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl<R: BufRead> BufRead for Kept<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.reader.fill_buf()
+    }
+
+    // the bytes being consumed are still in the buffer
+    fn consume(&mut self, amount: usize) {
+        if let Ok(buffer) = self.reader.fill_buf() {
+            self.read.extend_from_slice(&buffer[..amount.min(buffer.len())]);
+        }
+        self.reader.consume(amount);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::number::Number;
+
+    use super::*;
+
+    fn run(source: &str) -> Report {
+        run_source(&mut Evaluator::new(), "test.scm", source)
+    }
+
+    #[test]
+    fn render_unknown_symbol() {
+        let r = run("(define x 1)\n(+ x y)");
+        assert_eq!(r.errors, vec![
+"error: unknown symbol: y
+ --> test.scm:2:6
+  |
+2 | (+ x y)
+  |      ^"]);
+    }
+
+    #[test]
+    fn render_error_inside_function_defined_earlier() {
+        let r = run("(define (f x)\n    (car x))\n\n(f 1)");
+        assert_eq!(r.errors, vec![
+"error: invalid expression: 1
+ --> test.scm:2:5
+  |
+2 |     (car x))
+  |     ^^^^^^^"]);
+    }
+
+    #[test]
+    fn render_macro_use_without_matching_rule() {
+        let r = run("(define-syntax m (syntax-rules () ((_ a) a)))\n(display (m 1 2))");
+        assert_eq!(r.errors, vec![
+"error: invalid expression: (m 1 2)
+ --> test.scm:2:10
+  |
+2 | (display (m 1 2))
+  |          ^^^^^^^"]);
+    }
+
+    #[test]
+    fn render_error_after_comments() {
+        let r = run("#| about\n   this |#\n(define x 1) ; x\n#;(car x)\n(car x)");
+        assert_eq!(r.errors, vec![
+"error: invalid expression: 1
+ --> test.scm:5:1
+  |
+5 | (car x)
+  | ^^^^^^^"]);
+    }
+
+    #[test]
+    fn render_rest_parameter() {
+        let r = run("(define (f . args)\n  args)");
+        assert_eq!(r.errors, vec![
+"error: invalid expression: (f . args)
+ --> test.scm:1:9
+  |
+1 | (define (f . args)
+  |         ^^^^^^^^^^"]);
+    }
+
+    #[test]
+    fn render_whole_call() {
+        let r = run("(define z 0)\n  (+ 1 (/ 10 z))");
+        assert_eq!(r.errors, vec![
+"error: division by zero
+ --> test.scm:2:8
+  |
+2 |   (+ 1 (/ 10 z))
+  |        ^^^^^^^^"]);
+    }
+
+    #[test]
+    fn render_parse_errors() {
+        let r = run("(+ 1 2))\n\n\n\n\n\n\n\n\n)");
+        assert_eq!(r.errors, vec![
+"error: unmatched )
+ --> test.scm:1:8
+  |
+1 | (+ 1 2))
+  |        ^",
+"error: unmatched )
+  --> test.scm:10:1
+   |
+10 | )
+   | ^"]);
+    }
+
+    #[test]
+    fn render_multiline_span() {
+        let d = Diagnostic {
+            error: CompilerError::DivisionByZero,
+            span: parse_with_spans(lex_with_spans("\t(/ 1\n 0)")).unwrap().first().map(|f| f.spans.span),
+        };
+        assert_eq!(d.render("a.scm", "\t(/ 1\n 0)"),
+"error: division by zero
+ --> a.scm:1:2
+  |
+1 | \t(/ 1
+  | \t^^^^");
+    }
+
+    #[test]
+    fn results_before_error_are_kept() {
+        let r = run("(+ 1 2) (car '()) (+ 3 4)");
+        assert_eq!(r.results, vec![SExpression::Number(Number::Int(3))]);
+        assert_eq!(r.errors.len(), 1);
+    }
+
+    #[test]
+    fn reader_runs_forms_as_they_are_read() {
+        let mut evaluator = Evaluator::new();
+        let source = "(define x 1)\n(define y (car x))\n(define x 2)";
+        let errors = run_reader(&mut evaluator, "<stdin>", BufReader::with_capacity(4, source.as_bytes()));
+        assert_eq!(errors, vec![
+"error: invalid expression: 1
+ --> <stdin>:2:11
+  |
+2 | (define y (car x))
+  |           ^^^^^^^"]);
+        assert_eq!(evaluator.eval(SExpression::Identifier("x".to_string())), Ok(SExpression::Number(Number::Int(1))));
+
+        // forms before a parse error run, the ones after it are only parsed
+        let errors = run_reader(&mut evaluator, "<stdin>", "(define x 3) )\n(define x 4) (".as_bytes());
+        assert_eq!(errors.len(), 2, "{errors:?}");
+        assert!(errors[1].starts_with("error: unexpected end of input\n --> <stdin>:2:14"), "{errors:?}");
+        assert_eq!(evaluator.eval(SExpression::Identifier("x".to_string())), Ok(SExpression::Number(Number::Int(3))));
+    }
+
+    #[test]
+    fn file_lines_are_read_again_for_errors() {
+        let path = std::env::temp_dir().join(format!("lisp-diagnostics-{}.scm", std::process::id()));
+        std::fs::write(&path, "(define (f x)\n    (car x))\n\n(f 1)").unwrap();
+        let errors = run_file(&mut Evaluator::new(), &path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(errors, vec![format!(
+"error: invalid expression: 1
+ --> {}:2:5
+  |
+2 |     (car x))
+  |     ^^^^^^^", path.display())]);
+        assert!(run_file(&mut Evaluator::new(), Path::new("/does/not/exist.scm")).is_err());
+    }
+}
@@ -1,139 +1,761 @@
-use std::collections::HashMap;
+use std::{cell::{OnceCell, RefCell}, collections::HashMap, fmt, io::{self, Write}, rc::Rc};
 
-use crate::parser::{CompilerError, SExpression};
+use crate::{builtins::{self, Arity, Builtin, arity_mismatch, is_truthy}, compiler::{Chunk, Template}, debug::{Observer, Scope}, expander::{self, Expander}, heap::{GcStats, Heap}, lexer::lex, limits::{self, Limit, Limits, Usage}, modules::Modules, pair, parser::{parse, CompilerError, SExpression}, vm};
 
-struct Evaluator {
-    // todo: store as RefCell?
-    env: Env
+// derived forms written as macros, loaded into every evaluator
+const PRELUDE: &str = include_str!("prelude.scm");
+
+// how expressions are run, both give the same results
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Backend {
+    TreeWalker,
+    // expressions are compiled to bytecode first
+    Vm,
+}
+
+pub struct Evaluator {
+    pub(crate) env: EnvRef,
+    backend: Backend,
+    // where display and newline write to
+    out: Box<dyn Write>,
+    // innermost list expression that failed during the last eval
+    pub(crate) error_context: Option<SExpression>,
+    expander: Expander,
+    limits: Limits,
+    usage: Usage,
+    // native stack the tree walker may recurse into without a depth limit, see set_stack_size
+    stack_budget: usize,
+    pub(crate) modules: Modules,
+    pub(crate) heap: Heap,
+    // told about every expression the tree walker evaluates, for tracing and debugging
+    observer: Option<Box<dyn Observer>>,
+}
+
+#[derive(Clone)]
+pub struct Lambda {
+    pub(crate) params: Vec<String>,
+    pub(crate) body: Rc<[SExpression]>,
+    // frame the lambda was created in
+    pub(crate) env: EnvRef,
+    // bytecode of the body, compiled by the vm on the first call
+    pub(crate) code: Rc<OnceCell<Rc<Chunk>>>,
+}
+
+// env is skipped on purpose - it may contain the lambda itself
+impl fmt::Debug for Lambda {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Lambda")
+            .field("params", &self.params)
+            .field("body", &self.body)
+            .finish()
+    }
+}
+
+impl Lambda {
+    pub(crate) fn new(template: &Template, env: &EnvRef) -> Self {
+        Self {
+            params: template.params.clone(),
+            body: template.body.clone(),
+            env: env.clone(),
+            code: template.code.clone(),
+        }
+    }
+
+    pub fn params(&self) -> &[String] {
+        &self.params
+    }
+}
+
+impl PartialEq for Lambda {
+    fn eq(&self, other: &Self) -> bool {
+        self.params == other.params && self.body == other.body && Rc::ptr_eq(&self.env, &other.env)
+    }
+}
+
+// what's left of an expression after a special form did its part: either the value,
+// or an expression in tail position to evaluate next instead of recursing
+enum Step {
+    Done(SExpression),
+    Tail(SExpression, EnvRef),
+}
+
+impl Default for Evaluator {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Evaluator {
-    fn new() -> Self {
-        Self { env: Env::std_env() }
+    pub fn new() -> Self {
+        Self::with_backend(Backend::TreeWalker)
     }
 
-    fn eval_expr(&mut self, e: SExpression) -> Result<SExpression, CompilerError> {
-        match e {
-            SExpression::Void => Ok(e),
-            SExpression::Number(_) => Ok(e),
-            SExpression::Boolean(_) => Ok(e),
-            SExpression::String(_) => Ok(e),
-            SExpression::Identifier(id) => {
-                match self.env.env.get(&id) {
-                    Some(s) => Ok(s.clone()),
-                    None => Err(CompilerError::UnknownSymbol(id))
-                }
+    pub fn with_backend(backend: Backend) -> Self {
+        let mut heap = Heap::default();
+        let mut evaluator = Self {
+            env: heap.env(Env::std_env()),
+            backend,
+            out: Box::new(io::stdout()),
+            error_context: None,
+            expander: Expander::default(),
+            limits: Limits::default(),
+            usage: Usage::default(),
+            stack_budget: limits::stack_budget(limits::DEFAULT_STACK_SIZE),
+            modules: Modules::default(),
+            heap,
+            observer: None,
+        };
+        for e in parse(lex(PRELUDE)).expect("prelude does not parse") {
+            evaluator.eval(e).expect("prelude failed");
+        }
+        evaluator.expander.prelude_loaded(&evaluator.env.borrow());
+        evaluator
+    }
+
+    pub fn backend(&self) -> Backend {
+        self.backend
+    }
+
+    pub fn set_output(&mut self, out: impl Write + 'static) {
+        self.out = Box::new(out);
+    }
+
+    pub fn output(&mut self) -> &mut dyn Write {
+        self.out.as_mut()
+    }
+
+    // set after the prelude is loaded, so only expressions evaluated from now on are reported
+    pub fn set_observer(&mut self, observer: impl Observer + 'static) {
+        self.observer = Some(Box::new(observer));
+    }
+
+    pub fn clear_observer(&mut self) {
+        self.observer = None;
+    }
+
+    // makes a host function available to lisp code as a global procedure
+    pub fn register(&mut self, name: &str, arity: Arity, fun: impl Fn(&mut Evaluator, Vec<SExpression>) -> Result<SExpression, CompilerError> + 'static) {
+        self.env.borrow_mut().define(name.to_string(), SExpression::Builtin(Builtin::new(name, arity, fun)));
+    }
+
+    // limits apply from now on, to everything evaluated until the next reset_usage
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+        self.reset_usage();
+    }
+
+    pub fn reset_usage(&mut self) {
+        self.usage = Usage::default();
+    }
+
+    // stack of the thread the evaluator runs on. Without a depth limit the tree walker stops deep recursion
+    // before it uses all of it, a bigger stack allows deeper recursion
+    pub fn set_stack_size(&mut self, bytes: usize) {
+        self.stack_budget = limits::stack_budget(bytes);
+    }
+
+    // removes the builtins that do input or output and stops import from reading files, for running untrusted code
+    pub fn disable_io(&mut self) {
+        self.clear_search_paths();
+        let mut global = self.env.borrow_mut();
+        for b in builtins::io_lib() {
+            if matches!(global.env.get(&b.name), Some(SExpression::Builtin(current)) if current.name == b.name) {
+                global.env.remove(&b.name);
+            }
+        }
+    }
+
+    pub(crate) fn step(&mut self) -> Result<(), CompilerError> {
+        self.usage.steps += 1;
+        match self.limits.steps {
+            Some(max) if self.usage.steps > max => Err(CompilerError::LimitExceeded(Limit::Steps)),
+            _ => Ok(()),
+        }
+    }
+
+    // one level deeper, has to be matched with leave
+    pub(crate) fn enter(&mut self) -> Result<(), CompilerError> {
+        if self.usage.depth == 0 {
+            self.usage.stack = limits::stack_position();
+        }
+        match self.limits.depth {
+            Some(max) if self.usage.depth >= max => Err(CompilerError::LimitExceeded(Limit::Depth)),
+            // the tree walker recurses on the native stack, it fails before running out of it
+            None if limits::stack_position().abs_diff(self.usage.stack) > self.stack_budget => Err(CompilerError::LimitExceeded(Limit::Depth)),
+            _ => {
+                self.usage.depth += 1;
+                Ok(())
             },
-            SExpression::List(ref v) => {
-                let first = v.get(0);
-                let first = match first {
-                    Some(s) => s,
-                    None => return Ok(SExpression::Void),
-                };
+        }
+    }
 
-                match first {
-                    SExpression::Void => Err(CompilerError::InvalidList(e)),
-                    SExpression::Number(_) => Err(CompilerError::InvalidList(e)),
-                    SExpression::Boolean(_) => Err(CompilerError::InvalidList(e)),
-                    SExpression::String(_) => Err(CompilerError::InvalidList(e)),
-                    SExpression::Identifier(id) if id == "+" => self.plus(e),
-                    SExpression::Identifier(id) if id == "=" => self.equal(e),
-                    SExpression::Identifier(id) if id == "!=" => self.not_equal(e),
-                    SExpression::Identifier(id) if id == "if" => self.if_expression(e),
-                    SExpression::Identifier(id) if id == "define" => self.store_expression(e),
-                    SExpression::Identifier(_) => self.eval_expr(first.clone()),
-                    SExpression::List(_) => self.eval_expr(first.clone()),
-                }
+    pub(crate) fn leave(&mut self) {
+        self.usage.depth -= 1;
+    }
+
+    // accounts for memory used by a new value, host functions building big values should call it too
+    pub fn allocate(&mut self, n: usize) -> Result<(), CompilerError> {
+        self.usage.allocations = self.usage.allocations.saturating_add(n);
+        match self.limits.allocations {
+            Some(max) if self.usage.allocations > max => Err(CompilerError::LimitExceeded(Limit::Allocations)),
+            _ => Ok(()),
+        }
+    }
+
+    // frames, vectors and hash tables that are only reachable from cycles are freed on their own
+    // while allocating, this frees them right away
+    pub fn collect_garbage(&mut self) -> GcStats {
+        self.heap.collect();
+        self.heap.stats()
+    }
+
+    pub fn gc_stats(&self) -> GcStats {
+        self.heap.stats()
+    }
+
+    // new scope inside outer
+    pub(crate) fn extend(&mut self, outer: &EnvRef) -> EnvRef {
+        self.heap.env(Env::new(Some(outer.clone())))
+    }
+
+    // sorted names of all global bindings, builtins included
+    pub fn globals(&self) -> Vec<String> {
+        let mut names = self.env.borrow().env.keys().cloned().collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    pub fn lookup(&self, name: &str) -> Option<SExpression> {
+        self.env.borrow().get(name)
+    }
+
+    // evaluates a top level expression, definitions are kept for the following ones
+    pub fn eval(&mut self, e: SExpression) -> Result<SExpression, CompilerError> {
+        self.error_context = None;
+        let global = self.env.clone();
+        self.eval_declaration(e, &global)
+    }
+
+    // expands and evaluates an expression in env, declarations are handled by eval_declaration
+    // errors name identifiers the way they are written, not with the names expansion gave them
+    pub(crate) fn eval_in(&mut self, e: SExpression, env: &EnvRef) -> Result<SExpression, CompilerError> {
+        let e = self.expander.expand(&e, env).map_err(expander::original_names)?;
+        let result = match self.backend {
+            Backend::TreeWalker => self.eval_expr(e, env),
+            Backend::Vm => vm::run(self, &e, env),
+        };
+        result.map_err(expander::original_names)
+    }
+
+    pub fn error_context(&self) -> Option<&SExpression> {
+        self.error_context.as_ref()
+    }
+
+    fn eval_expr(&mut self, e: SExpression, env: &EnvRef) -> Result<SExpression, CompilerError> {
+        self.enter()?;
+        let result = match self.observer {
+            Some(_) => self.eval_observed(e, env),
+            None => self.eval_loop(e, env),
+        };
+        self.leave();
+        result
+    }
+
+    // kept out of eval_expr, the clone of e would make every frame bigger
+    fn eval_observed(&mut self, e: SExpression, env: &EnvRef) -> Result<SExpression, CompilerError> {
+        let original = e.clone();
+        let result = self.eval_loop(e, env);
+        if let Some(observer) = &mut self.observer {
+            observer.leave(&original, &result, self.usage.depth);
+        }
+        result
+    }
+
+    fn observe(&mut self, e: &SExpression, env: &EnvRef) {
+        if let Some(observer) = &mut self.observer {
+            observer.enter(e, &Scope::new(env), self.usage.depth);
+        }
+    }
+
+    // loops instead of recursing for expressions in tail position, so tail calls run in constant stack
+    fn eval_loop(&mut self, e: SExpression, env: &EnvRef) -> Result<SExpression, CompilerError> {
+        let mut e = e;
+        let mut env = env.clone();
+        // list that left `e` in its tail position, reported for errors of atoms
+        let mut caller = None;
+        loop {
+            if let Err(error) = self.step() {
+                self.error_context = self.error_context.take().or(caller);
+                return Err(error);
+            }
+            if self.observer.is_some() {
+                self.observe(&e, &env);
+            }
+            match e {
+                // one arm for all values keeps the frame small in debug builds, deep recursion runs out of stack otherwise
+                SExpression::Void | SExpression::Number(_) | SExpression::Boolean(_) | SExpression::String(_) | SExpression::Char(_)
+                | SExpression::Vector(_) | SExpression::HashTable(_) | SExpression::Lambda(_) | SExpression::Builtin(_) | SExpression::Condition(_) => return Ok(e),
+                SExpression::DottedList(..) | SExpression::Pair(_) => {
+                    self.error_context = self.error_context.take().or(caller);
+                    return Err(CompilerError::InvalidList(e));
+                },
+                SExpression::Identifier(id) => {
+                    let value = env.borrow().get(&id);
+                    return match value {
+                        Some(s) => Ok(s),
+                        None => {
+                            self.error_context = self.error_context.take().or(caller);
+                            Err(CompilerError::UnknownSymbol(id))
+                        },
+                    }
+                },
+                SExpression::List(_) => match self.eval_list(&e, &env) {
+                    Ok(Step::Done(result)) => return Ok(result),
+                    Ok(Step::Tail(next, next_env)) => {
+                        caller = Some(std::mem::replace(&mut e, next));
+                        env = next_env;
+                    },
+                    Err(error) => {
+                        // the innermost list that failed, so the error can be pointed at in the source
+                        if self.error_context.is_none() {
+                            self.error_context = Some(e);
+                        }
+                        return Err(error);
+                    },
+                },
+            }
+        }
+    }
+
+    fn eval_list(&mut self, e: &SExpression, env: &EnvRef) -> Result<Step, CompilerError> {
+        let first = match e {
+            SExpression::List(v) => v.first(),
+            _ => return Err(CompilerError::InvalidList(e.clone())),
+        };
+        let first = match first {
+            Some(s) => s,
+            None => return Ok(Step::Done(SExpression::Void)),
+        };
+
+        match first {
+            SExpression::Void | SExpression::Number(_) | SExpression::Boolean(_) | SExpression::String(_) | SExpression::Char(_)
+            | SExpression::Vector(_) | SExpression::HashTable(_) | SExpression::Condition(_) | SExpression::DottedList(..) | SExpression::Pair(_) => Err(CompilerError::InvalidList(e.clone())),
+            SExpression::Identifier(id) if id == "quote" => quote(e).map(Step::Done),
+            SExpression::Identifier(id) if id == "and" => self.and(e, env),
+            SExpression::Identifier(id) if id == "or" => self.or(e, env),
+            SExpression::Identifier(id) if id == "if" => self.if_expression(e, env),
+            SExpression::Identifier(id) if id == "cond" => self.cond_expression(e, env),
+            SExpression::Identifier(id) if id == "case" => self.case_expression(e, env),
+            SExpression::Identifier(id) if id == "begin" => self.begin_expression(e, env),
+            SExpression::Identifier(id) if id == "do" => self.do_expression(e, env),
+            SExpression::Identifier(id) if id == "define" => self.store_expression(e, env).map(Step::Done),
+            SExpression::Identifier(id) if id == "set!" => self.set_expression(e, env).map(Step::Done),
+            SExpression::Identifier(id) if id == "lambda" => self.lambda_expression(e, env).map(Step::Done),
+            SExpression::Identifier(id) if id == "let" => self.let_expression(e, env),
+            SExpression::Identifier(id) if id == "letrec" => self.letrec_expression(e, env),
+            // macros of the prelude call procedures they refer to directly
+            SExpression::Identifier(_) | SExpression::List(_) | SExpression::Lambda(_) | SExpression::Builtin(_) => self.apply(e, env),
+        }
+    }
+
+    // evaluates all but the last expression, which is left in tail position
+    fn eval_body(&mut self, body: &[SExpression], env: &EnvRef) -> Result<Step, CompilerError> {
+        let (last, init) = match body.split_last() {
+            Some(s) => s,
+            None => return Ok(Step::Done(SExpression::Void)),
+        };
+        for expr in init {
+            self.eval_expr(expr.clone(), env)?;
+        }
+        Ok(Step::Tail(last.clone(), env.clone()))
+    }
+
+    fn eval_args(&mut self, v: &[SExpression], env: &EnvRef) -> Result<Vec<SExpression>, CompilerError> {
+        // a loop instead of collect, the iterator adapters take a lot of stack in debug builds
+        let mut args = Vec::with_capacity(v.len());
+        for a in v {
+            args.push(self.eval_expr(a.clone(), env)?);
+        }
+        Ok(args)
+    }
+
+    // returns the first false value, or the last one. Remaining arguments are not evaluated
+    fn and(&mut self, e: &SExpression, env: &EnvRef) -> Result<Step, CompilerError> {
+        let (_, args) = split_call(e)?;
+        let (last, init) = match args.split_last() {
+            Some(s) => s,
+            None => return Ok(Step::Done(SExpression::Boolean(true))),
+        };
+        for a in init {
+            let result = self.eval_expr(a.clone(), env)?;
+            if !is_truthy(&result) {
+                return Ok(Step::Done(result));
             }
         }
+        Ok(Step::Tail(last.clone(), env.clone()))
     }
 
-    fn eval_to_number(&mut self, e: &SExpression) -> Result<i32, CompilerError> {
+    // returns the first true value, or the last one. Remaining arguments are not evaluated
+    fn or(&mut self, e: &SExpression, env: &EnvRef) -> Result<Step, CompilerError> {
+        let (_, args) = split_call(e)?;
+        let (last, init) = match args.split_last() {
+            Some(s) => s,
+            None => return Ok(Step::Done(SExpression::Boolean(false))),
+        };
+        for a in init {
+            let result = self.eval_expr(a.clone(), env)?;
+            if is_truthy(&result) {
+                return Ok(Step::Done(result));
+            }
+        }
+        Ok(Step::Tail(last.clone(), env.clone()))
+    }
+
+    fn if_expression(&mut self, e: &SExpression, env: &EnvRef) -> Result<Step, CompilerError> {
         match e {
-            SExpression::Number(n) => Ok(*n),
-            SExpression::List(_) | SExpression::Identifier(_) => {
-                let s = self.eval_expr(e.clone())?;
-                self.eval_to_number(&s)
+            SExpression::List(v) if v.len() == 4 => {
+                let condition = self.eval_expr(v[1].clone(), env)?;
+                if is_truthy(&condition) {
+                    Ok(Step::Tail(v[2].clone(), env.clone()))
+                } else {
+                    Ok(Step::Tail(v[3].clone(), env.clone()))
+                }
             },
             _ => Err(CompilerError::InvalidList(e.clone())),
         }
     }
 
-    fn eval_to_bool(&mut self, e: &SExpression) -> Result<bool, CompilerError> {
+    // (cond (test body...)... (else body...)) - a clause without a body returns the value of its test
+    fn cond_expression(&mut self, e: &SExpression, env: &EnvRef) -> Result<Step, CompilerError> {
+        let (_, clauses) = split_call(e)?;
+        for (i, clause) in clauses.iter().enumerate() {
+            let (test, body) = match clause {
+                SExpression::List(v) if !v.is_empty() => (&v[0], &v[1..]),
+                _ => return Err(CompilerError::InvalidList(e.clone())),
+            };
+
+            if matches!(test, SExpression::Identifier(id) if id == "else") {
+                if i != clauses.len() - 1 || body.is_empty() {
+                    return Err(CompilerError::InvalidList(e.clone()));
+                }
+                return self.eval_body(body, env);
+            }
+
+            let result = self.eval_expr(test.clone(), env)?;
+            if is_truthy(&result) {
+                if body.is_empty() {
+                    return Ok(Step::Done(result));
+                }
+                return self.eval_body(body, env);
+            }
+        }
+        Ok(Step::Done(SExpression::Void))
+    }
+
+    // (case key ((datum...) body...)... (else body...)) - the key is compared to the datums with eqv? semantics
+    fn case_expression(&mut self, e: &SExpression, env: &EnvRef) -> Result<Step, CompilerError> {
+        let (key, clauses) = match e {
+            SExpression::List(v) if v.len() >= 2 => (&v[1], &v[2..]),
+            _ => return Err(CompilerError::InvalidList(e.clone())),
+        };
+        let key = self.eval_expr(key.clone(), env)?;
+        for (i, clause) in clauses.iter().enumerate() {
+            match clause {
+                SExpression::List(v) if v.len() >= 2 => match &v[0] {
+                    SExpression::List(datums) if datums.contains(&key) => return self.eval_body(&v[1..], env),
+                    SExpression::List(_) => {},
+                    SExpression::Identifier(id) if id == "else" && i == clauses.len() - 1 => return self.eval_body(&v[1..], env),
+                    _ => return Err(CompilerError::InvalidList(e.clone())),
+                },
+                _ => return Err(CompilerError::InvalidList(e.clone())),
+            }
+        }
+        Ok(Step::Done(SExpression::Void))
+    }
+
+    fn begin_expression(&mut self, e: &SExpression, env: &EnvRef) -> Result<Step, CompilerError> {
+        let (_, body) = split_call(e)?;
+        self.eval_body(body, env)
+    }
+
+    // (do ((name init step)...) (test result...) body...) - every iteration gets fresh bindings
+    fn do_expression(&mut self, e: &SExpression, env: &EnvRef) -> Result<Step, CompilerError> {
+        let (bindings, test, result, body) = split_do(e).ok_or_else(|| CompilerError::InvalidList(e.clone()))?;
+
+        let mut scope = self.extend(env);
+        for (name, init, _) in &bindings {
+            let value = self.eval_expr((*init).clone(), env)?;
+            scope.borrow_mut().define(name.to_string(), value);
+        }
+        while !is_truthy(&self.eval_expr(test.clone(), &scope)?) {
+            for expr in body {
+                self.eval_expr(expr.clone(), &scope)?;
+            }
+            let next = self.extend(env);
+            for (name, _, step) in &bindings {
+                let value = match step {
+                    Some(step) => self.eval_expr((*step).clone(), &scope)?,
+                    None => scope.borrow().get(name).unwrap_or(SExpression::Void),
+                };
+                next.borrow_mut().define(name.to_string(), value);
+            }
+            scope = next;
+        }
+        self.eval_body(result, &scope)
+    }
+
+    fn store_expression(&mut self, e: &SExpression, env: &EnvRef) -> Result<SExpression, CompilerError> {
         match e {
-            SExpression::Boolean(n) => Ok(*n),
-            SExpression::List(_) | SExpression::Identifier(_) => {
-                let s = self.eval_expr(e.clone())?;
-                self.eval_to_bool(&s)
+            // (define (name args...) body...) is a sugar for (define name (lambda (args...) body...))
+            SExpression::List(v) if v.len() >= 3 && matches!(v[1], SExpression::List(_)) => {
+                match &v[1] {
+                    SExpression::List(signature) => match signature.split_first() {
+                        Some((SExpression::Identifier(symbol), params)) => {
+                            let lambda = make_lambda(params, &v[2..], env).ok_or_else(|| CompilerError::InvalidList(e.clone()))?;
+                            env.borrow_mut().define(symbol.clone(), lambda);
+                            Ok(SExpression::Void)
+                        },
+                        _ => Err(CompilerError::InvalidList(e.clone())),
+                    },
+                    _ => Err(CompilerError::InvalidList(e.clone())),
+                }
+            },
+            // rest parameters are not supported, (define (f . args) ...) is not taken for (define name value)
+            SExpression::List(v) if v.len() >= 3 && matches!(v[1], SExpression::DottedList(..)) => Err(CompilerError::InvalidList(v[1].clone())),
+            SExpression::List(v) if v.len() == 3 => {
+                match (v[1].clone(), self.eval_expr(v[2].clone(), env)?) {
+                    (SExpression::Identifier(symbol), result) => {
+                        env.borrow_mut().define(symbol, result);
+                        Ok(SExpression::Void)
+                    },
+                    _ => Err(CompilerError::InvalidList(e.clone()))
+                }                
             },
             _ => Err(CompilerError::InvalidList(e.clone())),
         }
     }
 
-    fn plus(&mut self, e: SExpression) -> Result<SExpression, CompilerError> {
+    fn set_expression(&mut self, e: &SExpression, env: &EnvRef) -> Result<SExpression, CompilerError> {
         match e {
-            SExpression::List(v) => {
-                let mut out = 0;
-                for a in &v[1..] {
-                    let num = self.eval_to_number(&a)?;
-                    out += num;
+            SExpression::List(v) if v.len() == 3 => {
+                match (v[1].clone(), self.eval_expr(v[2].clone(), env)?) {
+                    (SExpression::Identifier(symbol), result) => {
+                        if env.borrow_mut().set(&symbol, result) {
+                            Ok(SExpression::Void)
+                        } else {
+                            Err(CompilerError::UnknownSymbol(symbol))
+                        }
+                    },
+                    _ => Err(CompilerError::InvalidList(e.clone()))
                 }
-                Ok(SExpression::Number(out))
             },
-            _ => Err(CompilerError::InvalidList(e)),
+            _ => Err(CompilerError::InvalidList(e.clone())),
         }
     }
 
-    fn if_expression(&mut self, e: SExpression) -> Result<SExpression, CompilerError> {
+    fn lambda_expression(&mut self, e: &SExpression, env: &EnvRef) -> Result<SExpression, CompilerError> {
         match e {
-            SExpression::List(v) if v.len() == 4 => {
-                let condition = self.eval_to_bool(&v[1])?;
-                if condition {
-                    Ok(self.eval_expr(v[2].clone())?)
-                } else {
-                    Ok(self.eval_expr(v[3].clone())?)
-                }
+            SExpression::List(v) if v.len() >= 3 => match &v[1] {
+                SExpression::List(params) => make_lambda(params, &v[2..], env).ok_or_else(|| CompilerError::InvalidList(e.clone())),
+                _ => Err(CompilerError::InvalidList(e.clone())),
             },
-            _ => Err(CompilerError::InvalidList(e)),
+            _ => Err(CompilerError::InvalidList(e.clone())),
         }
     }
 
-    fn equal(&mut self, e: SExpression) -> Result<SExpression, CompilerError> {
-        match e {
-            SExpression::List(ref v) if v.len() == 3 => {
-                match (self.eval_expr(v[1].clone())?, self.eval_expr(v[2].clone())?) {
-                    (SExpression::Number(a), SExpression::Number(b)) => Ok(SExpression::Boolean(a==b)),
-                    (SExpression::Boolean(a), SExpression::Boolean(b)) => Ok(SExpression::Boolean(a==b)),
-                    (SExpression::String(a), SExpression::String(b)) => Ok(SExpression::Boolean(a==b)),
-                    _ => Err(CompilerError::InvalidList(e.clone()))
+    // (let ((name value)...) body...) - values are evaluated in the outer scope. In a named let
+    // (let loop ((name value)...) body...) loop is a procedure of the names, the body is its first call
+    fn let_expression(&mut self, e: &SExpression, env: &EnvRef) -> Result<Step, CompilerError> {
+        let (name, bindings, body) = split_let(e).ok_or_else(|| CompilerError::InvalidList(e.clone()))?;
+
+        let mut scope = self.extend(env);
+        if let Some(name) = name {
+            let params = bindings.iter().map(|(param, _)| SExpression::Identifier(param.to_string())).collect::<Vec<_>>();
+            let lambda = make_lambda(&params, body, &scope).ok_or_else(|| CompilerError::InvalidList(e.clone()))?;
+            scope.borrow_mut().define(name.to_string(), lambda);
+            scope = self.extend(&scope);
+        }
+        for (name, value) in bindings {
+            let value = self.eval_expr(value.clone(), env)?;
+            scope.borrow_mut().define(name.to_string(), value);
+        }
+        self.eval_body(body, &scope)
+    }
+
+    // (letrec ((name value)...) body...) - every value sees all the bindings, so they can be mutually recursive
+    fn letrec_expression(&mut self, e: &SExpression, env: &EnvRef) -> Result<Step, CompilerError> {
+        let (bindings, body) = match split_let(e) {
+            Some((None, bindings, body)) => (bindings, body),
+            _ => return Err(CompilerError::InvalidList(e.clone())),
+        };
+
+        let scope = self.extend(env);
+        for (name, _) in &bindings {
+            scope.borrow_mut().define(name.to_string(), SExpression::Void);
+        }
+        for (name, value) in bindings {
+            let value = self.eval_expr(value.clone(), &scope)?;
+            scope.borrow_mut().define(name.to_string(), value);
+        }
+        self.eval_body(body, &scope)
+    }
+
+    fn apply(&mut self, e: &SExpression, env: &EnvRef) -> Result<Step, CompilerError> {
+        let v = match e {
+            SExpression::List(v) => v,
+            _ => return Err(CompilerError::InvalidList(e.clone())),
+        };
+
+        let procedure = self.eval_expr(v[0].clone(), env)?;
+        if !matches!(procedure, SExpression::Lambda(_) | SExpression::Builtin(_)) {
+            return Err(CompilerError::InvalidList(e.clone()));
+        }
+        let args = self.eval_args(&v[1..], env)?;
+        match &procedure {
+            // the body of a lambda is a tail position of the call
+            SExpression::Lambda(lambda) => {
+                let frame = self.bind(lambda, args)?;
+                if self.observer.is_some() {
+                    self.observe_call(e, &procedure, &frame);
                 }
+                self.eval_body(&lambda.body, &frame)
+            },
+            _ => {
+                if self.observer.is_some() {
+                    self.observe_call(e, &procedure, env);
+                }
+                self.apply_procedure(procedure, args).map(Step::Done)
             },
-            _ => Err(CompilerError::InvalidList(e)),
         }
     }
 
-    fn not_equal(&mut self, e: SExpression) -> Result<SExpression, CompilerError> {
-        match self.equal(e.clone())? {
-            SExpression::Boolean(a) => Ok(SExpression::Boolean(!a)),
-            _ => Err(CompilerError::InvalidList(e)),
+    fn observe_call(&mut self, e: &SExpression, procedure: &SExpression, env: &EnvRef) {
+        if let Some(observer) = &mut self.observer {
+            observer.call(e, procedure, &Scope::new(env), self.usage.depth);
         }
     }
 
-    fn store_expression(&mut self, e: SExpression) -> Result<SExpression, CompilerError> {
-        match e {
-            SExpression::List(ref v) if v.len() == 3 => {
-                match (v[1].clone(), self.eval_expr(v[2].clone())?) {
-                    (SExpression::Identifier(symbol), result) => {
-                        self.env.env.insert(symbol, result);
-                        Ok(SExpression::Void)
-                    },
-                    _ => Err(CompilerError::InvalidList(e))
-                }                
+    // new frame with the parameters of the lambda bound to the arguments
+    pub(crate) fn bind(&mut self, lambda: &Lambda, args: Vec<SExpression>) -> Result<EnvRef, CompilerError> {
+        if lambda.params.len() != args.len() {
+            return Err(arity_mismatch("lambda", Arity::Exact(lambda.params.len()), args.len()));
+        }
+
+        let frame = self.extend(&lambda.env);
+        for (param, value) in lambda.params.iter().zip(args) {
+            frame.borrow_mut().define(param.clone(), value);
+        }
+        Ok(frame)
+    }
+
+    // calls a lambda or a builtin with already evaluated arguments
+    pub fn apply_procedure(&mut self, procedure: SExpression, args: Vec<SExpression>) -> Result<SExpression, CompilerError> {
+        match procedure {
+            SExpression::Builtin(b) => b.call(self, args),
+            SExpression::Lambda(lambda) if self.backend == Backend::Vm => vm::call(self, &lambda, args),
+            SExpression::Lambda(lambda) => {
+                let frame = self.bind(&lambda, args)?;
+                match self.eval_body(&lambda.body, &frame)? {
+                    Step::Done(result) => Ok(result),
+                    Step::Tail(e, env) => self.eval_expr(e, &env),
+                }
             },
-            _ => Err(CompilerError::InvalidList(e)),
+            other => Err(CompilerError::InvalidList(other)),
         }
     }
-    
+}
+
+// (quote x) returns x without evaluating it, lists in it are made of pairs like the ones cons builds
+fn quote(e: &SExpression) -> Result<SExpression, CompilerError> {
+    match e {
+        SExpression::List(v) if v.len() == 2 => Ok(pair::datum(&v[1])),
+        _ => Err(CompilerError::InvalidList(e.clone())),
+    }
+}
+
+// (op args...) -> (op, args)
+fn split_call(e: &SExpression) -> Result<(&str, &[SExpression]), CompilerError> {
+    match e {
+        SExpression::List(v) => match v.split_first() {
+            Some((SExpression::Identifier(op), args)) => Ok((op.as_str(), args)),
+            _ => Err(CompilerError::InvalidList(e.clone())),
+        },
+        _ => Err(CompilerError::InvalidList(e.clone())),
+    }
+}
+
+fn make_lambda(params: &[SExpression], body: &[SExpression], env: &EnvRef) -> Option<SExpression> {
+    let params = params.iter()
+        .map(|p| match p {
+            SExpression::Identifier(id) => Some(id.clone()),
+            _ => None,
+        })
+        .collect::<Option<Vec<_>>>()?;
+
+    Some(SExpression::Lambda(Lambda {
+        params,
+        body: body.into(),
+        env: env.clone(),
+        code: Rc::default(),
+    }))
+}
+
+pub(crate) type Bindings<'a> = Vec<(&'a str, &'a SExpression)>;
+
+// (let name ((name value)...) body...) -> (name, bindings, body). Only a named let has a name
+pub(crate) fn split_let(e: &SExpression) -> Option<(Option<&str>, Bindings<'_>, &[SExpression])> {
+    let v = match e {
+        SExpression::List(v) => v,
+        _ => return None,
+    };
+    match v.get(1)? {
+        SExpression::Identifier(name) if v.len() >= 4 => Some((Some(name.as_str()), split_bindings(&v[2])?, &v[3..])),
+        bindings @ SExpression::List(_) if v.len() >= 3 => Some((None, split_bindings(bindings)?, &v[2..])),
+        _ => None,
+    }
+}
+
+fn split_bindings(e: &SExpression) -> Option<Bindings<'_>> {
+    match e {
+        SExpression::List(bindings) => bindings.iter()
+            .map(|b| match b {
+                SExpression::List(pair) => match pair.as_slice() {
+                    [SExpression::Identifier(name), value] => Some((name.as_str(), value)),
+                    _ => None,
+                },
+                _ => None,
+            })
+            .collect(),
+        _ => None,
+    }
+}
+
+pub(crate) type DoBindings<'a> = Vec<(&'a str, &'a SExpression, Option<&'a SExpression>)>;
+
+// (do ((name init step)...) (test result...) body...) -> (bindings, test, result, body)
+pub(crate) fn split_do(e: &SExpression) -> Option<(DoBindings<'_>, &SExpression, &[SExpression], &[SExpression])> {
+    match e {
+        SExpression::List(v) if v.len() >= 3 => {
+            let bindings = match &v[1] {
+                SExpression::List(bindings) => bindings.iter()
+                    .map(|b| match b {
+                        SExpression::List(binding) => match binding.as_slice() {
+                            [SExpression::Identifier(name), init] => Some((name.as_str(), init, None)),
+                            [SExpression::Identifier(name), init, step] => Some((name.as_str(), init, Some(step))),
+                            _ => None,
+                        },
+                        _ => None,
+                    })
+                    .collect::<Option<Vec<_>>>()?,
+                _ => return None,
+            };
+            match &v[2] {
+                SExpression::List(exit) if !exit.is_empty() => Some((bindings, &exit[0], &exit[1..], &v[3..])),
+                _ => None,
+            }
+        },
+        _ => None,
+    }
 }
 
 pub fn eval(ast: Vec<SExpression>) -> Result<Vec<SExpression>, CompilerError> {
@@ -141,77 +763,126 @@ pub fn eval(ast: Vec<SExpression>) -> Result<Vec<SExpression>, CompilerError> {
     let mut out = vec![];
     
     for e in ast {
-        out.push(evaluator.eval_expr(e)?);
+        out.push(evaluator.eval(e)?);
     }
 
     Ok(out)
 }
 
+pub(crate) type EnvRef = Rc<RefCell<Env>>;
 
-struct Env {
-    // env: HashMap<String, Box<dyn Fn(&Evaluator,SExpression)->SExpression>>
-    env: HashMap<String, SExpression>
+// single scope, linked to the scope it was created in
+pub(crate) struct Env {
+    pub(crate) env: HashMap<String, SExpression>,
+    pub(crate) outer: Option<EnvRef>,
 }
 
 impl Env {
     fn std_env() -> Self {
-        // let x: Vec<(String, Box<dyn Fn(&Evaluator, SExpression) -> SExpression>)> = vec![
-        //     ("+".to_string(), Box::new(plus)),
-        //     ("-".to_string(), Box::new(minus)),
-        // ];
+        let builtins = builtins::std_lib()
+            .into_iter()
+            .chain(builtins::io_lib())
+            .map(|b| (b.name.clone(), SExpression::Builtin(b)));
 
         Self { 
-            env: HashMap::new()//from_iter(x)
+            env: HashMap::from_iter(builtins),
+            outer: None,
+        }
+    }
+
+    pub(crate) fn new(outer: Option<EnvRef>) -> Self {
+        Self { env: HashMap::new(), outer }
+    }
+
+    pub(crate) fn get(&self, id: &str) -> Option<SExpression> {
+        match self.env.get(id) {
+            Some(v) => Some(v.clone()),
+            None => self.outer.as_ref().and_then(|o| o.borrow().get(id)),
+        }
+    }
+
+    pub(crate) fn define(&mut self, id: String, value: SExpression) {
+        self.env.insert(id, value);
+    }
+
+    // updates the innermost existing binding, returns false if there is none
+    pub(crate) fn set(&mut self, id: &str, value: SExpression) -> bool {
+        match self.env.get_mut(id) {
+            Some(v) => {
+                *v = value;
+                true
+            },
+            None => match &self.outer {
+                Some(o) => o.borrow_mut().set(id, value),
+                None => false,
+            },
         }
     }
 }
 
 #[cfg(test)]
 mod test {
-    use crate::{parser::{parse}, lexer::lex};
+    use crate::{parser::{parse}, lexer::lex, number::Number, interpreter::Captured};
 
     use super::*;
 
     fn run(input: &str) -> Vec<SExpression> {
-        eval(parse(lex(input)).unwrap()).unwrap()
+        eval_both(input).unwrap()
+    }
+
+    // runs the program with both backends, they have to agree on the results and on the error context
+    fn eval_both(input: &str) -> Result<Vec<SExpression>, CompilerError> {
+        let ast = parse(lex(input)).unwrap();
+        let mut runs = vec![];
+        for backend in [Backend::TreeWalker, Backend::Vm] {
+            let mut evaluator = Evaluator::with_backend(backend);
+            let result = ast.iter().map(|e| evaluator.eval(e.clone())).collect::<Result<Vec<_>, _>>();
+            runs.push((result, evaluator.error_context.take()));
+        }
+
+        let vm = runs.pop().unwrap();
+        let tree = runs.pop().unwrap();
+        // compared through Debug, lambdas of different evaluators never have the same environment
+        assert_eq!(format!("{vm:?}"), format!("{tree:?}"), "backends disagree on {input}");
+        tree.0
     }
 
     #[test]
     fn eval_plus() {
         let r = run("(+ 1 2)");
         assert_eq!(r, vec![
-            SExpression::Number(3),
+            SExpression::Number(Number::Int(3)),
         ])
     }
 
     #[test]
     fn eval_plus_nested() {
         let r = run("(+ (+ 4 5) 2)");
-        assert_eq!(r, vec![SExpression::Number(11)])
+        assert_eq!(r, vec![SExpression::Number(Number::Int(11))])
     }
 
     #[test]
     fn eval_plus_nested2() {
         let r = run("(+ (+ 4 (+ 3 2)) 2)");
-        assert_eq!(r, vec![SExpression::Number(11)])
+        assert_eq!(r, vec![SExpression::Number(Number::Int(11))])
     }
 
     #[test]
     fn if_expression() {
         let r = run(r#"(if (= 1 2) "ok" "not ok")"#);
-        assert_eq!(r, vec![SExpression::String("\"not ok\"".to_owned())])
+        assert_eq!(r, vec![SExpression::String("not ok".into())])
     }
 
     #[test]
     fn if_expression_2() {
         let r = run(r#"(if (= (+ 1 1) 2) "ok" "not ok")"#);
-        assert_eq!(r, vec![SExpression::String("\"ok\"".to_owned())])
+        assert_eq!(r, vec![SExpression::String("ok".into())])
     }
 
     #[test]
     fn if_expression_3() {
         let r = run(r#"(if (= (+ 1 1) 2) (+ 10 10) 0)"#);
-        assert_eq!(r, vec![SExpression::Number(20)])
+        assert_eq!(r, vec![SExpression::Number(Number::Int(20))])
     }
 
     #[test]
@@ -236,13 +907,990 @@ mod test {
     fn define() {
         let r = run("(define a 2)
         (+ a 4)");
-        assert_eq!(r, vec![SExpression::Void, SExpression::Number(6)])
+        assert_eq!(r, vec![SExpression::Void, SExpression::Number(Number::Int(6))])
     }
     #[test]
     fn define_2() {
         let r = run("(define a 2)
         (define x (+ a 4))
         (if (= x 6) 12345 -1)");
-        assert_eq!(r, vec![SExpression::Void, SExpression::Void, SExpression::Number(12345)])
+        assert_eq!(r, vec![SExpression::Void, SExpression::Void, SExpression::Number(Number::Int(12345))])
+    }
+
+    #[test]
+    fn lambda_call() {
+        let r = run("((lambda (x y) (+ x y)) 2 3)");
+        assert_eq!(r, vec![SExpression::Number(Number::Int(5))])
+    }
+
+    #[test]
+    fn define_function() {
+        let r = run("(define (dbl x) (+ x x))
+        (dbl 2)");
+        assert_eq!(r, vec![SExpression::Void, SExpression::Number(Number::Int(4))])
+    }
+
+    #[test]
+    fn define_lambda() {
+        let r = run("(define add (lambda (a b) (+ a b)))
+        (add 4 (add 1 1))");
+        assert_eq!(r, vec![SExpression::Void, SExpression::Number(Number::Int(6))])
+    }
+
+    #[test]
+    fn recursive_function() {
+        let r = run("(define (sum n) (if (= n 0) 0 (+ n (sum (+ n -1)))))
+        (sum 10)");
+        assert_eq!(r, vec![SExpression::Void, SExpression::Number(Number::Int(55))])
+    }
+
+    #[test]
+    fn closure_captures_arguments() {
+        let r = run("(define (adder n) (lambda (x) (+ x n)))
+        (define add5 (adder 5))
+        (add5 10)");
+        assert_eq!(r, vec![SExpression::Void, SExpression::Void, SExpression::Number(Number::Int(15))])
+    }
+
+    #[test]
+    fn function_body_with_local_define() {
+        let r = run("(define (f x) (define y (+ x 1)) (+ x y))
+        (f 2)");
+        assert_eq!(r, vec![SExpression::Void, SExpression::Number(Number::Int(5))])
+    }
+
+    #[test]
+    fn call_with_wrong_arity() {
+        let r = eval_both("(define (f x) x) (f 1 2)");
+        assert_eq!(r, Err(CompilerError::ArityMismatch { name: "lambda".to_owned(), expected: Arity::Exact(1), got: 2 }))
+    }
+
+    #[test]
+    fn define_with_rest_parameter() {
+        let r = eval_both("(define (f . args) args)");
+        assert!(matches!(r, Err(CompilerError::InvalidList(SExpression::DottedList(..)))), "{r:?}");
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn call_not_a_procedure() {
+        let r = eval_both("(define x 1) (x 1)");
+        assert!(matches!(r, Err(CompilerError::InvalidList(_))))
+    }
+
+    #[test]
+    fn set_global() {
+        let r = run("(define x 1)
+        (set! x (+ x 1))
+        (+ x 0)");
+        assert_eq!(r, vec![SExpression::Void, SExpression::Void, SExpression::Number(Number::Int(2))])
+    }
+
+    #[test]
+    fn set_unknown_symbol() {
+        let r = eval_both("(set! x 1)");
+        assert_eq!(r, Err(CompilerError::UnknownSymbol("x".to_owned())))
+    }
+
+    #[test]
+    fn let_expression() {
+        let r = run("(let ((x 1) (y 2)) (+ x y))");
+        assert_eq!(r, vec![SExpression::Number(Number::Int(3))])
+    }
+
+    #[test]
+    fn let_values_see_outer_scope() {
+        let r = run("(define x 10)
+        (let ((x 1) (y x)) (+ x y))");
+        assert_eq!(r, vec![SExpression::Void, SExpression::Number(Number::Int(11))])
+    }
+
+    #[test]
+    fn named_let() {
+        let r = show("(let loop ((i 0) (acc '())) (if (= i 3) acc (loop (+ i 1) (cons i acc))))
+            (define (count n) (let loop ((i 0)) (if (= i n) i (loop (+ i 1)))))
+            (count 100000)
+            (define loop 5)
+            (let loop ((i loop)) i)
+            (let loop ((loop 1)) loop)");
+        assert_eq!(r, vec!["(2 1 0)", "#<void>", "100000", "#<void>", "5", "1"]);
+        assert!(matches!(eval_both("(let loop ((i 0)))"), Err(CompilerError::InvalidList(_))));
+        assert!(matches!(eval_both("(letrec loop ((i 0)) i)"), Err(CompilerError::InvalidList(_))));
+    }
+
+    #[test]
+    fn let_bindings_are_local() {
+        let r = eval_both("(let ((x 1)) x) (+ x 0)");
+        assert_eq!(r, Err(CompilerError::UnknownSymbol("x".to_owned())))
+    }
+
+    #[test]
+    fn let_star_expression() {
+        let r = run("(let* ((x 1) (y (+ x 1))) (+ x y))");
+        assert_eq!(r, vec![SExpression::Number(Number::Int(3))])
+    }
+
+    #[test]
+    fn letrec_mutual_recursion() {
+        let r = run("(letrec ((even (lambda (n) (if (= n 0) true (odd (+ n -1)))))
+                              (odd (lambda (n) (if (= n 0) false (even (+ n -1))))))
+            (even 10))");
+        assert_eq!(r, vec![SExpression::Boolean(true)])
+    }
+
+    #[test]
+    fn nested_scopes_shadowing() {
+        let r = run("(define x 1)
+        (let ((x 2))
+            (let ((x 3)) x))
+        (let ((x 2))
+            (let ((y 3)) x))
+        (+ x 0)");
+        assert_eq!(r, vec![SExpression::Void, SExpression::Number(Number::Int(3)), SExpression::Number(Number::Int(2)), SExpression::Number(Number::Int(1))])
+    }
+
+    #[test]
+    fn set_updates_innermost_binding() {
+        let r = run("(define x 1)
+        (let ((x 2))
+            (set! x 5)
+            x)
+        (+ x 0)");
+        assert_eq!(r, vec![SExpression::Void, SExpression::Number(Number::Int(5)), SExpression::Number(Number::Int(1))])
+    }
+
+    #[test]
+    fn closure_shares_defining_frame() {
+        let r = run("(define (make-counter)
+            (let ((count 0))
+                (lambda () (set! count (+ count 1)) count)))
+        (define c1 (make-counter))
+        (define c2 (make-counter))
+        (c1)
+        (c1)
+        (c2)");
+        assert_eq!(r[3..], vec![SExpression::Number(Number::Int(1)), SExpression::Number(Number::Int(2)), SExpression::Number(Number::Int(1))])
+    }
+
+    #[test]
+    fn closure_sees_later_changes_in_outer_scope() {
+        let r = run("(define y 1)
+        (define (get-y) y)
+        (set! y 2)
+        (get-y)");
+        assert_eq!(r[3], SExpression::Number(Number::Int(2)))
+    }
+
+    #[test]
+    fn lexical_not_dynamic_scope() {
+        let r = run("(define x 1)
+        (define (get-x) x)
+        (define (f x) (get-x))
+        (f 100)");
+        assert_eq!(r[3], SExpression::Number(Number::Int(1)))
+    }
+
+    #[test]
+    fn arithmetic() {
+        let r = run("(- 10 3 2) (- 5) (* 2 3 4) (*) (/ 20 2 5) (+)");
+        assert_eq!(r, vec![
+            SExpression::Number(Number::Int(5)),
+            SExpression::Number(Number::Int(-5)),
+            SExpression::Number(Number::Int(24)),
+            SExpression::Number(Number::Int(1)),
+            SExpression::Number(Number::Int(2)),
+            SExpression::Number(Number::Int(0)),
+        ])
+    }
+
+    fn show(input: &str) -> Vec<String> {
+        run(input).iter().map(|r| r.to_string()).collect()
+    }
+
+    #[test]
+    fn integer_overflow_promotes_to_bignum() {
+        let r = show("(+ 2147483647 1) (+ 9223372036854775807 1) (- (+ 9223372036854775807 1) 1) (* 99999999999 99999999999 99999999999) (- -9223372036854775808)");
+        assert_eq!(r, vec!["2147483648", "9223372036854775808", "9223372036854775807", "999999999970000000000299999999999", "9223372036854775808"]);
+        assert_eq!(run("(- (+ 9223372036854775807 1) 1)"), vec![SExpression::Number(Number::Int(i64::MAX))]);
+    }
+
+    #[test]
+    fn exact_rationals() {
+        let r = show("(/ 1 3) (/ 7 2) (/ 6 3) (+ (/ 1 3) (/ 2 3)) (* 2/3 3/4) (/ 0.5) 4/6 (exact->inexact 1/4) (inexact->exact 0.5) (numerator 6/4) (denominator 6/4)");
+        assert_eq!(r, vec!["1/3", "7/2", "2", "1", "1/2", "2.0", "2/3", "0.25", "1/2", "3", "2"]);
+    }
+
+    #[test]
+    fn floats() {
+        let r = show("3.14 1e-5 (+ 1 2.5) (* 1.5 2) (- 0.5 1/2) (/ 1 0.0) (= 1 1.0) (< 1/2 0.6 1) (max 1 2.0) (min 1 2)");
+        assert_eq!(r, vec!["3.14", "1e-5", "3.5", "3.0", "0.0", "+inf.0", "true", "true", "2.0", "1"]);
+    }
+
+    #[test]
+    fn numeric_predicates() {
+        let r = show("(integer? 2) (integer? 2.0) (integer? 1/2) (rational? 1/2) (rational? +inf.0) (exact? 1/2) (inexact? 1.0) (number? 'a) (real? 1.5)
+            (zero? 0.0) (positive? -1/2) (negative? -1/2) (even? 10) (odd? 10) (odd? 99999999999999999999) (nan? +nan.0)");
+        assert_eq!(r, vec!["true", "true", "false", "true", "false", "true", "true", "false", "true",
+            "true", "false", "true", "true", "false", "true", "true"]);
+    }
+
+    #[test]
+    fn rounding_and_powers() {
+        let r = show("(quotient 17 5) (quotient -17 5) (floor -7/2) (ceiling 7/2) (round 5/2) (round 2.6) (truncate -2.7) (sqrt 16) (sqrt 2) (expt 2 100) (expt 2 -2) (expt 2.0 3)");
+        assert_eq!(r, vec!["3", "-3", "-4", "4", "2", "3.0", "-2.0", "4", "1.4142135623730951", "1267650600228229401496703205376", "1/4", "8.0"]);
+        assert!(matches!(eval_both("(quotient 1 1/2)"), Err(CompilerError::InvalidList(_))));
+        assert_eq!(eval_both("(quotient 1 0)"), Err(CompilerError::DivisionByZero));
+    }
+
+    #[test]
+    fn modulo_and_remainder() {
+        let r = run("(modulo 13 4) (remainder 13 4) (modulo -13 4) (remainder -13 4) (modulo 13 -4) (remainder 13 -4) (modulo -12 4)");
+        assert_eq!(r, vec![
+            SExpression::Number(Number::Int(1)),
+            SExpression::Number(Number::Int(1)),
+            SExpression::Number(Number::Int(3)),
+            SExpression::Number(Number::Int(-1)),
+            SExpression::Number(Number::Int(-3)),
+            SExpression::Number(Number::Int(1)),
+            SExpression::Number(Number::Int(0)),
+        ])
+    }
+
+    #[test]
+    fn abs_min_max() {
+        let r = run("(abs -7) (abs 7) (min 3 1 2) (max 3 (+ 2 2) 1) (min 5)");
+        assert_eq!(r, vec![
+            SExpression::Number(Number::Int(7)),
+            SExpression::Number(Number::Int(7)),
+            SExpression::Number(Number::Int(1)),
+            SExpression::Number(Number::Int(4)),
+            SExpression::Number(Number::Int(5)),
+        ])
+    }
+
+    #[test]
+    fn comparisons() {
+        let r = run("(< 1 2 3) (< 1 3 2) (<= 1 1 2) (> 3 2 1) (>= 3 3 4) (< 1)");
+        assert_eq!(r, vec![
+            SExpression::Boolean(true),
+            SExpression::Boolean(false),
+            SExpression::Boolean(true),
+            SExpression::Boolean(true),
+            SExpression::Boolean(false),
+            SExpression::Boolean(true),
+        ])
+    }
+
+    #[test]
+    fn equality_chains() {
+        let r = run("(= 1 1 1) (= 1 1 2) (= 2 1 1) (= 1) (= 1 1.0 2/2)");
+        assert_eq!(r, [true, false, false, true, true].map(SExpression::Boolean));
+        assert!(matches!(eval_both("(= 1 1 \"1\")"), Err(CompilerError::InvalidList(_))));
+        assert!(matches!(eval_both("(=)"), Err(CompilerError::ArityMismatch { .. })));
+    }
+
+    #[test]
+    fn logical_operators() {
+        let r = run("(not false) (not 0) (and) (and 1 2) (and 1 false 2) (or) (or false 3) (or false false)");
+        assert_eq!(r, vec![
+            SExpression::Boolean(true),
+            SExpression::Boolean(false),
+            SExpression::Boolean(true),
+            SExpression::Number(Number::Int(2)),
+            SExpression::Boolean(false),
+            SExpression::Boolean(false),
+            SExpression::Number(Number::Int(3)),
+            SExpression::Boolean(false),
+        ])
+    }
+
+    #[test]
+    fn begin() {
+        let r = run("(begin) (begin 1 2 3) (define x 1) (begin (set! x 5) (+ x 1))");
+        assert_eq!(r, vec![
+            SExpression::Void,
+            SExpression::Number(Number::Int(3)),
+            SExpression::Void,
+            SExpression::Number(Number::Int(6)),
+        ])
+    }
+
+    #[test]
+    fn cond() {
+        let r = run("
+            (define (sign n) (cond ((< n 0) 'negative) ((= n 0) 'zero) (else 'positive)))
+            (sign -2) (sign 0) (sign 7)
+            (cond (false 1)) (cond ((+ 1 2))) (cond (true (define y 2) (* y y)))");
+        assert_eq!(r[1..], vec![
+            SExpression::Identifier(String::from("negative")),
+            SExpression::Identifier(String::from("zero")),
+            SExpression::Identifier(String::from("positive")),
+            SExpression::Void,
+            SExpression::Number(Number::Int(3)),
+            SExpression::Number(Number::Int(4)),
+        ])
+    }
+
+    #[test]
+    fn cond_else_must_be_last() {
+        assert!(matches!(eval_both("(cond (else 1) (true 2))"), Err(CompilerError::InvalidList(_))));
+    }
+
+    #[test]
+    fn case() {
+        let r = show("
+            (define (kind x) (case x ((1 2 3) 'small) ((a b) 'letter) ((\"s\") 'string) (else 'other)))
+            (kind 2) (kind 'b) (kind \"s\") (kind 4) (kind 2.0)
+            (define calls 0)
+            (case (begin (set! calls (+ calls 1)) 5) ((1) 'one) ((5) (define z 2) (* z 10)))
+            calls (case 9 ((1) 'one))");
+        assert_eq!(r[1..], vec!["small", "letter", "string", "other", "other", "#<void>", "20", "1", "#<void>"]);
+    }
+
+    #[test]
+    fn case_clauses_are_checked() {
+        assert!(matches!(eval_both("(case 1 (else 1) ((1) 2))"), Err(CompilerError::InvalidList(_))));
+        assert!(matches!(eval_both("(case 1 (1 2))"), Err(CompilerError::InvalidList(_))));
+        assert!(matches!(eval_both("(case 1 ((1)))"), Err(CompilerError::InvalidList(_))));
+        assert!(matches!(eval_both("(case)"), Err(CompilerError::InvalidList(_))));
+        // clauses after the matching one are not looked at
+        assert_eq!(show("(case 1 ((1) 'one) oops)"), vec!["one"]);
+    }
+
+    #[test]
+    fn when_and_unless() {
+        let r = show("
+            (define x 0)
+            (when (> 3 2) (set! x 1) (+ x 1)) (when false (car '())) x
+            (unless (> 3 2) (car '())) (unless false (set! x 7) x)");
+        assert_eq!(r[1..], vec!["2", "#<void>", "1", "#<void>", "7"]);
+        assert!(matches!(eval_both("(when true)"), Err(CompilerError::InvalidList(_))));
+        assert!(matches!(eval_both("(unless)"), Err(CompilerError::InvalidList(_))));
+    }
+
+    #[test]
+    fn do_loop() {
+        let r = show("
+            (do ((i 0 (+ i 1)) (acc '() (cons i acc))) ((= i 4) acc))
+            (define v 0)
+            (do ((i 1 (+ i 1))) ((> i 3)) (set! v (+ v i)))
+            v
+            (define fs '())
+            (do ((i 0 (+ i 1)) (n 10)) ((= i 3) n) (set! fs (cons (lambda () i) fs)))
+            (list ((car fs)) ((car (cdr fs))) ((car (cdr (cdr fs)))))
+            (do ((i 0 (+ i 1))) (true 'a 'b) (car '()))");
+        assert_eq!(r, vec!["(3 2 1 0)", "#<void>", "#<void>", "6", "#<void>", "10", "(2 1 0)", "b"]);
+    }
+
+    #[test]
+    fn do_is_checked() {
+        assert!(matches!(eval_both("(do ((i 0 1 2)) (true))"), Err(CompilerError::InvalidList(_))));
+        assert!(matches!(eval_both("(do ((i 0)) ())"), Err(CompilerError::InvalidList(_))));
+        assert!(matches!(eval_both("(do (i) (true))"), Err(CompilerError::InvalidList(_))));
+        assert_eq!(eval_both("(do ((i 0 (+ i 1))) ((= i 2) j))"), Err(CompilerError::UnknownSymbol(String::from("j"))));
+    }
+
+    #[test]
+    fn derived_forms_in_tail_position() {
+        let r = show("
+            (define (a n) (when (> n 0) (a (- n 1))))
+            (a 100000)
+            (define (b n) (unless (= n 0) (b (- n 1))))
+            (b 100000)
+            (define (c n) (case (if (= n 0) 0 1) ((0) 'done) (else (c (- n 1)))))
+            (c 100000)
+            (define (d n) (do ((i 0 (+ i 1))) ((= i 1) (if (= n 0) 'done (d (- n 1))))))
+            (d 100000)");
+        assert_eq!(r[1..], vec!["#<void>", "#<void>", "#<void>", "#<void>", "done", "#<void>", "done"]);
+    }
+
+    #[test]
+    fn define_syntax() {
+        let r = show("
+            (define-syntax my-if (syntax-rules (then else) ((_ c then t else e) (cond (c t) (else e)))))
+            (my-if (> 2 1) then 'yes else 'no)
+            (define-syntax my-list (syntax-rules () ((_ (k v) ...) (list (cons 'k v) ...))))
+            (my-list (a 1) (b (+ 1 1)))
+            (define-syntax while
+              (syntax-rules () ((_ c body ...) (letrec ((loop (lambda () (when c body ... (loop))))) (loop)))))
+            (define i 0)
+            (while (< i 5) (set! i (+ i 1)))
+            i");
+        assert_eq!(r, vec!["#<void>", "yes", "#<void>", "((a . 1) (b . 2))", "#<void>", "#<void>", "#<void>", "5"]);
+    }
+
+    #[test]
+    fn macros_are_hygienic() {
+        let r = show("
+            (define-syntax swap! (syntax-rules () ((_ a b) (let ((tmp a)) (set! a b) (set! b tmp)))))
+            (define tmp 1)
+            (define y 2)
+            (swap! tmp y)
+            (list tmp y)
+            (define-syntax my-or (syntax-rules () ((_) false) ((_ e) e) ((_ e r ...) (let ((t e)) (if t t (my-or r ...))))))
+            (define t 5)
+            (my-or false t)
+            (define-syntax with-helper (syntax-rules () ((_ e) ((lambda () (define x 10) (+ x e))))))
+            (define x 1)
+            (with-helper x)");
+        assert_eq!(r[4], "(2 1)");
+        assert_eq!(r[7], "5");
+        assert_eq!(r[10], "11");
+    }
+
+    #[test]
+    fn errors_name_identifiers_as_written_in_the_macro() {
+        let r = eval_both("(define-syntax bad (syntax-rules () ((_) (let ((tmp 1)) (tmp 2))))) (bad)");
+        assert_eq!(r, Err(CompilerError::InvalidList(SExpression::List(vec![
+            SExpression::Identifier("tmp".to_owned()),
+            SExpression::Number(Number::Int(2)),
+        ]))));
+        let r = show("(define-syntax bad (syntax-rules () ((_) (let loop ((i 1)) (i)))))
+            (guard (e (true (error-object-message e))) (bad))");
+        assert_eq!(r[1], "\"invalid expression: (i)\"");
+    }
+
+    #[test]
+    fn macro_names_refer_to_the_definition() {
+        let r = show("
+            (define (catch a b) 'mine)
+            (guard (e (true 'caught)) (raise 1))
+            (define-syntax first (syntax-rules () ((_ l) (car l))))
+            (define (f car) (first (list car 2)))
+            (f 1)");
+        assert_eq!(r[1], "caught");
+        assert_eq!(r[4], "1");
+    }
+
+    #[test]
+    fn macros_quote_the_global_values_they_refer_to() {
+        let r = show("(define x '(1 2)) (define y 'a) (define-syntax get (syntax-rules () ((_) (list x y)))) (let ((x 1) (y 2)) (get))");
+        assert_eq!(r[3], "((1 2) a)");
+    }
+
+    #[test]
+    fn local_variables_hide_macros() {
+        let r = show("(define (g when) (when 1 2)) (g +) (let ((unless list)) (unless 1 2))");
+        assert_eq!(r[1..], vec!["3", "(1 2)"]);
+    }
+
+    #[test]
+    fn define_syntax_in_a_body_is_local() {
+        let r = show("
+            (define (f x) (define-syntax twice (syntax-rules () ((_ e) (* 2 e)))) (twice x))
+            (f 4)");
+        assert_eq!(r[1], "8");
+        let r = eval_both("(define (f) (define-syntax m (syntax-rules () ((_) 1))) (m)) (f) (m)");
+        assert_eq!(r, Err(CompilerError::UnknownSymbol("m".to_string())));
+    }
+
+    #[test]
+    fn macro_without_matching_rule() {
+        let r = eval_both("(define-syntax m (syntax-rules () ((_ a) a))) (m 1 2)");
+        assert!(matches!(r, Err(CompilerError::InvalidList(SExpression::List(_)))));
+        assert!(matches!(eval_both("(define-syntax m (lambda (x) x))"), Err(CompilerError::InvalidList(_))));
+    }
+
+    #[test]
+    fn characters() {
+        let r = show("
+            #\\a (char? #\\a) (char? \"a\") (char->integer #\\A) (integer->char 955)
+            (char<? #\\a #\\b #\\c) (char=? #\\a #\\b) (char-upcase #\\ä) (char-numeric? #\\7) (char-whitespace? #\\tab)
+            (string-ref \"héllo\" 1) (string->list \"ab\") (list->string (list #\\x #\\space #\\y))
+            (case (string-ref \"x\" 0) ((#\\x #\\y) 'letter) (else 'other))");
+        assert_eq!(r, vec![
+            "#\\a", "true", "false", "65", "#\\λ",
+            "true", "false", "#\\Ä", "true", "true",
+            "#\\é", "(#\\a #\\b)", "\"x y\"",
+            "letter",
+        ]);
+        assert!(matches!(eval_both("(string-ref \"ab\" 2)"), Err(CompilerError::InvalidList(_))));
+        assert!(matches!(eval_both("(integer->char 55296)"), Err(CompilerError::InvalidList(_))));
+        assert!(matches!(eval_both("(#\\a)"), Err(CompilerError::InvalidList(_))));
+    }
+
+    #[test]
+    fn vectors() {
+        let r = show("
+            #(1 (2) \"three\") (vector) (vector 1 'a) (make-vector 3) (make-vector 2 'x)
+            (define v (vector 1 2 3))
+            (vector-set! v 0 'first) (vector->list v) (vector-ref v 0) (vector-length v)
+            (define (same w) w)
+            (vector-set! (same v) 2 'last) (vector->list v)
+            (list->vector '(1 2)) (vector? v) (vector? '(1))
+            (vector-fill! v 0) v");
+        assert_eq!(r, vec![
+            "#(1 (2) \"three\")", "#()", "#(1 a)", "#(0 0 0)", "#(x x)",
+            "#<void>",
+            "#<void>", "(first 2 3)", "first", "3",
+            "#<void>",
+            "#<void>", "(first 2 last)",
+            "#(1 2)", "true", "false",
+            "#<void>", "#(0 0 0)",
+        ]);
+        assert!(matches!(eval_both("(vector-ref (vector 1) 1)"), Err(CompilerError::InvalidList(_))));
+        assert!(matches!(eval_both("(vector-ref '(1) 0)"), Err(CompilerError::InvalidList(_))));
+        assert!(matches!(eval_both("(make-vector 1 2 3)"), Err(CompilerError::ArityMismatch { expected: Arity::Between(1, 2), got: 3, .. })));
+    }
+
+    #[test]
+    fn hash_tables() {
+        let r = show("
+            (define h (make-hash-table))
+            (hash-set! h 'a 1) (hash-set! h \"a\" 2) (hash-set! h '(1 2) 3) (hash-set! h 'a 4)
+            (hash-ref h 'a) (hash-ref h \"a\") (hash-ref h (list 1 2)) (hash-ref h 'missing 'default)
+            (hash-count h) (hash-contains? h 'a) (hash-table? h) (make-hash-table)
+            (hash-keys h) (hash-values h) (hash->list h)
+            (hash-remove! h 'a) (hash-contains? h 'a) (hash-keys h)
+            (define sum 0)
+            (hash-for-each h (lambda (k v) (set! sum (+ sum v)) (hash-set! h k 0)))
+            sum (hash-values h)");
+        assert_eq!(r, vec![
+            "#<void>",
+            "#<void>", "#<void>", "#<void>", "#<void>",
+            "4", "2", "3", "default",
+            "3", "true", "true", "#<hash-table 0>",
+            "(a \"a\" (1 2))", "(4 2 3)", "((a . 4) (\"a\" . 2) ((1 2) . 3))",
+            "#<void>", "false", "((1 2) \"a\")",
+            "#<void>",
+            "#<void>",
+            "5", "(0 0)",
+        ]);
+        assert_eq!(eval_both("(hash-ref (make-hash-table) 'x)"), Err(CompilerError::InvalidList(SExpression::Identifier(String::from("x")))));
+        assert!(matches!(eval_both("(hash-set! (make-hash-table) (vector 1) 1)"), Err(CompilerError::InvalidList(_))));
+        assert!(matches!(eval_both("(hash-set! (make-hash-table) car 1)"), Err(CompilerError::InvalidList(_))));
+    }
+
+    #[test]
+    fn guard_catches_raised_values() {
+        let r = show("
+            (guard (e ((number? e) (* e 2))) (+ 1 (raise 21)))
+            (guard (e (false 'no) (else (list 'caught e))) (raise 'oops))
+            (guard (e ((error-object? e) (list (error-object-message e) (error-object-irritants e)))) (error \"bad value:\" 1 \"two\"))
+            (guard (e (true e)) (error \"bad value:\" 1))
+            (guard (e (true 'unused)) 1 2 3)
+            (define (safe-div a b) (guard (e ((error-object? e) 'undefined)) (/ a b)))
+            (list (safe-div 1 2) (safe-div 1 0))
+            (catch (lambda () (raise 1)) (lambda (e) (+ e 1)))");
+        assert_eq!(r, vec!["42", "(caught oops)", "(\"bad value:\" (1 \"two\"))", "#<error \"bad value:\" 1>", "3", "#<void>", "(1/2 undefined)", "2"]);
+    }
+
+    #[test]
+    fn guard_does_not_depend_on_program_names() {
+        let r = show("
+            (define (catch thunk handler) 'mine)
+            (define (raise e) 'mine)
+            (guard (e (true (list 'caught e))) (/ 1 0) 'body)
+            (guard (e (true 'outer)) (guard (e ((string? e) 'inner)) (error \"x\")))
+            (let ((cond 1) (catch 2) (raise 3)) (guard (e ((error-object? e) (list cond catch raise))) (car '())))");
+        assert_eq!(r[2..], vec!["(caught #<error \"division by zero\">)", "outer", "(1 2 3)"]);
+    }
+
+    #[test]
+    fn native_errors_are_catchable() {
+        let r = show("
+            (guard (e ((error-object? e) (error-object-message e))) (undefined 1))
+            (guard (e ((error-object? e) (error-object-message e))) (car '()))
+            (guard (e (true (error-object-irritants e))) ((lambda (x) x)))");
+        assert_eq!(r, vec!["\"unknown symbol: undefined\"", "\"invalid expression: ()\"", "()"]);
+    }
+
+    #[test]
+    fn unhandled_values_are_raised_again() {
+        assert_eq!(eval_both("(guard (e ((number? e) e)) (raise 'oops))"), Err(CompilerError::Raised(SExpression::Identifier(String::from("oops")))));
+        // the original error comes out, not the condition it was caught as
+        assert_eq!(eval_both("(guard (e ((number? e) e)) (undefined 1))"), Err(CompilerError::UnknownSymbol(String::from("undefined"))));
+        assert_eq!(eval_both("(guard (e (true (raise e))) (/ 1 0))"), Err(CompilerError::DivisionByZero));
+        // an inner guard that doesn't handle the value leaves it to the outer one
+        let r = show("(guard (outer (true (list 'outer outer))) (guard (inner ((null? inner) 'inner)) (raise 1)))");
+        assert_eq!(r, vec!["(outer 1)"]);
+
+        let r = eval_both("(error \"bad value:\" 42 \"x\")").unwrap_err();
+        assert_eq!(r.to_string(), "bad value: 42 \"x\"");
+        assert_eq!(eval_both("(raise '(1 2))").unwrap_err().to_string(), "uncaught exception: (1 2)");
+        assert!(matches!(eval_both("(guard)"), Err(CompilerError::InvalidList(_))));
+        assert!(matches!(eval_both("(error 1)"), Err(CompilerError::InvalidList(_))));
+    }
+
+    #[test]
+    fn limits_are_not_catchable() {
+        let limits = Limits { steps: Some(10000), ..Limits::default() };
+        for backend in [Backend::TreeWalker, Backend::Vm] {
+            let r = eval_limited(backend, limits, "(define (spin) (spin)) (guard (e (true 'caught)) (spin))");
+            assert_eq!(r, Err(CompilerError::LimitExceeded(Limit::Steps)));
+            // caught errors give back the depth they used
+            let limits = Limits { depth: Some(50), ..Limits::default() };
+            let r = eval_limited(backend, limits, "(define (retry n) (if (= n 0) 'done (begin (guard (e (true e)) (car '())) (retry (- n 1))))) (retry 500)");
+            assert_eq!(r.map(|r| r[1].to_string()), Ok(String::from("done")));
+        }
+    }
+
+    fn eval_limited(backend: Backend, limits: Limits, input: &str) -> Result<Vec<SExpression>, CompilerError> {
+        let mut evaluator = Evaluator::with_backend(backend);
+        evaluator.set_limits(limits);
+        parse(lex(input)).unwrap().into_iter().map(|e| evaluator.eval(e)).collect()
+    }
+
+    #[test]
+    fn step_limit() {
+        let limits = Limits { steps: Some(10000), ..Limits::default() };
+        for backend in [Backend::TreeWalker, Backend::Vm] {
+            let r = eval_limited(backend, limits, "(define (spin) (spin)) (spin)");
+            assert_eq!(r, Err(CompilerError::LimitExceeded(Limit::Steps)));
+            let r = eval_limited(backend, limits, "(do ((i 0 (+ i 1))) (false))");
+            assert_eq!(r, Err(CompilerError::LimitExceeded(Limit::Steps)));
+            assert!(eval_limited(backend, limits, "(define (count n) (if (= n 0) 'done (count (- n 1)))) (count 100)").is_ok());
+        }
+    }
+
+    #[test]
+    fn depth_limit() {
+        let limits = Limits { depth: Some(100), ..Limits::default() };
+        let program = "(define (depth n) (if (= n 0) 0 (+ 1 (depth (- n 1)))))";
+        for backend in [Backend::TreeWalker, Backend::Vm] {
+            let mut evaluator = Evaluator::with_backend(backend);
+            evaluator.set_limits(limits);
+            let mut eval = |input| parse(lex(input)).unwrap().into_iter().map(|e| evaluator.eval(e)).collect::<Result<Vec<_>, _>>();
+            eval(program).unwrap();
+            for _ in 0..3 {
+                assert_eq!(eval("(depth 1000)"), Err(CompilerError::LimitExceeded(Limit::Depth)));
+            }
+            // failed calls gave their depth back
+            assert_eq!(eval("(depth 10)"), Ok(vec![SExpression::Number(Number::Int(10))]));
+            // tail calls don't go deeper
+            assert!(eval("(define (count n) (if (= n 0) 'done (count (- n 1)))) (count 10000)").is_ok());
+        }
+    }
+
+    #[test]
+    fn deep_recursion_without_a_limit() {
+        let deep = "(define (deep n) (if (= n 0) 0 (+ 1 (deep (- n 1)))))";
+        assert!(eval_limited(Backend::TreeWalker, Limits::default(), &format!("{deep} (deep 50)")).is_ok());
+        // the frames of the vm are not on the native stack
+        let r = eval_limited(Backend::Vm, Limits::default(), &format!("{deep} (deep 5000)")).unwrap();
+        assert_eq!(r[1], SExpression::Number(Number::Int(5000)));
+
+        // the tree walker goes as deep as the stack of its thread allows, and stops before overflowing it
+        let run = |stack: usize, n: usize| {
+            let program = format!("{deep} (deep {n})");
+            std::thread::Builder::new().stack_size(stack).spawn(move || {
+                let mut evaluator = Evaluator::new();
+                evaluator.set_stack_size(stack);
+                let ast = parse(lex(&program)).unwrap();
+                // values can't leave the thread, they are compared as text
+                ast.into_iter().map(|e| evaluator.eval(e)).last().unwrap().map(|r| r.to_string()).map_err(|e| e.to_string())
+            }).unwrap().join().unwrap()
+        };
+        assert_eq!(run(64 << 20, 5000), Ok("5000".to_string()));
+        assert_eq!(run(4 << 20, 1_000_000), Err(CompilerError::LimitExceeded(Limit::Depth).to_string()));
+    }
+
+    #[test]
+    fn allocation_limit() {
+        let limits = Limits { allocations: Some(1000), ..Limits::default() };
+        for backend in [Backend::TreeWalker, Backend::Vm] {
+            let r = eval_limited(backend, limits, "(define (grow s) (grow (string-append s s))) (grow \"ab\")");
+            assert_eq!(r, Err(CompilerError::LimitExceeded(Limit::Allocations)));
+            let build = "(define (build n acc) (if (= n 0) acc (build (- n 1) (cons n acc))))";
+            let r = eval_limited(backend, limits, &format!("{build} (build 2000 '())"));
+            assert_eq!(r, Err(CompilerError::LimitExceeded(Limit::Allocations)));
+            // every cons is one new cell, the list it extends is not charged again
+            assert!(eval_limited(backend, limits, &format!("{build} (build 900 '())")).is_ok());
+            // lists and strings are shared, using them again doesn't copy them
+            assert!(eval_limited(backend, limits, &format!("{build} (define l (build 900 '())) (list l l l l (cdr l) (car l))")).is_ok());
+            assert!(eval_limited(backend, limits, "(define s (number->string (expt 10 600))) (list s s s s (vector s s))").is_ok());
+            let r = eval_limited(backend, limits, "(define (double l n) (if (= n 0) l (double (append l l) (- n 1)))) (double '(1) 20)");
+            assert_eq!(r, Err(CompilerError::LimitExceeded(Limit::Allocations)));
+            // charged before anything is built
+            for program in ["(make-vector 100000000 0)", "(expt 2 99999999)", "(expt 2 99999999999)"] {
+                assert_eq!(eval_limited(backend, limits, program), Err(CompilerError::LimitExceeded(Limit::Allocations)));
+            }
+            assert!(eval_limited(backend, limits, "(list 1 2 (string-append \"a\" \"b\") (expt 2 100) (make-vector 10))").is_ok());
+        }
+    }
+
+    #[test]
+    fn disable_io() {
+        let mut evaluator = Evaluator::new();
+        evaluator.disable_io();
+        let r = evaluator.eval(parse(lex("(display 1)")).unwrap().remove(0));
+        assert_eq!(r, Err(CompilerError::UnknownSymbol(String::from("display"))));
+        // user definitions with the same name are not touched
+        let mut evaluator = Evaluator::new();
+        for e in parse(lex("(define (newline) 'mine)")).unwrap() {
+            evaluator.eval(e).unwrap();
+        }
+        evaluator.disable_io();
+        assert!(evaluator.lookup("newline").is_some());
+        for name in ["display", "write", "load"] {
+            assert!(evaluator.lookup(name).is_none(), "{name} is still defined");
+        }
+    }
+
+    #[test]
+    fn tail_calls_run_in_constant_stack() {
+        let r = run("
+            (define (loop n) (if (= n 0) 0 (loop (- n 1))))
+            (loop 1000000)
+            (define (count n acc) (cond ((= n 0) acc) (else (begin (count (- n 1) (+ acc 1))))))
+            (count 100000 0)
+            (define (even? n) (if (= n 0) true (odd? (- n 1))))
+            (define (odd? n) (if (= n 0) false (even? (- n 1))))
+            (even? 100001)
+            (define (spin n) (let ((m (- n 1))) (and true (or false (if (= m 0) 'done (spin m))))))
+            (spin 100000)");
+        assert_eq!(r, vec![
+            SExpression::Void,
+            SExpression::Number(Number::Int(0)),
+            SExpression::Void,
+            SExpression::Number(Number::Int(100000)),
+            SExpression::Void,
+            SExpression::Void,
+            SExpression::Boolean(false),
+            SExpression::Void,
+            SExpression::Identifier(String::from("done")),
+        ])
+    }
+
+    #[test]
+    fn and_or_short_circuit() {
+        let r = run("(and false undefined) (or true undefined)");
+        assert_eq!(r, vec![SExpression::Boolean(false), SExpression::Boolean(true)])
+    }
+
+    #[test]
+    fn if_on_non_boolean_condition() {
+        let r = run(r#"(if 0 "yes" "no") (if (and 1 false) "yes" "no")"#);
+        assert_eq!(r, vec![SExpression::String("yes".into()), SExpression::String("no".into())])
+    }
+
+    #[test]
+    fn division_by_zero() {
+        assert_eq!(eval_both("(/ 1 0)"), Err(CompilerError::DivisionByZero));
+        assert_eq!(eval_both("(/ 0)"), Err(CompilerError::DivisionByZero));
+        assert_eq!(eval_both("(modulo 1 0)"), Err(CompilerError::DivisionByZero));
+    }
+
+    #[test]
+    fn builtin_arity_mismatch() {
+        assert_eq!(eval_both("(-)"), Err(CompilerError::ArityMismatch { name: "-".to_owned(), expected: Arity::AtLeast(1), got: 0 }));
+        assert_eq!(eval_both("(abs 1 2)"), Err(CompilerError::ArityMismatch { name: "abs".to_owned(), expected: Arity::Exact(1), got: 2 }));
+        assert_eq!(eval_both("(remainder 1)"), Err(CompilerError::ArityMismatch { name: "remainder".to_owned(), expected: Arity::Exact(2), got: 1 }));
+        assert_eq!(eval_both("(not)"), Err(CompilerError::ArityMismatch { name: "not".to_owned(), expected: Arity::Exact(1), got: 0 }));
+    }
+
+    #[test]
+    fn builtin_as_value() {
+        let r = run("(define (twice f a b) (f (f a b) b))
+        (twice + 1 2)
+        (twice * 3 3)
+        (define plus +)
+        (plus 1 1)");
+        assert_eq!(r[1..], vec![SExpression::Number(Number::Int(5)), SExpression::Number(Number::Int(27)), SExpression::Void, SExpression::Number(Number::Int(2))])
+    }
+
+    #[test]
+    fn builtin_can_be_shadowed() {
+        let r = run("(let ((+ *)) (+ 2 5))
+        (+ 2 5)
+        (define (f max) (+ max 1))
+        (f 10)");
+        assert_eq!(r, vec![SExpression::Number(Number::Int(10)), SExpression::Number(Number::Int(7)), SExpression::Void, SExpression::Number(Number::Int(11))])
+    }
+
+    #[test]
+    fn register_host_function() {
+        for backend in [Backend::TreeWalker, Backend::Vm] {
+            let mut evaluator = Evaluator::with_backend(backend);
+            evaluator.register("square", Arity::Exact(1), |_, args| match args[0] {
+                SExpression::Number(ref n) => Ok(SExpression::Number(n.mul(n))),
+                _ => Err(CompilerError::InvalidList(args[0].clone())),
+            });
+
+            let r = evaluator.eval(parse(lex("(+ 1 (square 3))")).unwrap().remove(0));
+            assert_eq!(r, Ok(SExpression::Number(Number::Int(10))));
+
+            let r = evaluator.eval(parse(lex("(square 1 2)")).unwrap().remove(0));
+            assert_eq!(r, Err(CompilerError::ArityMismatch { name: "square".to_owned(), expected: Arity::Exact(1), got: 2 }));
+        }
+    }
+
+    #[test]
+    fn host_function_calls_back_into_lisp() {
+        for backend in [Backend::TreeWalker, Backend::Vm] {
+            let mut evaluator = Evaluator::with_backend(backend);
+            evaluator.register("call-twice", Arity::Exact(2), |ev, mut args| {
+                let x = args.pop().unwrap();
+                let f = args.pop().unwrap();
+                let once = ev.apply_procedure(f.clone(), vec![x])?;
+                ev.apply_procedure(f, vec![once])
+            });
+
+            let r = evaluator.eval(parse(lex("(call-twice (lambda (x) (* x 3)) 2)")).unwrap().remove(0));
+            assert_eq!(r, Ok(SExpression::Number(Number::Int(18))));
+        }
+    }
+
+    #[test]
+    fn lambdas_are_shared_between_backends() {
+        let mut evaluator = Evaluator::with_backend(Backend::Vm);
+        evaluator.eval(parse(lex("(define (add n) (lambda (x) (+ x n)))")).unwrap().remove(0)).unwrap();
+        let add2 = evaluator.eval(parse(lex("(add 2)")).unwrap().remove(0)).unwrap();
+
+        let mut tree = Evaluator::new();
+        assert_eq!(tree.apply_procedure(add2, vec![SExpression::Number(Number::Int(3))]), Ok(SExpression::Number(Number::Int(5))));
+    }
+
+    #[test]
+    fn globals_list_builtins() {
+        let mut evaluator = Evaluator::new();
+        evaluator.register("host-fn", Arity::Exact(0), |_, _| Ok(SExpression::Void));
+        let globals = evaluator.globals();
+        for name in ["+", "modulo", "<=", "not", "host-fn"] {
+            assert!(globals.contains(&name.to_string()), "{name} missing");
+        }
+    }
+
+    #[test]
+    fn quote() {
+        let r = run("(quote (+ 1 2)) 'x '() '(1 (2 3))");
+        assert_eq!(r, vec![
+            SExpression::List(vec![SExpression::Identifier("+".to_owned()), SExpression::Number(Number::Int(1)), SExpression::Number(Number::Int(2))]),
+            SExpression::Identifier("x".to_owned()),
+            SExpression::List(vec![]),
+            SExpression::List(vec![SExpression::Number(Number::Int(1)), SExpression::List(vec![SExpression::Number(Number::Int(2)), SExpression::Number(Number::Int(3))])]),
+        ])
+    }
+
+    #[test]
+    fn cons_car_cdr() {
+        let r = run("(cons 1 '(2 3)) (cons 1 2) (car '(1 2)) (cdr '(1 2)) (cdr '(1)) (cdr (cons 1 2)) (car (cons 1 2)) (cdr '(1 2 . 3))");
+        assert_eq!(r, vec![
+            SExpression::List(vec![SExpression::Number(Number::Int(1)), SExpression::Number(Number::Int(2)), SExpression::Number(Number::Int(3))]),
+            SExpression::DottedList(vec![SExpression::Number(Number::Int(1))], Box::new(SExpression::Number(Number::Int(2)))),
+            SExpression::Number(Number::Int(1)),
+            SExpression::List(vec![SExpression::Number(Number::Int(2))]),
+            SExpression::List(vec![]),
+            SExpression::Number(Number::Int(2)),
+            SExpression::Number(Number::Int(1)),
+            SExpression::DottedList(vec![SExpression::Number(Number::Int(2))], Box::new(SExpression::Number(Number::Int(3)))),
+        ])
+    }
+
+    #[test]
+    fn car_of_empty_list() {
+        let r = eval_both("(car '())");
+        assert_eq!(r, Err(CompilerError::InvalidList(SExpression::List(vec![]))))
+    }
+
+    #[test]
+    fn list_predicates() {
+        let r = run("(null? '()) (null? '(1)) (null? 1) (pair? '(1)) (pair? (cons 1 2)) (pair? '())");
+        assert_eq!(r, vec![
+            SExpression::Boolean(true),
+            SExpression::Boolean(false),
+            SExpression::Boolean(false),
+            SExpression::Boolean(true),
+            SExpression::Boolean(true),
+            SExpression::Boolean(false),
+        ])
+    }
+
+    #[test]
+    fn list_functions() {
+        let r = run("(list 1 (+ 1 1) 'a) (list) (length '(1 2 3)) (length '()) (reverse '(1 2 3)) (append '(1) '(2 3) '() '(4)) (append '(1) 2) (append)");
+        let n = |i| SExpression::Number(Number::Int(i));
+        assert_eq!(r, vec![
+            SExpression::List(vec![n(1), n(2), SExpression::Identifier("a".to_owned())]),
+            SExpression::List(vec![]),
+            n(3),
+            n(0),
+            SExpression::List(vec![n(3), n(2), n(1)]),
+            SExpression::List(vec![n(1), n(2), n(3), n(4)]),
+            SExpression::DottedList(vec![n(1)], Box::new(n(2))),
+            SExpression::List(vec![]),
+        ])
+    }
+
+    #[test]
+    fn pairs_are_shared() {
+        let r = show("(define a '(1 2)) (define b (cons 0 a)) (eq? (cdr b) a) (eq? '(1) '(1)) (car (cdr (cdr b)))
+            (do ((i 0 (+ i 1)) (xs '() (cons i xs))) ((= i 100000) (length xs)))");
+        assert_eq!(r[2..], vec!["true", "false", "2", "100000"]);
+    }
+
+    #[test]
+    fn equivalence_predicates() {
+        let r = show("(eqv? 2 2) (eqv? 2 2.0) (eq? 'a 'a) (eq? '() '()) (eqv? \"ab\" \"ab\") (eqv? (vector) (vector))
+            (let ((v (vector 1))) (eq? v v)) (equal? (vector 1 '(2 . 3)) (vector 1 (cons 2 3))) (equal? '(1 (2)) (list 1 (list 2)))
+            (equal? '(1 2) '(1 2 3)) (equal? 2 2.0)");
+        assert_eq!(r, vec!["true", "false", "true", "true", "true", "false", "true", "true", "true", "false", "false"]);
+    }
+
+    #[test]
+    fn length_of_improper_list() {
+        let r = eval_both("(length (cons 1 2))");
+        assert!(matches!(r, Err(CompilerError::InvalidList(_))))
+    }
+
+    #[test]
+    fn process_list_recursively() {
+        let r = run("(define (sum xs) (if (null? xs) 0 (+ (car xs) (sum (cdr xs)))))
+        (define (map f xs) (if (null? xs) '() (cons (f (car xs)) (map f (cdr xs)))))
+        (sum '(1 2 3 4))
+        (map (lambda (x) (* x x)) (list 1 2 3))");
+        assert_eq!(r[2..], vec![
+            SExpression::Number(Number::Int(10)),
+            SExpression::List(vec![SExpression::Number(Number::Int(1)), SExpression::Number(Number::Int(4)), SExpression::Number(Number::Int(9))]),
+        ])
+    }
+
+    #[test]
+    fn string_builtins() {
+        let r = show(r#"(string-append "foo" "" "bar") (string-length "héllo") (substring "héllo" 1 3) (substring "abc" 3 3)
+            (string->number "1/2") (string->number "2.5") (string->number "abc") (number->string 42) (number->string 0.5)
+            (string-split "a,b,,c" ",") (string-split "abc" "") (string=? "a" "a" "a") (string=? "a" "b")"#);
+        assert_eq!(r, vec![
+            r#""foobar""#, "5", r#""él""#, r#""""#,
+            "1/2", "2.5", "false", r#""42""#, r#""0.5""#,
+            r#"("a" "b" "" "c")"#, r#"("a" "b" "c")"#, "true", "false",
+        ]);
+    }
+
+    #[test]
+    fn string_builtin_errors() {
+        let r = eval_both(r#"(substring "abc" 2 4)"#);
+        assert_eq!(r, Err(CompilerError::InvalidList(SExpression::Number(Number::Int(4)))));
+        let r = eval_both(r#"(substring "abc" 2 1)"#);
+        assert_eq!(r, Err(CompilerError::InvalidList(SExpression::Number(Number::Int(2)))));
+        let r = eval_both(r#"(string-append "a" 1)"#);
+        assert_eq!(r, Err(CompilerError::InvalidList(SExpression::Number(Number::Int(1)))));
+    }
+
+    #[test]
+    fn display_and_newline() {
+        for backend in [Backend::TreeWalker, Backend::Vm] {
+            let captured = Captured::default();
+            let mut evaluator = Evaluator::with_backend(backend);
+            evaluator.set_output(captured.clone());
+
+            for e in parse(lex(r#"(display "hello") (newline) (display (+ 1 2)) (display '(1 "a\tb" b)) (newline) (display "q\"\u{41}\n")"#)).unwrap() {
+                assert_eq!(evaluator.eval(e), Ok(SExpression::Void));
+            }
+            assert_eq!(captured.text(), "hello\n3(1 a\tb b)\nq\"A\n");
+        }
+    }
+
+    #[test]
+    fn write_keeps_strings_readable() {
+        for backend in [Backend::TreeWalker, Backend::Vm] {
+            let captured = Captured::default();
+            let mut evaluator = Evaluator::with_backend(backend);
+            evaluator.set_output(captured.clone());
+
+            for e in parse(lex(r#"(write '(1 "a\tb" #\c)) (write "q\"") (display #(#\c "d"))"#)).unwrap() {
+                assert_eq!(evaluator.eval(e), Ok(SExpression::Void));
+            }
+            assert_eq!(captured.text(), "(1 \"a\\tb\" #\\c)\"q\\\"\"#(c d)");
+        }
+    }
+}
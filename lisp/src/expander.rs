@@ -0,0 +1,752 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{evaluator::{Env, EnvRef}, parser::{CompilerError, SExpression}};
+
+const ELLIPSIS: &str = "...";
+
+// (syntax-rules (literal...) (pattern template)...)
+#[derive(Debug, Clone)]
+struct Macro {
+    literals: Vec<String>,
+    rules: Vec<(SExpression, SExpression)>,
+    origin: Origin,
+}
+
+// where a macro was defined, the names its templates don't bind refer to the bindings there
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Origin {
+    // bindings of the prelude, programs can't change them
+    Prelude,
+    // global bindings of the program
+    Global,
+    // a body, only the macro's own names are renamed
+    Local,
+}
+
+// name an identifier was written as in a template, and the macro it comes from
+#[derive(Debug, Clone)]
+struct Alias {
+    name: String,
+    origin: Origin,
+}
+
+// what a pattern variable matched, a variable followed by ... matches a sequence
+#[derive(Debug, Clone)]
+enum Binding {
+    One(SExpression),
+    Many(Vec<Binding>),
+}
+
+type Bindings = HashMap<String, Binding>;
+
+// fresh names given to the identifiers of a template during one expansion
+struct Renames {
+    names: HashMap<String, String>,
+    origin: Origin,
+}
+
+// names bound around the expression being expanded
+#[derive(Clone, Default)]
+struct Scope {
+    // local variables, they hide macros with the same name
+    variables: HashSet<String>,
+    // macros defined with define-syntax in an enclosing body
+    macros: HashMap<String, Macro>,
+    // false at the top level, where define-syntax defines a global macro
+    local: bool,
+}
+
+impl Scope {
+    fn bind<'a>(&self, names: impl IntoIterator<Item = &'a SExpression>) -> Scope {
+        let mut inner = self.clone();
+        inner.local = true;
+        for name in names {
+            if let SExpression::Identifier(id) = name {
+                inner.macros.remove(id);
+                inner.variables.insert(id.clone());
+            }
+        }
+        inner
+    }
+}
+
+// rewrites uses of macros defined with define-syntax before an expression is evaluated.
+// A define-syntax at the top level defines the macro for the rest of the program, one in a body only for that body
+#[derive(Default)]
+pub struct Expander {
+    macros: HashMap<String, Macro>,
+    // names introduced by templates
+    aliases: HashMap<String, Alias>,
+    renamed: usize,
+    // global bindings once the prelude is loaded, names used by the macros of the prelude refer to them
+    prelude: Option<HashMap<String, SExpression>>,
+}
+
+impl Expander {
+    // expands every macro use in e, evaluated in env. Names introduced by a template that end up bound by the
+    // expansion keep a fresh name, so they can't capture variables of the caller. The others refer to the
+    // bindings where the macro was defined
+    pub(crate) fn expand(&mut self, e: &SExpression, env: &EnvRef) -> Result<SExpression, CompilerError> {
+        let result = self.expand_expr(e, &Scope::default()).map(|e| self.resolve(&e, &HashSet::new(), &env.borrow()));
+        self.aliases.clear();
+        result
+    }
+
+    // macros defined from now on belong to the program
+    pub(crate) fn prelude_loaded(&mut self, global: &Env) {
+        self.prelude = Some(global.env.clone());
+    }
+
+    // name an identifier was written as in the template that introduced it
+    fn original<'a>(&'a self, mut name: &'a str) -> &'a str {
+        while let Some(original) = self.aliases.get(name) {
+            name = &original.name;
+        }
+        name
+    }
+
+    // the macro a list starting with id uses, unless a local variable hides it
+    fn find_macro(&self, id: &str, scope: &Scope) -> Option<Macro> {
+        if scope.variables.contains(id) {
+            return None;
+        }
+        let name = self.original(id);
+        scope.macros.get(name).or_else(|| self.macros.get(name)).cloned()
+    }
+
+    fn expand_expr(&mut self, e: &SExpression, scope: &Scope) -> Result<SExpression, CompilerError> {
+        let v = match e {
+            SExpression::List(v) if !v.is_empty() => v,
+            _ => return Ok(e.clone()),
+        };
+        let (head, found) = match &v[0] {
+            SExpression::Identifier(id) => match self.original(id) {
+                name @ ("quote" | "define-syntax") => (name.to_string(), None),
+                name => (name.to_string(), self.find_macro(id, scope)),
+            },
+            _ => return self.expand_all(v, 0, scope),
+        };
+        if let Some(m) = found {
+            let expansion = self.apply_macro(&m, e)?;
+            return self.expand_expr(&expansion, scope);
+        }
+
+        match (head.as_str(), v.as_slice()) {
+            ("quote", _) => Ok(e.clone()),
+            // bodies handle their own define-syntax
+            ("define-syntax", _) if scope.local => Err(CompilerError::InvalidList(e.clone())),
+            ("define-syntax", _) => {
+                let origin = if self.prelude.is_some() { Origin::Global } else { Origin::Prelude };
+                let (name, m) = self.define_syntax(e, origin)?;
+                self.macros.insert(name, m);
+                Ok(SExpression::Void)
+            },
+            ("lambda", [_, SExpression::List(params), body @ ..]) => {
+                let mut out = v[..2].to_vec();
+                out.extend(self.expand_body(body, &scope.bind(params))?);
+                Ok(SExpression::List(out))
+            },
+            ("define", [_, SExpression::List(signature), body @ ..]) if !signature.is_empty() => {
+                let mut out = v[..2].to_vec();
+                out.extend(self.expand_body(body, &scope.bind(&signature[1..]))?);
+                Ok(SExpression::List(out))
+            },
+            // parameters and names being defined are not expressions
+            ("lambda" | "define" | "set!", _) => self.expand_all(v, 2, scope),
+            // named let, the name is bound in the body
+            ("let", [_, name @ SExpression::Identifier(_), bindings, body @ ..]) => {
+                let inner = scope.bind(binding_names(bindings).chain([name]));
+                let mut out = vec![v[0].clone(), name.clone(), self.expand_bindings(bindings, scope, scope)?];
+                out.extend(self.expand_body(body, &inner)?);
+                Ok(SExpression::List(out))
+            },
+            ("let" | "letrec", [_, bindings, body @ ..]) => {
+                let inner = scope.bind(binding_names(bindings));
+                // only letrec sees its own bindings in the values
+                let values = if head == "letrec" { &inner } else { scope };
+                let mut out = vec![v[0].clone(), self.expand_bindings(bindings, values, values)?];
+                out.extend(self.expand_body(body, &inner)?);
+                Ok(SExpression::List(out))
+            },
+            // the step of a binding and the rest are evaluated with the bindings
+            ("do", [_, bindings, rest @ ..]) => {
+                let inner = scope.bind(binding_names(bindings));
+                let mut out = vec![v[0].clone(), self.expand_bindings(bindings, scope, &inner)?];
+                for (i, e) in rest.iter().enumerate() {
+                    // (test result...)
+                    out.push(match e {
+                        SExpression::List(clause) if i == 0 => self.expand_all(clause, 0, &inner)?,
+                        _ => self.expand_expr(e, &inner)?,
+                    });
+                }
+                Ok(SExpression::List(out))
+            },
+            ("cond", _) => {
+                let mut out = vec![v[0].clone()];
+                for clause in &v[1..] {
+                    out.push(match clause {
+                        SExpression::List(c) => self.expand_all(c, 0, scope)?,
+                        other => other.clone(),
+                    });
+                }
+                Ok(SExpression::List(out))
+            },
+            // the datums of a clause are quoted
+            ("case", [_, key, clauses @ ..]) => {
+                let mut out = vec![v[0].clone(), self.expand_expr(key, scope)?];
+                for clause in clauses {
+                    out.push(match clause {
+                        SExpression::List(c) => self.expand_all(c, 1, scope)?,
+                        other => other.clone(),
+                    });
+                }
+                Ok(SExpression::List(out))
+            },
+            _ => self.expand_all(v, 0, scope),
+        }
+    }
+
+    // expands the elements of v after the first `skip` ones
+    fn expand_all(&mut self, v: &[SExpression], skip: usize, scope: &Scope) -> Result<SExpression, CompilerError> {
+        let mut out = v[..skip.min(v.len())].to_vec();
+        for e in v.iter().skip(skip) {
+            out.push(self.expand_expr(e, scope)?);
+        }
+        Ok(SExpression::List(out))
+    }
+
+    // the names defined in a body are bound in all of it, its macros from their define-syntax on
+    fn expand_body(&mut self, body: &[SExpression], scope: &Scope) -> Result<Vec<SExpression>, CompilerError> {
+        let mut scope = scope.bind(self.defined_names(body));
+        let mut out = vec![];
+        for e in body {
+            match e {
+                SExpression::List(v) if matches!(v.first(), Some(SExpression::Identifier(id)) if self.original(id) == "define-syntax" && !scope.variables.contains(id)) => {
+                    let (name, m) = self.define_syntax(e, Origin::Local)?;
+                    scope.variables.remove(&name);
+                    scope.macros.insert(name, m);
+                    out.push(SExpression::Void);
+                },
+                _ => out.push(self.expand_expr(e, &scope)?),
+            }
+        }
+        Ok(out)
+    }
+
+    // ((name init step)...) of let, letrec and do, the first value is expanded in `init` and the step in `step`
+    fn expand_bindings(&mut self, bindings: &SExpression, init: &Scope, step: &Scope) -> Result<SExpression, CompilerError> {
+        let bindings = match bindings {
+            SExpression::List(bindings) => bindings,
+            other => return Ok(other.clone()),
+        };
+        let mut out = vec![];
+        for b in bindings {
+            out.push(match b {
+                SExpression::List(b) if !b.is_empty() => {
+                    let mut expanded = vec![b[0].clone()];
+                    for (i, e) in b[1..].iter().enumerate() {
+                        expanded.push(self.expand_expr(e, if i == 0 { init } else { step })?);
+                    }
+                    SExpression::List(expanded)
+                },
+                other => other.clone(),
+            });
+        }
+        Ok(SExpression::List(out))
+    }
+
+    // names of the definitions directly in a body
+    fn defined_names<'a>(&'a self, body: &'a [SExpression]) -> impl Iterator<Item = &'a SExpression> {
+        body.iter().filter_map(|e| match e {
+            SExpression::List(v) if v.len() >= 2 && matches!(&v[0], SExpression::Identifier(id) if self.original(id) == "define") => match &v[1] {
+                SExpression::List(signature) => signature.first(),
+                name => Some(name),
+            },
+            _ => None,
+        })
+    }
+
+    // (define-syntax name (syntax-rules (literal...) ((_ pattern...) template)...))
+    fn define_syntax(&self, e: &SExpression, origin: Origin) -> Result<(String, Macro), CompilerError> {
+        let invalid = || CompilerError::InvalidList(e.clone());
+        let (name, rules) = match e {
+            SExpression::List(v) => match v.as_slice() {
+                [_, SExpression::Identifier(name), SExpression::List(rules)] => (self.original(name).to_string(), rules),
+                _ => return Err(invalid()),
+            },
+            _ => return Err(invalid()),
+        };
+        let (literals, rules) = match rules.as_slice() {
+            [SExpression::Identifier(head), SExpression::List(literals), rules @ ..] if self.original(head) == "syntax-rules" => (literals, rules),
+            _ => return Err(invalid()),
+        };
+
+        let literals = literals.iter()
+            .map(|l| match l {
+                SExpression::Identifier(id) => Some(id.clone()),
+                _ => None,
+            })
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(invalid)?;
+        let rules = rules.iter()
+            .map(|r| match r {
+                SExpression::List(rule) => match rule.as_slice() {
+                    [pattern @ (SExpression::List(_) | SExpression::DottedList(..)), template] => Some((pattern.clone(), template.clone())),
+                    _ => None,
+                },
+                _ => None,
+            })
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(invalid)?;
+
+        Ok((name, Macro { literals, rules, origin }))
+    }
+
+    // rewrites a use of a macro with the template of the first rule that matches it
+    fn apply_macro(&mut self, m: &Macro, e: &SExpression) -> Result<SExpression, CompilerError> {
+        for (pattern, template) in &m.rules {
+            let mut bindings = Bindings::new();
+            // the keyword itself is not matched
+            if self.matches(&skip_keyword(pattern), &skip_keyword(e), &m.literals, &mut bindings) {
+                let mut renames = Renames { names: HashMap::new(), origin: m.origin };
+                return self.instantiate(template, &bindings, &mut renames);
+            }
+        }
+        Err(CompilerError::InvalidList(e.clone()))
+    }
+
+    fn matches(&self, pattern: &SExpression, form: &SExpression, literals: &[String], bindings: &mut Bindings) -> bool {
+        match (pattern, form) {
+            (SExpression::Identifier(p), _) if p == "_" => true,
+            (SExpression::Identifier(p), _) if literals.contains(p) => {
+                matches!(form, SExpression::Identifier(id) if self.original(id) == p)
+            },
+            (SExpression::Identifier(p), _) => {
+                bindings.insert(p.clone(), Binding::One(form.clone()));
+                true
+            },
+            (SExpression::List(ps), SExpression::List(fs)) => self.matches_list(ps, fs, literals, bindings),
+            (SExpression::DottedList(ps, tail), SExpression::List(fs) | SExpression::DottedList(fs, _)) if fs.len() >= ps.len() => {
+                let rest = match form {
+                    SExpression::DottedList(_, form_tail) if fs.len() == ps.len() => form_tail.as_ref().clone(),
+                    SExpression::DottedList(_, form_tail) => SExpression::DottedList(fs[ps.len()..].to_vec(), form_tail.clone()),
+                    _ => SExpression::List(fs[ps.len()..].to_vec()),
+                };
+                ps.iter().zip(fs).all(|(p, f)| self.matches(p, f, literals, bindings))
+                    && self.matches(tail, &rest, literals, bindings)
+            },
+            (SExpression::List(_) | SExpression::DottedList(..), _) => false,
+            _ => pattern == form,
+        }
+    }
+
+    // (a b ... c) matches the elements between a and c against b
+    fn matches_list(&self, ps: &[SExpression], fs: &[SExpression], literals: &[String], bindings: &mut Bindings) -> bool {
+        let ellipsis = ps.iter().position(is_ellipsis);
+        let (before, repeated, after) = match ellipsis {
+            Some(i) if i > 0 => (&ps[..i - 1], Some(&ps[i - 1]), &ps[i + 1..]),
+            _ => (ps, None, &ps[..0]),
+        };
+        let repeated = match repeated {
+            Some(r) => r,
+            None => return ps.len() == fs.len() && ps.iter().zip(fs).all(|(p, f)| self.matches(p, f, literals, bindings)),
+        };
+        if fs.len() < before.len() + after.len() {
+            return false;
+        }
+
+        let middle = &fs[before.len()..fs.len() - after.len()];
+        let mut matches = vec![];
+        for f in middle {
+            let mut m = Bindings::new();
+            if !self.matches(repeated, f, literals, &mut m) {
+                return false;
+            }
+            matches.push(m);
+        }
+        let mut vars = vec![];
+        pattern_vars(repeated, literals, &mut vars);
+        for var in vars {
+            let sequence = matches.iter_mut().map(|m| m.remove(&var).expect("pattern variable was not bound")).collect();
+            bindings.insert(var, Binding::Many(sequence));
+        }
+
+        before.iter().zip(fs).all(|(p, f)| self.matches(p, f, literals, bindings))
+            && after.iter().zip(&fs[fs.len() - after.len()..]).all(|(p, f)| self.matches(p, f, literals, bindings))
+    }
+
+    fn instantiate(&mut self, template: &SExpression, bindings: &Bindings, renames: &mut Renames) -> Result<SExpression, CompilerError> {
+        match template {
+            SExpression::Identifier(id) => match bindings.get(id) {
+                Some(Binding::One(e)) => Ok(e.clone()),
+                // used without enough ...
+                Some(Binding::Many(_)) => Err(CompilerError::InvalidList(template.clone())),
+                None => Ok(SExpression::Identifier(self.rename(id, renames))),
+            },
+            SExpression::List(ts) => Ok(SExpression::List(self.instantiate_elements(ts, bindings, renames)?)),
+            SExpression::DottedList(ts, tail) => {
+                let mut elements = self.instantiate_elements(ts, bindings, renames)?;
+                match self.instantiate(tail, bindings, renames)? {
+                    SExpression::List(rest) => {
+                        elements.extend(rest);
+                        Ok(SExpression::List(elements))
+                    },
+                    SExpression::DottedList(rest, tail) => {
+                        elements.extend(rest);
+                        Ok(SExpression::DottedList(elements, tail))
+                    },
+                    tail => Ok(SExpression::DottedList(elements, Box::new(tail))),
+                }
+            },
+            _ => Ok(template.clone()),
+        }
+    }
+
+    // an element followed by ... is repeated for every match of the sequence variables in it
+    fn instantiate_elements(&mut self, ts: &[SExpression], bindings: &Bindings, renames: &mut Renames) -> Result<Vec<SExpression>, CompilerError> {
+        let mut out = vec![];
+        let mut i = 0;
+        while i < ts.len() {
+            if !ts.get(i + 1).is_some_and(is_ellipsis) {
+                out.push(self.instantiate(&ts[i], bindings, renames)?);
+                i += 1;
+                continue;
+            }
+
+            let mut vars = vec![];
+            template_sequences(&ts[i], bindings, &mut vars);
+            let lengths = vars.iter()
+                .map(|v| match &bindings[v] {
+                    Binding::Many(m) => m.len(),
+                    Binding::One(_) => 0,
+                })
+                .collect::<Vec<_>>();
+            if lengths.is_empty() || lengths.iter().any(|l| *l != lengths[0]) {
+                return Err(CompilerError::InvalidList(SExpression::List(ts.to_vec())));
+            }
+
+            for n in 0..lengths[0] {
+                let mut element = bindings.clone();
+                for var in &vars {
+                    if let Binding::Many(m) = &bindings[var] {
+                        element.insert(var.clone(), m[n].clone());
+                    }
+                }
+                out.push(self.instantiate(&ts[i], &element, renames)?);
+            }
+            i += 2;
+        }
+        Ok(out)
+    }
+
+    // fresh name for an identifier introduced by the template, the same one for the whole expansion
+    fn rename(&mut self, id: &str, renames: &mut Renames) -> String {
+        if let Some(alias) = renames.names.get(id) {
+            return alias.clone();
+        }
+        self.renamed += 1;
+        // ' can't be part of an identifier in the source, so the alias can't clash with one
+        let alias = format!("{id}'{}", self.renamed);
+        self.aliases.insert(alias.clone(), Alias { name: id.to_string(), origin: renames.origin });
+        renames.names.insert(id.to_string(), alias.clone());
+        alias
+    }
+
+    // replaces the aliases that are not in `bound`, the names bound around e, with what they refer to where
+    // their macro was defined
+    fn resolve(&self, e: &SExpression, bound: &HashSet<String>, env: &Env) -> SExpression {
+        let v = match e {
+            SExpression::Identifier(id) => return self.resolve_reference(id, bound, env),
+            SExpression::List(v) if !v.is_empty() => v,
+            _ => return e.clone(),
+        };
+        let head = match &v[0] {
+            SExpression::Identifier(id) => self.resolve_name(id, bound),
+            _ => return self.resolve_all(v, bound, env),
+        };
+
+        match (head.as_str(), v.as_slice()) {
+            ("quote", _) => self.strip(e),
+            ("case", [_, _, ..]) => {
+                let mut out = vec![SExpression::Identifier(head), self.resolve(&v[1], bound, env)];
+                out.extend(v[2..].iter().map(|clause| match clause {
+                    SExpression::List(c) if !c.is_empty() => {
+                        let mut resolved = vec![self.strip(&c[0])];
+                        resolved.extend(c[1..].iter().map(|e| self.resolve(e, bound, env)));
+                        SExpression::List(resolved)
+                    },
+                    other => self.resolve(other, bound, env),
+                }));
+                SExpression::List(out)
+            },
+            ("define" | "set!", [_, name @ SExpression::Identifier(_), rest @ ..]) => {
+                let mut out = vec![SExpression::Identifier(head), self.resolve_binder(name, bound, env)];
+                out.extend(rest.iter().map(|e| self.resolve(e, bound, env)));
+                SExpression::List(out)
+            },
+            ("lambda", [_, SExpression::List(params), body @ ..]) => {
+                let inner = self.scope(bound, params.iter(), body);
+                let params = params.iter().map(|p| self.resolve_binder(p, &inner, env)).collect();
+                let mut out = vec![SExpression::Identifier(head), SExpression::List(params)];
+                out.extend(body.iter().map(|e| self.resolve(e, &inner, env)));
+                SExpression::List(out)
+            },
+            ("define", [_, SExpression::List(signature), body @ ..]) if !signature.is_empty() => {
+                let inner = self.scope(bound, signature[1..].iter(), body);
+                let mut resolved = vec![self.resolve_binder(&signature[0], bound, env)];
+                resolved.extend(signature[1..].iter().map(|p| self.resolve_binder(p, &inner, env)));
+                let mut out = vec![SExpression::Identifier(head), SExpression::List(resolved)];
+                out.extend(body.iter().map(|e| self.resolve(e, &inner, env)));
+                SExpression::List(out)
+            },
+            // named let
+            ("let", [_, name @ SExpression::Identifier(_), SExpression::List(bindings), body @ ..]) => {
+                let inner = self.scope(bound, binding_names(&v[2]).chain([name]), body);
+                let mut out = vec![
+                    SExpression::Identifier(head),
+                    self.resolve_binder(name, &inner, env),
+                    self.resolve_bindings(bindings, bound, &inner, env),
+                ];
+                out.extend(body.iter().map(|e| self.resolve(e, &inner, env)));
+                SExpression::List(out)
+            },
+            ("let" | "letrec" | "do", [_, SExpression::List(bindings), rest @ ..]) => {
+                let inner = self.scope(bound, binding_names(&v[1]), rest);
+                // only letrec sees its own bindings in the values
+                let values = if head == "letrec" { &inner } else { bound };
+                let mut out = vec![SExpression::Identifier(head), self.resolve_bindings(bindings, values, &inner, env)];
+                out.extend(rest.iter().map(|e| self.resolve(e, &inner, env)));
+                SExpression::List(out)
+            },
+            _ => self.resolve_all(v, bound, env),
+        }
+    }
+
+    fn resolve_all(&self, v: &[SExpression], bound: &HashSet<String>, env: &Env) -> SExpression {
+        SExpression::List(v.iter().map(|e| self.resolve(e, bound, env)).collect())
+    }
+
+    // ((name init step)...), the names and the step of a do binding are in the new scope
+    fn resolve_bindings(&self, bindings: &[SExpression], values: &HashSet<String>, inner: &HashSet<String>, env: &Env) -> SExpression {
+        let bindings = bindings.iter()
+            .map(|b| match b {
+                SExpression::List(b) if !b.is_empty() => {
+                    let mut resolved = vec![self.resolve_binder(&b[0], inner, env)];
+                    resolved.extend(b[1..].iter().enumerate().map(|(i, e)| self.resolve(e, if i == 1 { inner } else { values }, env)));
+                    SExpression::List(resolved)
+                },
+                other => self.resolve(other, values, env),
+            })
+            .collect();
+        SExpression::List(bindings)
+    }
+
+    // a name being bound or assigned is never replaced by a value
+    fn resolve_binder(&self, e: &SExpression, bound: &HashSet<String>, env: &Env) -> SExpression {
+        match e {
+            SExpression::Identifier(id) => SExpression::Identifier(self.resolve_name(id, bound)),
+            other => self.resolve(other, bound, env),
+        }
+    }
+
+    // an alias bound by its expansion keeps its fresh name, the others get the name written in the template
+    fn resolve_name(&self, id: &str, bound: &HashSet<String>) -> String {
+        let mut name = id;
+        while let Some(alias) = self.aliases.get(name) {
+            if bound.contains(name) {
+                break;
+            }
+            name = &alias.name;
+        }
+        name.to_string()
+    }
+
+    // a name a template doesn't bind refers to the binding where its macro was defined. Names of the prelude
+    // refer to the prelude's bindings, names of a global macro to the global binding when a local one hides it
+    fn resolve_reference(&self, id: &str, bound: &HashSet<String>, env: &Env) -> SExpression {
+        let mut name = id;
+        let mut origin = None;
+        while let Some(alias) = self.aliases.get(name) {
+            if bound.contains(name) {
+                return SExpression::Identifier(name.to_string());
+            }
+            origin = Some(alias.origin);
+            name = &alias.name;
+        }
+        let value = match origin {
+            Some(Origin::Prelude) => self.prelude.as_ref().and_then(|prelude| prelude.get(name).cloned()),
+            Some(Origin::Global) if bound.contains(name) => env.get(name),
+            _ => None,
+        };
+        match value {
+            // symbols and lists would be evaluated again
+            Some(value @ (SExpression::Identifier(_) | SExpression::List(_) | SExpression::DottedList(..) | SExpression::Pair(_))) => {
+                SExpression::List(vec![SExpression::Identifier("quote".to_string()), value])
+            },
+            Some(value) => value,
+            None => SExpression::Identifier(name.to_string()),
+        }
+    }
+
+    // bound extended with the given names and the names defined at the start of the body
+    fn scope<'a>(&'a self, bound: &HashSet<String>, names: impl Iterator<Item = &'a SExpression>, body: &'a [SExpression]) -> HashSet<String> {
+        let mut inner = bound.clone();
+        for name in names.chain(self.defined_names(body)) {
+            if let SExpression::Identifier(id) = name {
+                inner.insert(id.clone());
+            }
+        }
+        inner
+    }
+
+    // quoted data gets the names as they were written
+    fn strip(&self, e: &SExpression) -> SExpression {
+        match e {
+            SExpression::Identifier(id) => SExpression::Identifier(self.original(id).to_string()),
+            SExpression::List(v) => SExpression::List(v.iter().map(|e| self.strip(e)).collect()),
+            SExpression::DottedList(v, tail) => SExpression::DottedList(v.iter().map(|e| self.strip(e)).collect(), Box::new(self.strip(tail))),
+            _ => e.clone(),
+        }
+    }
+}
+
+// the error with the identifiers renamed by an expansion written the way they are in the macro
+pub(crate) fn original_names(error: CompilerError) -> CompilerError {
+    match error {
+        CompilerError::UnknownSymbol(name) => CompilerError::UnknownSymbol(source_name(&name).to_string()),
+        CompilerError::InvalidList(e) => CompilerError::InvalidList(source_names(&e)),
+        CompilerError::ArityMismatch { name, expected, got } => CompilerError::ArityMismatch { name: source_name(&name).to_string(), expected, got },
+        error => error,
+    }
+}
+
+// an alias is the name followed by ' and a number, see Expander::rename
+fn source_name(name: &str) -> &str {
+    name.split_once('\'').map_or(name, |(name, _)| name)
+}
+
+fn source_names(e: &SExpression) -> SExpression {
+    match e {
+        SExpression::Identifier(id) => SExpression::Identifier(source_name(id).to_string()),
+        SExpression::List(v) => SExpression::List(v.iter().map(source_names).collect()),
+        SExpression::DottedList(v, tail) => SExpression::DottedList(v.iter().map(source_names).collect(), Box::new(source_names(tail))),
+        _ => e.clone(),
+    }
+}
+
+// names of ((name value...)...)
+fn binding_names(bindings: &SExpression) -> impl Iterator<Item = &SExpression> {
+    let bindings = match bindings {
+        SExpression::List(bindings) => bindings.as_slice(),
+        _ => &[],
+    };
+    bindings.iter().filter_map(|b| match b {
+        SExpression::List(b) => b.first(),
+        _ => None,
+    })
+}
+
+fn is_ellipsis(e: &SExpression) -> bool {
+    matches!(e, SExpression::Identifier(id) if id == ELLIPSIS)
+}
+
+// (keyword a b) -> (a b)
+fn skip_keyword(e: &SExpression) -> SExpression {
+    match e {
+        SExpression::List(v) => SExpression::List(v.iter().skip(1).cloned().collect()),
+        SExpression::DottedList(v, tail) if v.len() == 1 => tail.as_ref().clone(),
+        SExpression::DottedList(v, tail) => SExpression::DottedList(v[1..].to_vec(), tail.clone()),
+        _ => e.clone(),
+    }
+}
+
+fn pattern_vars(pattern: &SExpression, literals: &[String], out: &mut Vec<String>) {
+    match pattern {
+        SExpression::Identifier(id) if id != "_" && id != ELLIPSIS && !literals.contains(id) => out.push(id.clone()),
+        SExpression::List(v) => v.iter().for_each(|p| pattern_vars(p, literals, out)),
+        SExpression::DottedList(v, tail) => v.iter().chain([tail.as_ref()]).for_each(|p| pattern_vars(p, literals, out)),
+        _ => {},
+    }
+}
+
+// pattern variables matched by a sequence that appear in the template
+fn template_sequences(template: &SExpression, bindings: &Bindings, out: &mut Vec<String>) {
+    match template {
+        SExpression::Identifier(id) if matches!(bindings.get(id), Some(Binding::Many(_))) && !out.contains(id) => out.push(id.clone()),
+        SExpression::List(v) => v.iter().for_each(|t| template_sequences(t, bindings, out)),
+        SExpression::DottedList(v, tail) => v.iter().chain([tail.as_ref()]).for_each(|t| template_sequences(t, bindings, out)),
+        _ => {},
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use crate::{lexer::lex, parser::parse};
+
+    use super::*;
+
+    fn global() -> EnvRef {
+        Rc::new(RefCell::new(Env::new(None)))
+    }
+
+    fn expand(input: &str) -> Vec<String> {
+        let mut expander = Expander::default();
+        let env = global();
+        parse(lex(input)).unwrap().iter().map(|e| expander.expand(e, &env).unwrap().to_string()).collect()
+    }
+
+    #[test]
+    fn expand_ellipsis() {
+        let r = expand("
+            (define-syntax my-list (syntax-rules () ((_ (a b) ...) (list (cons a b) ...))))
+            (my-list (1 2) (3 4)) (my-list)");
+        assert_eq!(r, vec!["#<void>", "(list (cons 1 2) (cons 3 4))", "(list)"]);
+    }
+
+    #[test]
+    fn expand_literals_and_tails() {
+        let r = expand("
+            (define-syntax arrow (syntax-rules (=>) ((_ a => b) (b a)) ((_ a b) (list a b))))
+            (arrow 1 => f) (arrow 1 f)
+            (define-syntax rest (syntax-rules () ((_ a . r) 'r) ((_ a b ... c) (c b ...))))
+            (rest 1 2 3)");
+        assert_eq!(r[1..], vec!["(f 1)", "(list 1 f)", "#<void>", "(quote (2 3))"]);
+    }
+
+    #[test]
+    fn introduced_bindings_are_renamed() {
+        let r = expand("
+            (define-syntax swap! (syntax-rules () ((_ a b) (let ((tmp a)) (set! a b) (set! b tmp)))))
+            (swap! tmp y)");
+        let alias = "tmp'2";
+        assert_eq!(r[1], format!("(let (({alias} tmp)) (set! tmp y) (set! y {alias}))"));
+    }
+
+    #[test]
+    fn quoted_data_is_not_expanded() {
+        let r = expand("
+            (define-syntax m (syntax-rules () ((_) 'm)))
+            '(m) (m)");
+        assert_eq!(r[1..], vec!["(quote (m))", "(quote m)"]);
+    }
+
+    #[test]
+    fn local_names_hide_macros() {
+        let r = expand("
+            (define-syntax m (syntax-rules () ((_) 1)))
+            (lambda (m) (m)) (let loop ((m (m))) (m)) (m)");
+        assert_eq!(r[1..], vec!["(lambda (m) (m))", "(let loop ((m 1)) (m))", "1"]);
+    }
+
+    #[test]
+    fn no_matching_rule() {
+        let mut expander = Expander::default();
+        let env = global();
+        let ast = parse(lex("(define-syntax m (syntax-rules () ((_ a) a))) (m 1 2) (define-syntax)")).unwrap();
+        assert_eq!(expander.expand(&ast[0], &env), Ok(SExpression::Void));
+        assert_eq!(expander.expand(&ast[1], &env), Err(CompilerError::InvalidList(ast[1].clone())));
+        assert_eq!(expander.expand(&ast[2], &env), Err(CompilerError::InvalidList(ast[2].clone())));
+    }
+}
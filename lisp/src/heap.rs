@@ -0,0 +1,304 @@
+use std::{cell::RefCell, collections::HashMap, mem, rc::{Rc, Weak}};
+
+use crate::{evaluator::{Env, EnvRef}, pair, parser::SExpression, table::HashTable};
+
+// objects counted since the last collection before the next one starts
+const MIN_THRESHOLD: usize = 10_000;
+
+// values that can be part of a reference cycle: frames, vectors and hash tables.
+// The heap only holds weak references, values are freed by Rc as soon as nothing uses them.
+// The collector frees what Rc can't - objects that are only kept alive by each other
+pub(crate) struct Heap {
+    objects: Vec<Object>,
+    threshold: usize,
+    stats: GcStats,
+}
+
+// what the collector did so far
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct GcStats {
+    pub collections: usize,
+    // frames, vectors and hash tables in use
+    pub live: usize,
+    // objects freed because they were only reachable from a cycle
+    pub collected: usize,
+}
+
+#[derive(Clone)]
+enum Object {
+    Env(Weak<RefCell<Env>>),
+    Vector(Weak<RefCell<Vec<SExpression>>>),
+    Table(Weak<RefCell<HashTable>>),
+}
+
+// strong reference held while collecting
+enum Strong {
+    Env(EnvRef),
+    Vector(Rc<RefCell<Vec<SExpression>>>),
+    Table(Rc<RefCell<HashTable>>),
+}
+
+impl Object {
+    fn upgrade(&self) -> Option<Strong> {
+        match self {
+            Object::Env(w) => w.upgrade().map(Strong::Env),
+            Object::Vector(w) => w.upgrade().map(Strong::Vector),
+            Object::Table(w) => w.upgrade().map(Strong::Table),
+        }
+    }
+
+    fn is_live(&self) -> bool {
+        match self {
+            Object::Env(w) => w.strong_count() > 0,
+            Object::Vector(w) => w.strong_count() > 0,
+            Object::Table(w) => w.strong_count() > 0,
+        }
+    }
+}
+
+impl Strong {
+    fn address(&self) -> usize {
+        match self {
+            Strong::Env(rc) => address(rc),
+            Strong::Vector(rc) => address(rc),
+            Strong::Table(rc) => address(rc),
+        }
+    }
+
+    fn strong_count(&self) -> usize {
+        match self {
+            Strong::Env(rc) => Rc::strong_count(rc),
+            Strong::Vector(rc) => Rc::strong_count(rc),
+            Strong::Table(rc) => Rc::strong_count(rc),
+        }
+    }
+
+    // calls f with every object this one holds a strong reference to, false if it is borrowed right now
+    fn references(&self, f: &mut impl FnMut(usize)) -> bool {
+        match self {
+            Strong::Env(rc) => match rc.try_borrow() {
+                Ok(env) => {
+                    env.env.values().for_each(|v| references(v, f));
+                    if let Some(outer) = &env.outer {
+                        f(address(outer));
+                    }
+                    true
+                },
+                Err(_) => false,
+            },
+            Strong::Vector(rc) => match rc.try_borrow() {
+                Ok(v) => {
+                    v.iter().for_each(|v| references(v, f));
+                    true
+                },
+                Err(_) => false,
+            },
+            Strong::Table(rc) => match rc.try_borrow() {
+                Ok(table) => {
+                    table.entries().iter().for_each(|(k, v)| {
+                        references(k, f);
+                        references(v, f);
+                    });
+                    true
+                },
+                Err(_) => false,
+            },
+        }
+    }
+
+    // drops the contents, which breaks the cycles the object is part of
+    fn clear(&self) {
+        match self {
+            Strong::Env(rc) => if let Ok(mut env) = rc.try_borrow_mut() {
+                env.env.clear();
+                env.outer = None;
+            },
+            Strong::Vector(rc) => if let Ok(mut v) = rc.try_borrow_mut() {
+                v.clear();
+            },
+            Strong::Table(rc) => if let Ok(mut table) = rc.try_borrow_mut() {
+                *table = HashTable::default();
+            },
+        }
+    }
+}
+
+fn address<T>(rc: &Rc<T>) -> usize {
+    Rc::as_ptr(rc) as *const () as usize
+}
+
+// objects a value holds directly. Values shared through other Rcs (conditions, lambda bodies) are
+// left out, an object only they refer to looks like it's used from outside and is kept
+fn references(e: &SExpression, f: &mut impl FnMut(usize)) {
+    match e {
+        SExpression::Lambda(l) => f(address(&l.env)),
+        SExpression::Vector(v) => f(address(v)),
+        SExpression::HashTable(t) => f(address(t)),
+        SExpression::List(_) | SExpression::DottedList(..) | SExpression::Pair(_) => {
+            let mut items = pair::iter(e);
+            items.by_ref().for_each(|e| references(e, f));
+            if let Some(tail) = items.tail() {
+                references(tail, f);
+            }
+        },
+        _ => {},
+    }
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Self { objects: vec![], threshold: MIN_THRESHOLD, stats: GcStats::default() }
+    }
+}
+
+impl Heap {
+    pub(crate) fn env(&mut self, env: Env) -> EnvRef {
+        let rc = Rc::new(RefCell::new(env));
+        self.track(Object::Env(Rc::downgrade(&rc)));
+        rc
+    }
+
+    pub(crate) fn vector(&mut self, v: Vec<SExpression>) -> SExpression {
+        let rc = Rc::new(RefCell::new(v));
+        self.track(Object::Vector(Rc::downgrade(&rc)));
+        SExpression::Vector(rc)
+    }
+
+    pub(crate) fn table(&mut self) -> SExpression {
+        let rc = Rc::default();
+        self.track(Object::Table(Rc::downgrade(&rc)));
+        SExpression::HashTable(rc)
+    }
+
+    fn track(&mut self, object: Object) {
+        if self.objects.len() >= self.threshold {
+            self.collect();
+        }
+        self.objects.push(object);
+    }
+
+    pub(crate) fn stats(&self) -> GcStats {
+        GcStats { live: self.objects.iter().filter(|o| o.is_live()).count(), ..self.stats }
+    }
+
+    // mark and sweep. The roots are the objects referenced from outside the heap: the global
+    // environment, the frames and values of evaluations in progress and everything the host holds.
+    // They are found by subtracting the references between objects from their reference counts
+    pub(crate) fn collect(&mut self) {
+        let objects = self.objects.iter().filter_map(Object::upgrade).collect::<Vec<_>>();
+        let index = objects.iter().enumerate().map(|(i, o)| (o.address(), i)).collect::<HashMap<_, _>>();
+
+        // references from outside, the one held by objects doesn't count
+        let mut outside = objects.iter().map(|o| o.strong_count() as isize - 1).collect::<Vec<_>>();
+        let mut roots = vec![];
+        for (i, o) in objects.iter().enumerate() {
+            let traced = o.references(&mut |address| if let Some(&j) = index.get(&address) {
+                outside[j] -= 1;
+            });
+            // what a borrowed object refers to can't be seen, so it has to be kept
+            if !traced {
+                roots.push(i);
+            }
+        }
+        roots.extend(outside.iter().enumerate().filter(|(_, &n)| n > 0).map(|(i, _)| i));
+
+        let mut marked = vec![false; objects.len()];
+        while let Some(i) = roots.pop() {
+            if mem::replace(&mut marked[i], true) {
+                continue;
+            }
+            objects[i].references(&mut |address| if let Some(&j) = index.get(&address) {
+                if !marked[j] {
+                    roots.push(j);
+                }
+            });
+        }
+
+        let garbage = objects.iter().zip(&marked).filter(|(_, &marked)| !marked).map(|(o, _)| o).collect::<Vec<_>>();
+        garbage.iter().for_each(|o| o.clear());
+        self.stats.collections += 1;
+        self.stats.collected += garbage.len();
+        drop(garbage);
+        drop(objects);
+
+        self.objects.retain(Object::is_live);
+        self.threshold = MIN_THRESHOLD.max(self.objects.len() * 2);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{evaluator::{Backend, Evaluator}, lexer::lex, parser::{parse, CompilerError}};
+
+    use super::*;
+
+    fn eval_all(evaluator: &mut Evaluator, input: &str) -> Result<Vec<String>, CompilerError> {
+        parse(lex(input)).unwrap().into_iter().map(|e| evaluator.eval(e).map(|r| r.to_string())).collect()
+    }
+
+    #[test]
+    fn cycles_are_collected() {
+        for backend in [Backend::TreeWalker, Backend::Vm] {
+            let mut evaluator = Evaluator::with_backend(backend);
+            evaluator.collect_garbage();
+            let before = evaluator.gc_stats();
+            eval_all(&mut evaluator, "
+                (define (cycles)
+                  (define v (vector 1 2))
+                  (define t (make-hash-table))
+                  (vector-set! v 0 v)
+                  (hash-set! t 'self t)
+                  (hash-set! t 'frame (lambda () v))
+                  (define (self) self)
+                  self)
+                (cycles) (cycles)").unwrap();
+            let after = evaluator.collect_garbage();
+            assert_eq!(after.live, before.live);
+            assert_eq!(after.collected - before.collected, 6);
+        }
+    }
+
+    #[test]
+    fn reachable_values_are_kept() {
+        for backend in [Backend::TreeWalker, Backend::Vm] {
+            let mut evaluator = Evaluator::with_backend(backend);
+            eval_all(&mut evaluator, "
+                (define (counter)
+                  (define n 0)
+                  (define (next) (set! n (+ n 1)) n)
+                  next)
+                (define next (counter))
+                (define v (vector 0))
+                (vector-set! v 0 v)
+                (next)").unwrap();
+            evaluator.collect_garbage();
+            let r = eval_all(&mut evaluator, "(next) (vector-length (vector-ref (vector-ref v 0) 0))");
+            assert_eq!(r, Ok(vec![String::from("2"), String::from("1")]));
+        }
+    }
+
+    #[test]
+    fn objects_used_by_the_host_are_kept() {
+        let mut evaluator = Evaluator::new();
+        eval_all(&mut evaluator, "(define v (vector 1)) (vector-set! v 0 v)").unwrap();
+        let v = evaluator.lookup("v").unwrap();
+        eval_all(&mut evaluator, "(set! v 0)").unwrap();
+        evaluator.collect_garbage();
+        assert!(matches!(&v, SExpression::Vector(inner) if matches!(&inner.borrow()[0], SExpression::Vector(_))));
+    }
+
+    #[test]
+    fn collections_run_while_allocating() {
+        for backend in [Backend::TreeWalker, Backend::Vm] {
+            let mut evaluator = Evaluator::with_backend(backend);
+            let r = eval_all(&mut evaluator, "
+                (define (cycle) (define (self) self) self)
+                (do ((i 0 (+ i 1))) ((= i 100000)) (cycle))
+                (hash-ref (gc-stats) 'live)");
+            let live = r.unwrap().last().unwrap().parse::<usize>().unwrap();
+            let stats = evaluator.gc_stats();
+            assert!(stats.collections > 0 && stats.collected >= 90_000, "{stats:?}");
+            assert!(live < MIN_THRESHOLD * 2, "{live}");
+        }
+    }
+}
@@ -0,0 +1,173 @@
+use std::{cell::RefCell, fmt, io::{self, Write}, path::PathBuf, rc::Rc};
+
+use crate::{
+    builtins::Arity,
+    diagnostics::{eval_source, Diagnostic},
+    evaluator::{Backend, Evaluator},
+    limits::Limits,
+    parser::{CompilerError, SExpression},
+};
+
+// entry point for running lisp code from rust
+pub struct Interpreter {
+    evaluator: Evaluator,
+    // set while output is captured instead of written to stdout
+    captured: Option<Captured>,
+}
+
+// why eval_str failed: every parse error, or the error that stopped evaluation
+#[derive(Debug, PartialEq)]
+pub struct Error {
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl Error {
+    // the first error, the one that stopped evaluation if the code could be parsed
+    pub fn error(&self) -> &CompilerError {
+        &self.diagnostics[0].error
+    }
+
+    // errors with the source line they point at, see Diagnostic::render
+    pub fn render(&self, file_name: &str, source: &str) -> String {
+        self.diagnostics.iter().map(|d| d.render(file_name, source)).collect::<Vec<_>>().join("\n")
+    }
+}
+
+// 2:6: unknown symbol: y
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, d) in self.diagnostics.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            match d.span {
+                Some(span) => write!(f, "{}:{}: {}", span.start.line, span.start.col, d.error)?,
+                None => write!(f, "{}", d.error)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<CompilerError> for Error {
+    fn from(error: CompilerError) -> Self {
+        Error { diagnostics: vec![Diagnostic { error, span: None }] }
+    }
+}
+
+impl Default for Interpreter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Interpreter {
+    pub fn new() -> Self {
+        Self::with_backend(Backend::TreeWalker)
+    }
+
+    pub fn with_backend(backend: Backend) -> Self {
+        Self { evaluator: Evaluator::with_backend(backend), captured: None }
+    }
+
+    // limits for every later call of eval_str and call, each one starts from zero
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.evaluator.set_limits(limits);
+    }
+
+    // stack of the thread the interpreter runs on, see Evaluator::set_stack_size
+    pub fn set_stack_size(&mut self, bytes: usize) {
+        self.evaluator.set_stack_size(bytes);
+    }
+
+    // removes display, write, newline and load, the builtins that do input or output
+    pub fn disable_io(&mut self) {
+        self.evaluator.disable_io();
+    }
+
+    // directories import looks for modules in, the current directory is searched by default
+    pub fn add_search_path(&mut self, dir: impl Into<PathBuf>) {
+        self.evaluator.add_search_path(dir);
+    }
+
+    // evaluates every expression in the source and returns the value of the last one.
+    // Definitions are kept for later calls
+    pub fn eval_str(&mut self, source: &str) -> Result<SExpression, Error> {
+        self.evaluator.reset_usage();
+        let (mut results, diagnostics) = eval_source(&mut self.evaluator, source);
+        if !diagnostics.is_empty() {
+            return Err(Error { diagnostics });
+        }
+        Ok(results.pop().unwrap_or(SExpression::Void))
+    }
+
+    // calls the global procedure `name` with the arguments
+    pub fn call(&mut self, name: &str, args: Vec<SExpression>) -> Result<SExpression, Error> {
+        self.evaluator.reset_usage();
+        let procedure = self.evaluator.lookup(name).ok_or_else(|| CompilerError::UnknownSymbol(name.to_string()))?;
+        Ok(self.evaluator.apply_procedure(procedure, args)?)
+    }
+
+    pub fn set_global(&mut self, name: &str, value: impl Into<SExpression>) {
+        self.evaluator.env.borrow_mut().define(name.to_string(), value.into());
+    }
+
+    pub fn get_global(&self, name: &str) -> Option<SExpression> {
+        self.evaluator.lookup(name)
+    }
+
+    // makes a rust function available to lisp code as a global procedure
+    pub fn register(&mut self, name: &str, arity: Arity, fun: impl Fn(&mut Evaluator, Vec<SExpression>) -> Result<SExpression, CompilerError> + 'static) {
+        self.evaluator.register(name, arity, fun);
+    }
+
+    // where display and newline write to, stdout by default
+    pub fn set_output(&mut self, out: impl Write + 'static) {
+        self.captured = None;
+        self.evaluator.set_output(out);
+    }
+
+    // keeps the output of display and newline, so it can be read with take_output
+    pub fn capture_output(&mut self) {
+        let captured = Captured::default();
+        self.evaluator.set_output(captured.clone());
+        self.captured = Some(captured);
+    }
+
+    // output captured since the last call, empty if output is not captured
+    pub fn take_output(&mut self) -> String {
+        match &self.captured {
+            Some(captured) => captured.take(),
+            None => String::new(),
+        }
+    }
+}
+
+// keeps what is written to it, clones share the text. Used by capture_output, it can also be given to
+// Evaluator::set_output or to a debug::Tracer
+#[derive(Clone, Default)]
+pub struct Captured(Rc<RefCell<Vec<u8>>>);
+
+impl Captured {
+    // everything written so far
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.0.borrow()).into_owned()
+    }
+
+    // what was written since the last call
+    pub fn take(&self) -> String {
+        String::from_utf8_lossy(&self.0.borrow_mut().split_off(0)).into_owned()
+    }
+}
+
+impl Write for Captured {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
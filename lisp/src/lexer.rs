@@ -1,76 +1,538 @@
-use std::{iter::Peekable, str::Chars};
+use std::{fmt, io::{self, BufRead}, mem};
 
+use crate::number::Number;
+
+// every token knows the line and column it starts at
 #[derive(Clone, Debug, PartialEq)]
 pub enum Token {
-    Opening{line: usize},
-    Closing{line: usize},
-    Literal{line: usize, v: Literal},
-    Identifier{line: usize, v: String},
-    Invalid{line: usize, v: String},
+    Opening{line: usize, col: usize},
+    // #( starts a vector literal, closed by )
+    VectorOpening{line: usize, col: usize},
+    Closing{line: usize, col: usize},
+    Quote{line: usize, col: usize},
+    Literal{line: usize, col: usize, v: Literal},
+    Identifier{line: usize, col: usize, v: String},
+    // v is the text of the token as it was written
+    Invalid{line: usize, col: usize, v: String, reason: InvalidReason},
+}
+
+// what is wrong with an invalid token
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InvalidReason {
+    // the input ended before the closing quote
+    UnterminatedString,
+    // the input ended inside a #| comment
+    UnterminatedComment,
+    // a string with an escape sequence that is not known, like "\q"
+    BadEscape,
+    // starts like a number but isn't one, like 1.2.3 or 1/0
+    BadNumber,
+    // #\ followed by a name that is not a character
+    BadChar,
+}
+
+impl fmt::Display for InvalidReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InvalidReason::UnterminatedString => write!(f, "unterminated string"),
+            InvalidReason::UnterminatedComment => write!(f, "unterminated block comment"),
+            InvalidReason::BadEscape => write!(f, "unknown escape sequence"),
+            InvalidReason::BadNumber => write!(f, "bad number"),
+            InvalidReason::BadChar => write!(f, "bad character"),
+        }
+    }
+}
+
+impl Token {
+    pub fn position(&self) -> Position {
+        let (line, col) = match self {
+            Token::Opening { line, col } | Token::VectorOpening { line, col } | Token::Closing { line, col } | Token::Quote { line, col } => (*line, *col),
+            Token::Literal { line, col, .. } | Token::Identifier { line, col, .. } | Token::Invalid { line, col, .. } => (*line, *col),
+        };
+        Position { line, col }
+    }
+}
+
+// prints the token the way it was written
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Opening { .. } => write!(f, "("),
+            Token::VectorOpening { .. } => write!(f, "#("),
+            Token::Closing { .. } => write!(f, ")"),
+            Token::Quote { .. } => write!(f, "'"),
+            Token::Literal { v: Literal::Number(n), .. } => write!(f, "{n}"),
+            Token::Literal { v: Literal::String(s), .. } => write!(f, "{}", escape(s)),
+            Token::Literal { v: Literal::Boolean(b), .. } => write!(f, "{b}"),
+            Token::Literal { v: Literal::Char(c), .. } => write!(f, "{}", write_char(*c)),
+            Token::Identifier { v, .. } | Token::Invalid { v, .. } => write!(f, "{v}"),
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum Literal {
-    Number(i32),
+    Number(Number),
     String(String),
     Boolean(bool),
+    Char(char),
+}
+
+
+// 1-based line and column
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Position {
+    pub line: usize,
+    pub col: usize,
 }
 
+impl Position {
+    const START: Position = Position { line: 1, col: 1 };
+
+    fn advance(&mut self, c: char) {
+        if c == '\n' {
+            self.line += 1;
+            self.col = 1;
+        } else {
+            self.col += 1;
+        }
+    }
+}
+
+// from start up to, but not including, end
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Span {
+    pub start: Position,
+    pub end: Position,
+}
+
+impl Span {
+    // covers everything from the start of the first span to the end of the last one
+    pub fn join(&self, other: &Span) -> Span {
+        Span { start: self.start, end: other.end }
+    }
+}
 
 pub fn lex(input: &str) -> Vec<Token> {
-    let mut chars = input.chars().peekable();
-    let mut out = Vec::new();
-    let mut line_number = 1;
-
-    while let Some(current) = chars.next() {
-        let tok = match current {
-            c if c.is_whitespace() => {
-                if current == '\n' {
-                    line_number += 1;
-                }
-                None
+    Lexer::new(input.chars()).map(|(t, _)| t).collect()
+}
+
+pub fn lex_with_spans(input: &str) -> Vec<(Token, Span)> {
+    Lexer::new(input.chars()).collect()
+}
+
+// tokens of a stream of characters, the characters are read as tokens are taken
+pub struct Lexer<I: Iterator<Item = char>> {
+    chars: I,
+    // taken from chars by peek, not consumed yet
+    peeked: Option<char>,
+    position: Position,
+    // text of the lexeme being read
+    text: String,
+    datum_comments: DatumComments,
+}
+
+// a token, or the text between tokens
+enum Lexeme {
+    Token(Token),
+    // whitespace or a comment
+    Skipped,
+    // #;
+    DatumComment,
+}
+
+struct Scanned {
+    lexeme: Lexeme,
+    text: String,
+    span: Span,
+}
+
+impl Scanned {
+    // true if more input could make it longer: words, line comments and unterminated strings or comments
+    fn may_continue(&self) -> bool {
+        match &self.lexeme {
+            Lexeme::Token(Token::Literal { v: Literal::String(_), .. }) => false,
+            Lexeme::Token(Token::Literal { .. } | Token::Identifier { .. }) => true,
+            Lexeme::Token(Token::Invalid { reason, .. }) => *reason != InvalidReason::BadEscape,
+            Lexeme::Skipped => self.text.starts_with(';'),
+            _ => false,
+        }
+    }
+}
+
+impl<I: Iterator<Item = char>> Lexer<I> {
+    pub fn new(chars: I) -> Self {
+        Self { chars, peeked: None, position: Position::START, text: String::new(), datum_comments: DatumComments::default() }
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        if self.peeked.is_none() {
+            self.peeked = self.chars.next();
+        }
+        self.peeked
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peeked.take().or_else(|| self.chars.next())?;
+        self.position.advance(c);
+        self.text.push(c);
+        Some(c)
+    }
+
+    // reads characters while fun returns true for them, returns what was read
+    fn read_while(&mut self, fun: impl Fn(char) -> bool) -> String {
+        let mut out = String::new();
+        while let Some(next) = self.peek().filter(|&c| fun(c)) {
+            out.push(next);
+            self.bump();
+        }
+        out
+    }
+
+    // the next token, or whitespace or a comment
+    fn read_lexeme(&mut self) -> Option<Scanned> {
+        let start = self.position;
+        let (line, col) = (start.line, start.col);
+        let current = self.bump()?;
+        let lexeme = match current {
+            c if c.is_whitespace() => Ok(Lexeme::Skipped),
+            ')' => Ok(Lexeme::Token(Token::Closing{line, col})),
+            '(' => Ok(Lexeme::Token(Token::Opening{line, col})),
+            '\'' => Ok(Lexeme::Token(Token::Quote{line, col})),
+            ';' => {
+                self.read_while(|c| c != '\n');
+                Ok(Lexeme::Skipped)
             },
-            ')' => Some(Token::Closing{line: line_number}),
-            '(' => Some(Token::Opening{line: line_number}),
-            '"' => {
-                let word = current.to_string() + &read_until(&mut chars, |c| c != '"');
-                let last_char = chars.peek();
-                match last_char {
-                    // we need to terminate the string. Next.unwrap is safe here, as we just peeked
-                    Some('"') => Some(Token::Literal { line: line_number, v: Literal::String(word + &chars.next().unwrap().to_string())}),
-                    _ => Some(Token::Invalid{line: line_number, v: word})
+            '#' if self.peek() == Some('|') => if self.read_block_comment() {
+                Ok(Lexeme::Skipped)
+            } else {
+                Err(InvalidReason::UnterminatedComment)
+            },
+            '#' if self.peek() == Some('(') => {
+                self.bump();
+                Ok(Lexeme::Token(Token::VectorOpening{line, col}))
+            },
+            '#' if self.peek() == Some('\\') => {
+                self.read_char();
+                match parse_char(&self.text) {
+                    Some(c) => Ok(Lexeme::Token(Token::Literal { line, col, v: Literal::Char(c)})),
+                    None => Err(InvalidReason::BadChar),
                 }
             },
-            other => {
-                let word = other.to_string() + &read_until(&mut chars, |c| !c.is_whitespace() && c != ')' && c !='(' && c != '"');
-                match word.parse::<i32>() {
-                    Ok(num) => Some(Token::Literal { line: line_number, v: Literal::Number(num)}),
-                    _ => match word.parse::<bool>() {
-                        Ok(v) => Some(Token::Literal { line: line_number, v: Literal::Boolean(v)}),
-                        Err(_) => Some(Token::Identifier{line: line_number, v:word})
-                    }
+            '#' if self.peek() == Some(';') => {
+                self.bump();
+                Ok(Lexeme::DatumComment)
+            },
+            '"' => self.read_string().map(|s| Lexeme::Token(Token::Literal { line, col, v: Literal::String(s)})),
+            _ => {
+                self.read_while(|c| !is_delimiter(c));
+                let word = &self.text;
+                match Number::parse(word) {
+                    Some(num) => Ok(Lexeme::Token(Token::Literal { line, col, v: Literal::Number(num)})),
+                    None if looks_like_number(word) => Err(InvalidReason::BadNumber),
+                    None => match word.parse::<bool>() {
+                        Ok(v) => Ok(Lexeme::Token(Token::Literal { line, col, v: Literal::Boolean(v)})),
+                        Err(_) => Ok(Lexeme::Token(Token::Identifier{line, col, v: word.clone()})),
+                    },
                 }
-            }
+            },
         };
-    
-        if let Some(t) = tok {
-            out.push(t);
+
+        let text = mem::take(&mut self.text);
+        let lexeme = lexeme.unwrap_or_else(|reason| Lexeme::Token(Token::Invalid { line, col, v: text.clone(), reason }));
+        Some(Scanned { lexeme, text, span: Span { start, end: self.position } })
+    }
+
+    // reads a character literal after the #. The character right after #\ is always part of it, so #\( and #\  work
+    fn read_char(&mut self) {
+        self.bump();
+        self.bump();
+        self.read_while(|c| !is_delimiter(c));
+    }
+
+    // reads a block comment after the #, they can be nested. False if the input ends inside it
+    fn read_block_comment(&mut self) -> bool {
+        self.bump();
+        let mut depth = 1;
+
+        while let Some(c) = self.bump() {
+            match (c, self.peek()) {
+                ('#', Some('|')) => depth += 1,
+                ('|', Some('#')) => depth -= 1,
+                _ => continue,
+            }
+            self.bump();
+            if depth == 0 {
+                return true;
+            }
+        }
+        false
+    }
+
+    // reads the rest of a string literal after the opening quote, returns its content
+    fn read_string(&mut self) -> Result<String, InvalidReason> {
+        let mut content = String::new();
+        // the rest is still read, so the token ends at the closing quote
+        let mut bad_escape = false;
+
+        loop {
+            let unescaped = match self.bump().ok_or(InvalidReason::UnterminatedString)? {
+                '"' if bad_escape => return Err(InvalidReason::BadEscape),
+                '"' => return Ok(content),
+                '\\' => match self.bump() {
+                    Some('n') => Some('\n'),
+                    Some('t') => Some('\t'),
+                    Some('"') => Some('"'),
+                    Some('\\') => Some('\\'),
+                    // \u{1F600}
+                    Some('u') if self.peek() == Some('{') => {
+                        let code = self.read_while(|c| c != '}' && c != '"');
+                        if self.peek() == Some('}') {
+                            self.bump();
+                            u32::from_str_radix(&code[1..], 16).ok().and_then(char::from_u32)
+                        } else {
+                            None
+                        }
+                    },
+                    _ => None,
+                },
+                c => Some(c),
+            };
+            match unescaped {
+                Some(c) => content.push(c),
+                None => bad_escape = true,
+            }
+        }
+    }
+}
+
+impl<I: Iterator<Item = char>> Iterator for Lexer<I> {
+    type Item = (Token, Span);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let scanned = self.read_lexeme()?;
+            if let Some(t) = self.datum_comments.filter(scanned) {
+                return Some(t);
+            }
         }
     }
-    out
 }
 
-// &mut Peekable<impl Iterator<Item = char>>
-fn read_until(chars: &mut Peekable<Chars>, fun: impl Fn(char) -> bool) -> String {
-    let mut out = String::new();
+impl<R: BufRead> Lexer<ReadChars<R>> {
+    // reads a line at a time, large files are never in memory as a whole
+    pub fn from_reader(reader: R) -> Self {
+        Self::new(ReadChars { reader, line: String::new(), at: 0, error: None })
+    }
+
+    // the error that stopped reading, the tokens end early if there was one
+    pub fn read_error(&mut self) -> Option<io::Error> {
+        self.chars.error.take()
+    }
+}
+
+// characters of a reader, see Lexer::from_reader
+pub struct ReadChars<R> {
+    reader: R,
+    line: String,
+    // byte offset of the next character in line
+    at: usize,
+    error: Option<io::Error>,
+}
+
+impl<R: BufRead> Iterator for ReadChars<R> {
+    type Item = char;
 
-    while let Some(&next) = chars.peek() {
-        if !fun(next) {
-            break;
+    fn next(&mut self) -> Option<char> {
+        if self.at == self.line.len() {
+            if self.error.is_some() {
+                return None;
+            }
+            self.line.clear();
+            self.at = 0;
+            match self.reader.read_line(&mut self.line) {
+                Ok(0) => return None,
+                Ok(_) => {},
+                Err(error) => {
+                    self.error = Some(error);
+                    return None;
+                },
+            }
         }
-        out.push(next);
-        chars.next();
+        let c = self.line[self.at..].chars().next()?;
+        self.at += c.len_utf8();
+        Some(c)
+    }
+}
+
+// lexes input that arrives in pieces, like the lines typed into the repl. A token that could go on
+// in the next piece - a word, an unterminated string or comment - is held back until it's complete
+pub struct Incremental {
+    // text of the lexeme held back
+    pending: String,
+    // where the pending text starts
+    start: Position,
+    datum_comments: DatumComments,
+}
+
+impl Default for Incremental {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Incremental {
+    pub fn new() -> Self {
+        Self { pending: String::new(), start: Position::START, datum_comments: DatumComments::default() }
     }
+
+    // tokens that are complete with the text added
+    pub fn feed(&mut self, text: &str) -> Vec<(Token, Span)> {
+        self.lex(text, true)
+    }
+
+    // the tokens held back, when there is no more input
+    pub fn finish(&mut self) -> Vec<(Token, Span)> {
+        self.lex("", false)
+    }
+
+    // true if part of a token is waiting for more input
+    pub fn is_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    fn lex(&mut self, text: &str, hold_back: bool) -> Vec<(Token, Span)> {
+        let input = mem::take(&mut self.pending) + text;
+        let mut lexer = Lexer::new(input.chars());
+        lexer.position = self.start;
+        let mut scanned = std::iter::from_fn(|| lexer.read_lexeme()).collect::<Vec<_>>();
+        self.start = lexer.position;
+
+        if let Some(last) = scanned.pop_if(|last| hold_back && last.span.end == self.start && last.may_continue()) {
+            self.start = last.span.start;
+            self.pending = last.text;
+        }
+        scanned.into_iter().filter_map(|s| self.datum_comments.filter(s)).collect()
+    }
+}
+
+// #; comments out the datum that follows it, which may be a whole list
+#[derive(Default)]
+struct DatumComments {
+    // lists opened so far
+    depth: usize,
+    // depth of every #; still waiting for the end of its datum
+    pending: Vec<usize>,
+}
+
+impl DatumComments {
+    // the token if it is not part of a commented datum
+    fn filter(&mut self, scanned: Scanned) -> Option<(Token, Span)> {
+        match scanned.lexeme {
+            Lexeme::Token(t) if self.keep(&t) => Some((t, scanned.span)),
+            Lexeme::DatumComment => {
+                self.pending.push(self.depth);
+                None
+            },
+            _ => None,
+        }
+    }
+
+    // false for tokens that are part of a commented datum
+    fn keep(&mut self, t: &Token) -> bool {
+        match t {
+            Token::Opening { .. } | Token::VectorOpening { .. } => {
+                self.depth += 1;
+                self.pending.is_empty()
+            },
+            Token::Closing { .. } => {
+                // the list ended before the datum, as in (a #;)
+                while self.pending.last() == Some(&self.depth) {
+                    self.pending.pop();
+                }
+                let keep = self.pending.is_empty();
+                self.depth = self.depth.saturating_sub(1);
+                self.finish();
+                keep
+            },
+            Token::Quote { .. } => self.pending.is_empty(),
+            // errors are reported even inside a comment
+            Token::Invalid { .. } => {
+                self.finish();
+                true
+            },
+            Token::Literal { .. } | Token::Identifier { .. } => {
+                let keep = self.pending.is_empty();
+                self.finish();
+                keep
+            },
+        }
+    }
+
+    // a datum at the depth of the innermost #; is complete
+    fn finish(&mut self) {
+        if self.pending.last() == Some(&self.depth) {
+            self.pending.pop();
+        }
+    }
+}
+
+fn is_delimiter(c: char) -> bool {
+    c.is_whitespace() || c == ')' || c == '(' || c == '"' || c == '\'' || c == ';'
+}
+
+// a digit, maybe after a sign or a dot. Words like that are numbers or errors, never identifiers
+fn looks_like_number(word: &str) -> bool {
+    let word = word.strip_prefix(['+', '-']).unwrap_or(word);
+    let word = word.strip_prefix('.').unwrap_or(word);
+    word.starts_with(|c: char| c.is_ascii_digit())
+}
+
+// #\a, #\space or #\x41
+fn parse_char(word: &str) -> Option<char> {
+    let name = word.strip_prefix("#\\")?;
+    let mut chars = name.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => Some(c),
+        _ => match name {
+            "space" => Some(' '),
+            "newline" => Some('\n'),
+            "tab" => Some('\t'),
+            "return" => Some('\r'),
+            "null" => Some('\0'),
+            "delete" => Some('\x7f'),
+            _ => u32::from_str_radix(name.strip_prefix('x')?, 16).ok().and_then(char::from_u32),
+        },
+    }
+}
+
+// character literal that reads back as the same character
+pub fn write_char(c: char) -> String {
+    match c {
+        ' ' => String::from("#\\space"),
+        '\n' => String::from("#\\newline"),
+        '\t' => String::from("#\\tab"),
+        '\r' => String::from("#\\return"),
+        '\0' => String::from("#\\null"),
+        '\x7f' => String::from("#\\delete"),
+        c if c.is_control() => format!("#\\x{:x}", c as u32),
+        c => format!("#\\{c}"),
+    }
+}
+
+// string literal that would read back as the same content
+pub fn escape(s: &str) -> String {
+    let mut out = String::from('"');
+    for c in s.chars() {
+        match c {
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
     out
 }
 
@@ -86,21 +548,21 @@ mod tests {
         let input = "(define somevalue 10)
         (+ 3 (* somevalue somevalue))";
         let expected = vec![
-            Token::Opening{line: 1},
-            Token::Identifier{ line: 1, v: s("define") },
-            Token::Identifier{ line: 1, v: s("somevalue")},
-            Token::Literal { line: 1, v: Literal::Number(10) },
-            Token::Closing{line: 1},
-
-            Token::Opening{line: 2},
-            Token::Identifier{ line: 2, v: s("+")},
-            Token::Literal { line: 2, v: Literal::Number(3) },
-            Token::Opening{line: 2},
-            Token::Identifier{ line: 2, v: s("*")},
-            Token::Identifier{ line: 2, v: s("somevalue")},
-            Token::Identifier{ line: 2, v: s("somevalue")},
-            Token::Closing { line: 2 },
-            Token::Closing { line: 2 },
+            Token::Opening{line: 1, col: 1},
+            Token::Identifier{ line: 1, col: 2, v: s("define") },
+            Token::Identifier{ line: 1, col: 9, v: s("somevalue")},
+            Token::Literal { line: 1, col: 19, v: Literal::Number(Number::Int(10)) },
+            Token::Closing{line: 1, col: 21},
+
+            Token::Opening{line: 2, col: 9},
+            Token::Identifier{ line: 2, col: 10, v: s("+")},
+            Token::Literal { line: 2, col: 12, v: Literal::Number(Number::Int(3)) },
+            Token::Opening{line: 2, col: 14},
+            Token::Identifier{ line: 2, col: 15, v: s("*")},
+            Token::Identifier{ line: 2, col: 17, v: s("somevalue")},
+            Token::Identifier{ line: 2, col: 27, v: s("somevalue")},
+            Token::Closing { line: 2, col: 36 },
+            Token::Closing { line: 2, col: 37 },
         ];
         assert_eq!(lex(input), expected)
     }
@@ -109,8 +571,8 @@ mod tests {
     fn lex_identifiers() {
         let input = " somevalue definee";
         let expected = vec![
-            Token::Identifier{line: 1, v: s("somevalue")},
-            Token::Identifier{line: 1, v: s("definee")},
+            Token::Identifier{line: 1, col: 2, v: s("somevalue")},
+            Token::Identifier{line: 1, col: 12, v: s("definee")},
         ];
         assert_eq!(lex(input), expected)
     }
@@ -118,21 +580,32 @@ mod tests {
     #[test]
     fn lex_number() {
         let input = " 1234";
-        let expected = vec![Token::Literal { line: 1, v: Literal::Number(1234)}];
+        let expected = vec![Token::Literal { line: 1, col: 2, v: Literal::Number(Number::Int(1234))}];
         assert_eq!(lex(input), expected)
     }
 
+    #[test]
+    fn lex_numeric_tower() {
+        let numbers = lex("3.14 -1e-5 1/3 99999999999999999999 +inf.0 1/0 1.2.3").into_iter()
+            .map(|t| match t {
+                Token::Literal { v: Literal::Number(n), .. } => n.to_string(),
+                other => format!("not a number: {other}"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(numbers, vec!["3.14", "-1e-5", "1/3", "99999999999999999999", "+inf.0", "not a number: 1/0", "not a number: 1.2.3"]);
+    }
+
     #[test]
     fn lex_whitespaces() {
         let input = " \t \n 123\t";
-        let expected = vec![Token::Literal { line: 2, v: Literal::Number(123)}];
+        let expected = vec![Token::Literal { line: 2, col: 2, v: Literal::Number(Number::Int(123))}];
         assert_eq!(lex(input), expected)
     }
 
     #[test]
     fn lex_whitespaces_and_string() {
         let input = " \t \n \" fo\no\t\"\t";
-        let expected = vec![Token::Literal{line: 2, v: Literal::String(s("\" fo\no\t\""))}];
+        let expected = vec![Token::Literal{line: 2, col: 2, v: Literal::String(s(" fo\no\t"))}];
         assert_eq!(lex(input), expected)
     }
 
@@ -141,8 +614,8 @@ mod tests {
         let input = "\" hello world if 123\" 123";
 
         let expected = vec![
-            Token::Literal { line: 1, v: Literal::String(s("\" hello world if 123\""))},
-            Token::Literal{line: 1, v: Literal::Number(123)},
+            Token::Literal { line: 1, col: 1, v: Literal::String(s(" hello world if 123"))},
+            Token::Literal{line: 1, col: 23, v: Literal::Number(Number::Int(123))},
         ];
         assert_eq!(lex(input), expected)
     }
@@ -151,7 +624,7 @@ mod tests {
     fn lex_invalid_string() {
         let input = "\" hello world ";
 
-        let expected = vec![Token::Invalid{line: 1, v: s("\" hello world ")}];
+        let expected = vec![Token::Invalid{line: 1, col: 1, v: s("\" hello world "), reason: InvalidReason::UnterminatedString}];
         assert_eq!(lex(input), expected)
     }
 
@@ -162,15 +635,15 @@ mod tests {
         (define s \" hello world ";
 
         let expected = vec![
-            Token::Opening{line: 2},
-            Token::Identifier { line: 2, v: s("define")},
-            Token::Identifier{line: 2, v: "x".to_owned()},
-            Token::Literal { line: 2, v: Literal::Number(3)},
-            Token::Closing{line: 2},
-            Token::Opening{line: 3},
-            Token::Identifier { line: 3, v: s("define")},
-            Token::Identifier{line: 3, v: "s".to_owned()},
-            Token::Invalid{line: 3, v: "\" hello world ".to_owned()},
+            Token::Opening{line: 2, col: 9},
+            Token::Identifier { line: 2, col: 10, v: s("define")},
+            Token::Identifier{line: 2, col: 17, v: "x".to_owned()},
+            Token::Literal { line: 2, col: 19, v: Literal::Number(Number::Int(3))},
+            Token::Closing{line: 2, col: 20},
+            Token::Opening{line: 3, col: 9},
+            Token::Identifier { line: 3, col: 10, v: s("define")},
+            Token::Identifier{line: 3, col: 17, v: "s".to_owned()},
+            Token::Invalid{line: 3, col: 19, v: "\" hello world ".to_owned(), reason: InvalidReason::UnterminatedString},
         ];
         assert_eq!(lex(input), expected)
     }
@@ -184,34 +657,34 @@ mod tests {
             (printf \"Oranges\"))";
 
         let expected = vec![
-            Token::Opening{line: 1},
-            Token::Identifier{line: 1, v: "define".to_owned()},
-            Token::Identifier{line: 1, v: "apples".to_owned()},
-            Token::Literal{line: 1, v: Literal::Number(5)},
-            Token::Closing{line: 1},
-            Token::Opening{line: 2},
-            Token::Identifier{line: 2, v: s("define")},
-            Token::Identifier{line: 2, v: s("oranges")},
-            Token::Literal{line: 2, v: Literal::Number(6)},
-            Token::Closing{line: 2},
-            Token::Opening{line: 3},
-            Token::Identifier{line: 3, v: s("if")},
-            Token::Opening{line: 3},
-            Token::Identifier{line: 3, v: "<=".to_owned()},
-            Token::Identifier{line: 3, v: "apples".to_owned()},
-            Token::Identifier{line: 3, v: "oranges".to_owned()},
-            Token::Closing{line: 3},
+            Token::Opening{line: 1, col: 1},
+            Token::Identifier{line: 1, col: 2, v: "define".to_owned()},
+            Token::Identifier{line: 1, col: 9, v: "apples".to_owned()},
+            Token::Literal{line: 1, col: 16, v: Literal::Number(Number::Int(5))},
+            Token::Closing{line: 1, col: 17},
+            Token::Opening{line: 2, col: 9},
+            Token::Identifier{line: 2, col: 10, v: s("define")},
+            Token::Identifier{line: 2, col: 17, v: s("oranges")},
+            Token::Literal{line: 2, col: 25, v: Literal::Number(Number::Int(6))},
+            Token::Closing{line: 2, col: 26},
+            Token::Opening{line: 3, col: 9},
+            Token::Identifier{line: 3, col: 10, v: s("if")},
+            Token::Opening{line: 3, col: 13},
+            Token::Identifier{line: 3, col: 14, v: "<=".to_owned()},
+            Token::Identifier{line: 3, col: 17, v: "apples".to_owned()},
+            Token::Identifier{line: 3, col: 24, v: "oranges".to_owned()},
+            Token::Closing{line: 3, col: 31},
             
-            Token::Opening{line: 4},
-            Token::Identifier{line: 4, v: "printf".to_owned()},
-            Token::Literal{line: 4, v: Literal::String(s("\"Apples\""))},
-            Token::Closing{line: 4},
+            Token::Opening{line: 4, col: 13},
+            Token::Identifier{line: 4, col: 14, v: "printf".to_owned()},
+            Token::Literal{line: 4, col: 21, v: Literal::String(s("Apples"))},
+            Token::Closing{line: 4, col: 29},
             
-            Token::Opening{line: 5},
-            Token::Identifier{line: 5, v: "printf".to_owned()},
-            Token::Literal{line: 5, v: Literal::String(s("\"Oranges\""))},
-            Token::Closing{line: 5},
-            Token::Closing{line: 5},
+            Token::Opening{line: 5, col: 13},
+            Token::Identifier{line: 5, col: 14, v: "printf".to_owned()},
+            Token::Literal{line: 5, col: 21, v: Literal::String(s("Oranges"))},
+            Token::Closing{line: 5, col: 30},
+            Token::Closing{line: 5, col: 31},
         ];
         assert_eq!(lex(input), expected)
     }
@@ -221,13 +694,13 @@ mod tests {
         let input = "< <= > >= ! !! !=";
 
         let expected = vec![
-            Token::Identifier { line: 1, v: s("<")},
-            Token::Identifier { line: 1, v: s("<=")},
-            Token::Identifier { line: 1, v: s(">")},
-            Token::Identifier { line: 1, v: s(">=")},
-            Token::Identifier { line: 1, v: s("!")},
-            Token::Identifier { line: 1, v: s("!!")},
-            Token::Identifier { line: 1, v: s("!=")},
+            Token::Identifier { line: 1, col: 1, v: s("<")},
+            Token::Identifier { line: 1, col: 3, v: s("<=")},
+            Token::Identifier { line: 1, col: 6, v: s(">")},
+            Token::Identifier { line: 1, col: 8, v: s(">=")},
+            Token::Identifier { line: 1, col: 11, v: s("!")},
+            Token::Identifier { line: 1, col: 13, v: s("!!")},
+            Token::Identifier { line: 1, col: 16, v: s("!=")},
         ];
         assert_eq!(lex(input), expected)
     }
@@ -237,7 +710,7 @@ mod tests {
         let input = "<<=>>=!!!!=";
 
         let expected = vec![
-            Token::Identifier{line: 1, v: s("<<=>>=!!!!=")},
+            Token::Identifier{line: 1, col: 1, v: s("<<=>>=!!!!=")},
         ];
         assert_eq!(lex(input), expected)
     }
@@ -249,24 +722,24 @@ mod tests {
     
                             (dbl 2)";
         let expected = vec![
-            Token::Opening{line: 1},
-            Token::Identifier{line: 1, v: "define".to_string()},
-            Token::Opening{line: 1},
-            Token::Identifier{line: 1, v: "dbl".to_string()},
-            Token::Identifier{line: 1, v: "x".to_string()},
-            Token::Closing{line: 1},
-
-            Token::Opening{line: 2},
-            Token::Identifier{line: 2, v: "*".to_string()},
-            Token::Literal{line: 2, v: Literal::Number(2)},
-            Token::Identifier{line: 2, v: "x".to_string()},
-            Token::Closing{line: 2},
-            Token::Closing{line: 2},
+            Token::Opening{line: 1, col: 1},
+            Token::Identifier{line: 1, col: 2, v: "define".to_string()},
+            Token::Opening{line: 1, col: 9},
+            Token::Identifier{line: 1, col: 10, v: "dbl".to_string()},
+            Token::Identifier{line: 1, col: 14, v: "x".to_string()},
+            Token::Closing{line: 1, col: 15},
+
+            Token::Opening{line: 2, col: 29},
+            Token::Identifier{line: 2, col: 30, v: "*".to_string()},
+            Token::Literal{line: 2, col: 32, v: Literal::Number(Number::Int(2))},
+            Token::Identifier{line: 2, col: 34, v: "x".to_string()},
+            Token::Closing{line: 2, col: 35},
+            Token::Closing{line: 2, col: 36},
             
-            Token::Opening{line: 4},
-            Token::Identifier{line: 4, v: "dbl".to_string()},
-            Token::Literal{ line: 4, v: Literal::Number(2)},
-            Token::Closing{line: 4},
+            Token::Opening{line: 4, col: 29},
+            Token::Identifier{line: 4, col: 30, v: "dbl".to_string()},
+            Token::Literal{ line: 4, col: 34, v: Literal::Number(Number::Int(2))},
+            Token::Closing{line: 4, col: 35},
         ];
         assert_eq!(lex(input), expected)
     }
@@ -277,27 +750,223 @@ mod tests {
                            (define y false)
                            (define z(= x y))";
         let expected = vec![
-            Token::Opening{line: 1},
-            Token::Identifier{line: 1, v: "define".to_string()},
-            Token::Identifier{line: 1, v: "x".to_string()},
-            Token::Literal{line: 1, v: Literal::Boolean(true)},
-            Token::Closing{line: 1},
-
-            Token::Opening{line: 2},
-            Token::Identifier{line: 2, v: "define".to_string()},
-            Token::Identifier{line: 2, v: "y".to_string()},
-            Token::Literal{line: 2, v: Literal::Boolean(false)},
-            Token::Closing{line: 2},
-
-            Token::Opening{line: 3},
-            Token::Identifier{line: 3, v: "define".to_string()},
-            Token::Identifier{line: 3, v: "z".to_string()},
-            Token::Opening{line: 3},
-            Token::Identifier{line: 3, v: "=".to_string()},
-            Token::Identifier{line: 3, v: "x".to_string()},
-            Token::Identifier{line: 3, v: "y".to_string()},
-            Token::Closing{line: 3},
-            Token::Closing{line: 3},
+            Token::Opening{line: 1, col: 1},
+            Token::Identifier{line: 1, col: 2, v: "define".to_string()},
+            Token::Identifier{line: 1, col: 9, v: "x".to_string()},
+            Token::Literal{line: 1, col: 11, v: Literal::Boolean(true)},
+            Token::Closing{line: 1, col: 15},
+
+            Token::Opening{line: 2, col: 28},
+            Token::Identifier{line: 2, col: 29, v: "define".to_string()},
+            Token::Identifier{line: 2, col: 36, v: "y".to_string()},
+            Token::Literal{line: 2, col: 38, v: Literal::Boolean(false)},
+            Token::Closing{line: 2, col: 43},
+
+            Token::Opening{line: 3, col: 28},
+            Token::Identifier{line: 3, col: 29, v: "define".to_string()},
+            Token::Identifier{line: 3, col: 36, v: "z".to_string()},
+            Token::Opening{line: 3, col: 37},
+            Token::Identifier{line: 3, col: 38, v: "=".to_string()},
+            Token::Identifier{line: 3, col: 40, v: "x".to_string()},
+            Token::Identifier{line: 3, col: 42, v: "y".to_string()},
+            Token::Closing{line: 3, col: 43},
+            Token::Closing{line: 3, col: 44},
+        ];
+        assert_eq!(lex(input), expected)
+    }
+
+    #[test]
+    fn lex_quote() {
+        let input = "'x '(1 a) b'c";
+        let expected = vec![
+            Token::Quote{line: 1, col: 1},
+            Token::Identifier{line: 1, col: 2, v: s("x")},
+            Token::Quote{line: 1, col: 4},
+            Token::Opening{line: 1, col: 5},
+            Token::Literal{line: 1, col: 6, v: Literal::Number(Number::Int(1))},
+            Token::Identifier{line: 1, col: 8, v: s("a")},
+            Token::Closing{line: 1, col: 9},
+            Token::Identifier{line: 1, col: 11, v: s("b")},
+            Token::Quote{line: 1, col: 12},
+            Token::Identifier{line: 1, col: 13, v: s("c")},
+        ];
+        assert_eq!(lex(input), expected)
+    }
+
+    #[test]
+    fn lex_spans() {
+        let input = "(define x\n  \"a\nb\" 'y)";
+        let spans = lex_with_spans(input).into_iter().map(|(_, span)| span).collect::<Vec<_>>();
+        let span = |line, col, end_line, end_col| Span {
+            start: Position { line, col },
+            end: Position { line: end_line, col: end_col },
+        };
+        assert_eq!(spans, vec![
+            span(1, 1, 1, 2),
+            span(1, 2, 1, 8),
+            span(1, 9, 1, 10),
+            span(2, 3, 3, 3),
+            span(3, 4, 3, 5),
+            span(3, 5, 3, 6),
+            span(3, 6, 3, 7),
+        ]);
+    }
+
+    #[test]
+    fn lex_string_escapes() {
+        let input = r#""a\n\t\"q\"\\ \u{41}\u{1F600}" "\q" "\u{110000}" "\u{41""#;
+        let expected = vec![
+            Token::Literal{line: 1, col: 1, v: Literal::String(s("a\n\t\"q\"\\ A\u{1F600}"))},
+            Token::Invalid{line: 1, col: 32, v: s(r#""\q""#), reason: InvalidReason::BadEscape},
+            Token::Invalid{line: 1, col: 37, v: s(r#""\u{110000}""#), reason: InvalidReason::BadEscape},
+            Token::Invalid{line: 1, col: 50, v: s(r#""\u{41""#), reason: InvalidReason::BadEscape},
+        ];
+        let tokens = lex(input);
+        assert_eq!(tokens, expected);
+        assert_eq!(tokens[0].to_string(), r#""a\n\t\"q\"\\ A😀""#);
+    }
+
+    fn reasons(input: &str) -> Vec<Option<InvalidReason>> {
+        lex(input).into_iter().map(|t| match t {
+            Token::Invalid { reason, .. } => Some(reason),
+            _ => None,
+        }).collect()
+    }
+
+    #[test]
+    fn invalid_reasons() {
+        use InvalidReason::*;
+        assert_eq!(reasons(r#""abc"#), vec![Some(UnterminatedString)]);
+        assert_eq!(reasons(r#""abc\""#), vec![Some(UnterminatedString)]);
+        assert_eq!(reasons(r#""\q" "\q"#), vec![Some(BadEscape), Some(UnterminatedString)]);
+        assert_eq!(reasons("abc #| a |#"), vec![None]);
+        assert_eq!(reasons("#| a #| b |#"), vec![Some(UnterminatedComment)]);
+        assert_eq!(reasons("1/0 1.2.3 -1x .5e 1+ + - ... -x .a"), vec![Some(BadNumber); 5].into_iter().chain(vec![None; 5]).collect::<Vec<_>>());
+        assert_eq!(reasons(r"#\ab #\x110000 #\b"), vec![Some(BadChar), Some(BadChar), None]);
+    }
+
+    #[test]
+    fn tokens_start_where_their_spans_do() {
+        let input = "(define x\n  \"a\nb\" 'y) #;(z)\n  bad\"";
+        for (t, span) in lex_with_spans(input) {
+            assert_eq!(t.position(), span.start, "{t}");
+        }
+    }
+
+    #[test]
+    fn lex_from_reader() {
+        let input = "(define (f x)\n  \"λ\nμ\") ; done\n(f 1)";
+        let mut lexer = Lexer::from_reader(io::BufReader::with_capacity(4, input.as_bytes()));
+        assert_eq!(lexer.by_ref().collect::<Vec<_>>(), lex_with_spans(input));
+        assert!(lexer.read_error().is_none());
+
+        let mut lexer = Lexer::from_reader(&b"(a b)\n(c \xff)"[..]);
+        assert_eq!(lexer.by_ref().map(|(t, _)| t.to_string()).collect::<Vec<_>>(), vec!["(", "a", "b", ")"]);
+        assert_eq!(lexer.read_error().map(|e| e.kind()), Some(io::ErrorKind::InvalidData));
+    }
+
+    #[test]
+    fn lex_incrementally() {
+        let input = "(define (f x) ; comment\n  \"a\nb\" #| block\n|# #\\space 123 #;(x y) 'z)\n#| open";
+        // every way to split the input gives the same tokens
+        for i in 0..input.len() {
+            for j in i..input.len() {
+                if !(input.is_char_boundary(i) && input.is_char_boundary(j)) {
+                    continue;
+                }
+                let mut lexer = Incremental::new();
+                let mut tokens = lexer.feed(&input[..i]);
+                tokens.extend(lexer.feed(&input[i..j]));
+                tokens.extend(lexer.feed(&input[j..]));
+                assert!(lexer.is_pending());
+                tokens.extend(lexer.finish());
+                assert_eq!(tokens, lex_with_spans(input), "split at {i} and {j}");
+            }
+        }
+    }
+
+    #[test]
+    fn incomplete_tokens_are_held_back() {
+        let words = |tokens: Vec<(Token, Span)>| tokens.iter().map(|(t, _)| t.to_string()).collect::<Vec<_>>();
+        let mut lexer = Incremental::new();
+        assert_eq!(words(lexer.feed("(display \"a")), vec!["(", "display"]);
+        assert!(lexer.is_pending());
+        assert_eq!(words(lexer.feed("b\")")), vec!["\"ab\"", ")"]);
+        assert!(!lexer.is_pending());
+        assert_eq!(words(lexer.feed("12")), Vec::<String>::new());
+        assert_eq!(words(lexer.feed("3 ")), vec!["123"]);
+        assert_eq!(words(lexer.finish()), Vec::<String>::new());
+    }
+
+    #[test]
+    fn lex_line_comments() {
+        let input = "; header\n(define x 1) ; trailing\nx;no space\n\";\"";
+        let expected = vec![
+            Token::Opening{line: 2, col: 1},
+            Token::Identifier{line: 2, col: 2, v: s("define")},
+            Token::Identifier{line: 2, col: 9, v: s("x")},
+            Token::Literal{line: 2, col: 11, v: Literal::Number(Number::Int(1))},
+            Token::Closing{line: 2, col: 12},
+            Token::Identifier{line: 3, col: 1, v: s("x")},
+            Token::Literal{line: 4, col: 1, v: Literal::String(s(";"))},
+        ];
+        assert_eq!(lex(input), expected)
+    }
+
+    #[test]
+    fn lex_block_comments() {
+        let input = "a #| one\n #| nested |# still\n comment |# b #||# c\n#| open";
+        let expected = vec![
+            Token::Identifier{line: 1, col: 1, v: s("a")},
+            Token::Identifier{line: 3, col: 13, v: s("b")},
+            Token::Identifier{line: 3, col: 20, v: s("c")},
+            Token::Invalid{line: 4, col: 1, v: s("#| open"), reason: InvalidReason::UnterminatedComment},
+        ];
+        assert_eq!(lex(input), expected);
+        let spans = lex_with_spans(input).into_iter().map(|(_, span)| span.start).collect::<Vec<_>>();
+        assert_eq!(spans[1], Position { line: 3, col: 13 });
+    }
+
+    #[test]
+    fn lex_datum_comments() {
+        let tokens = |input| lex(input).iter().map(|t| t.to_string()).collect::<Vec<_>>().join(" ");
+        assert_eq!(tokens("(a #;(b (c)) d)"), "( a d )");
+        assert_eq!(tokens("#;#;a b c"), "c");
+        assert_eq!(tokens("#; 'x y"), "y");
+        assert_eq!(tokens("(a #;) b"), "( a ) b");
+        assert_eq!(tokens("#;\n; comment\n(a\n b) c"), "c");
+        assert_eq!(tokens("(#;#| block |# x y)"), "( y )");
+    }
+
+    #[test]
+    fn lex_chars() {
+        let input = r"#\a #\space #\( #\) #\x41 #\λ #\newline(#\;) #\ab #\";
+        let tokens = lex(input);
+        let chars = tokens.iter()
+            .map(|t| match t {
+                Token::Literal { v: Literal::Char(c), .. } => c.to_string(),
+                other => format!("[{other}]"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(chars, vec!["a", " ", "(", ")", "A", "λ", "\n", "[(]", ";", "[)]", "[#\\ab]", "[#\\]"]);
+        assert!(matches!(tokens[10], Token::Invalid { .. }));
+        assert_eq!(tokens[1].to_string(), r"#\space");
+        assert_eq!(write_char('\u{1}'), r"#\x1");
+    }
+
+    #[test]
+    fn lex_vectors() {
+        let tokens = lex("#(1 #(a)) #;#(x) '#()");
+        assert_eq!(tokens.iter().map(|t| t.to_string()).collect::<Vec<_>>().join(" "), "#( 1 #( a ) ) ' #( )");
+        assert_eq!(tokens[0], Token::VectorOpening { line: 1, col: 1 });
+    }
+
+    #[test]
+    fn lex_line_after_multiline_string() {
+        let input = "\"a\nb\" x";
+        let expected = vec![
+            Token::Literal{line: 1, col: 1, v: Literal::String(s("a\nb"))},
+            Token::Identifier{line: 2, col: 4, v: s("x")},
         ];
         assert_eq!(lex(input), expected)
     }
@@ -0,0 +1,31 @@
+// lisp interpreter that can be embedded in rust programs, see Interpreter
+pub mod lexer;
+pub mod parser;
+pub mod evaluator;
+pub mod builtins;
+pub mod diagnostics;
+pub mod number;
+pub mod limits;
+pub mod condition;
+pub mod table;
+pub mod pair;
+pub mod heap;
+pub mod printer;
+pub mod debug;
+mod compiler;
+mod vm;
+mod expander;
+mod convert;
+mod interpreter;
+mod modules;
+
+pub use builtins::Arity;
+pub use condition::Condition;
+pub use evaluator::{Backend, Evaluator};
+pub use heap::GcStats;
+pub use interpreter::{Captured, Error, Interpreter};
+pub use limits::{Limit, Limits};
+pub use number::Number;
+pub use pair::Pair;
+pub use parser::{CompilerError, SExpression};
+pub use table::HashTable;
@@ -0,0 +1,60 @@
+use std::fmt;
+
+// bounds for running untrusted code, None means unlimited
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Limits {
+    // expressions evaluated by the tree walker, or instructions run by the vm
+    pub steps: Option<u64>,
+    // nested evaluations, or call frames of the vm. Tail calls don't count. Without it the tree walker
+    // still stops before it runs out of native stack, see Evaluator::set_stack_size
+    pub depth: Option<usize>,
+    // every list cell or vector slot and every byte of a string or number created by a builtin counts as one
+    pub allocations: Option<usize>,
+}
+
+// the limit that stopped evaluation
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Limit {
+    Steps,
+    Depth,
+    Allocations,
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Limit::Steps => write!(f, "step"),
+            Limit::Depth => write!(f, "recursion depth"),
+            Limit::Allocations => write!(f, "allocation"),
+        }
+    }
+}
+
+// what was used since the last reset
+#[derive(Debug, Default)]
+pub(crate) struct Usage {
+    pub(crate) steps: u64,
+    pub(crate) depth: usize,
+    pub(crate) allocations: usize,
+    // where the outermost evaluation started on the native stack
+    pub(crate) stack: usize,
+}
+
+// stack the command line runs the program with. The tree walker recurses on the native stack, deep
+// recursion needs a lot of it
+pub const STACK_SIZE: usize = 256 << 20;
+
+// stack an evaluator assumes it has until told otherwise, what a thread spawned by std gets
+pub(crate) const DEFAULT_STACK_SIZE: usize = 2 << 20;
+
+// native stack nested evaluations may use without a depth limit on a thread with stack_size bytes of
+// stack. The rest is left for what ran before the evaluation and for builtins called at the deepest level
+pub(crate) fn stack_budget(stack_size: usize) -> usize {
+    stack_size / 4 * 3
+}
+
+// position on the native stack, the distance between two is the stack used in between
+pub(crate) fn stack_position() -> usize {
+    let marker = 0u8;
+    std::hint::black_box(&marker) as *const u8 as usize
+}
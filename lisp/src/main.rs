@@ -1,55 +1,167 @@
 use std::env;
 use std::fs;
-use std::io::{self, Write};
+use std::io::{self, IsTerminal, Write};
+use std::path::Path;
+use std::process::ExitCode;
+use std::thread;
 
-use crate::lexer::lex;
-use crate::parser::parse;
+use lisp::debug::Tracer;
+use lisp::diagnostics::{run_file, run_reader, run_source};
+use lisp::printer::{self, format_source};
+use lisp::{limits, Backend, Evaluator, SExpression};
 
-mod lexer;
-mod parser;
-mod evaluator;
+mod debugger;
+mod repl;
 
-fn main() {
+const USAGE: &str = "usage: lisp [--vm | --trace] [file | - | -e <expression>]\n       lisp fmt <file>";
+
+// the program runs on a thread with a stack of known size, the tree walker recurses on it
+fn main() -> ExitCode {
+    match thread::Builder::new().stack_size(limits::STACK_SIZE).spawn(run) {
+        Ok(thread) => thread.join().unwrap_or(ExitCode::FAILURE),
+        Err(error) => {
+            eprintln!("could not start the interpreter: {error}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run() -> ExitCode {
     let args = env::args().skip(1).collect::<Vec<_>>();
+    let mut args = args.iter().map(String::as_str).collect::<Vec<_>>();
+    let mut backend = Backend::TreeWalker;
+    let mut trace = false;
+    while let Some(flag) = args.first() {
+        match *flag {
+            "--vm" => backend = Backend::Vm,
+            "--trace" => trace = true,
+            _ => break,
+        }
+        args.remove(0);
+    }
+    // only the tree walker reports what it evaluates
+    if trace && backend == Backend::Vm {
+        eprintln!("--trace can't be used with --vm\n{USAGE}");
+        return ExitCode::from(2);
+    }
+
+    let evaluator = || {
+        let mut evaluator = Evaluator::with_backend(backend);
+        evaluator.set_stack_size(limits::STACK_SIZE);
+        if trace {
+            evaluator.set_observer(Tracer::new(io::stderr()));
+        }
+        evaluator
+    };
+    let ok = match args.as_slice() {
+        [] if io::stdin().is_terminal() => {
+            repl::run(backend, trace);
+            true
+        },
+        [] | ["-"] => stdin_mode(evaluator()),
+        ["fmt", file_name] => fmt_mode(file_name),
+        ["-e", expression] => run_program(&mut evaluator(), "<expression>", expression, true),
+        [file_name] if !file_name.starts_with('-') => file_mode(file_name, evaluator()),
+        _ => {
+            eprintln!("{USAGE}");
+            return ExitCode::from(2);
+        }
+    };
 
-    match args.len() {
-        0 => interpreter_mode(),
-        1 => file_mode(args.get(0).unwrap()),
-        v => println!("Invalid numer of arguments {}, exiting", v),
+    if ok {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
     }
 }
 
-fn file_mode(file_name: &str) {
-    match fs::read_to_string(file_name) {
-        Ok(file_content) => println!("{:?}", parse(lex(&file_content))),
-        Err(error) => println!("error opening file {file_name}: {error}"),
+fn file_mode(file_name: &str, mut evaluator: Evaluator) -> bool {
+    // modules next to the script can be imported wherever it's run from
+    if let Some(dir) = Path::new(file_name).parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        evaluator.add_search_path(dir);
+    }
+    match run_file(&mut evaluator, Path::new(file_name)) {
+        Ok(errors) => report_errors(&errors),
+        Err(error) => {
+            eprintln!("error opening file {file_name}: {error}");
+            false
+        }
     }
 }
 
-fn interpreter_mode() {
-    println!("Welcome to Lisp interpreter");
-    println!("Type 'quit' to exit");
-    loop {
-        print!("> ");
-        io::stdout().flush().unwrap();
+// prints the file formatted, see format_source
+fn fmt_mode(file_name: &str) -> bool {
+    let source = match fs::read_to_string(file_name) {
+        Ok(source) => source,
+        Err(error) => {
+            eprintln!("error opening file {file_name}: {error}");
+            return false;
+        }
+    };
+    match format_source(&source, printer::WIDTH) {
+        Ok(formatted) => {
+            print!("{formatted}");
+            true
+        },
+        Err(diagnostics) => {
+            for d in diagnostics {
+                eprintln!("{}", d.render(file_name, &source));
+            }
+            false
+        }
+    }
+}
 
-        let data = read_line();
-        let trimmed = data.trim();
+fn stdin_mode(mut evaluator: Evaluator) -> bool {
+    let errors = run_reader(&mut evaluator, "<stdin>", io::stdin().lock());
+    report_errors(&errors)
+}
 
-        if trimmed == "quit" {
-            break;
-        } else if !trimmed.is_empty() {
-            print!("{}", data);
+// returns false if the program could not be parsed or failed
+fn run_program(evaluator: &mut Evaluator, file_name: &str, source: &str, print_results: bool) -> bool {
+    let report = run_source(evaluator, file_name, source);
+    if print_results {
+        for r in report.results.iter().filter(|r| **r != SExpression::Void) {
+            println!("{}", printer::pretty(r, printer::WIDTH));
         }
     }
-    println!("Goodbye")
+    report_errors(&report.errors)
+}
+
+// returns true if there were none
+fn report_errors(errors: &[String]) -> bool {
+    // output of display has to show up before the error
+    let _ = io::stdout().flush();
+    for e in errors {
+        eprintln!("{e}");
+    }
+    errors.is_empty()
 }
 
-fn read_line() -> String {
-    let mut out = String::new();
-    io::stdin()
-        .read_line(&mut out)
-        .expect("Failed to read line");
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    out
+    #[test]
+    fn program_succeeds() {
+        assert!(run_program(&mut Evaluator::with_backend(Backend::TreeWalker), "test", "(define (f x) (* x 2)) (f 2)", false));
+        assert!(run_program(&mut Evaluator::with_backend(Backend::Vm), "test", "(define (f x) (* x 2)) (f 2)", false));
+    }
+
+    #[test]
+    fn program_fails_on_parse_error() {
+        assert!(!run_program(&mut Evaluator::with_backend(Backend::TreeWalker), "test", "(+ 1 2) )", false));
+    }
+
+    #[test]
+    fn program_fails_on_eval_error() {
+        assert!(!run_program(&mut Evaluator::with_backend(Backend::TreeWalker), "test", "(define x 1) (car x)", false));
+        assert!(!run_program(&mut Evaluator::with_backend(Backend::Vm), "test", "(define x 1) (car x)", false));
+    }
+
+    #[test]
+    fn missing_file() {
+        assert!(!file_mode("/does/not/exist.scm", Evaluator::new()));
+        assert!(!fmt_mode("/does/not/exist.scm"));
+    }
 }
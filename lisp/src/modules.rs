@@ -0,0 +1,295 @@
+use std::{collections::HashMap, fs::{self, File}, io::BufReader, path::{Path, PathBuf}};
+
+use crate::{
+    evaluator::{EnvRef, Evaluator},
+    lexer::Lexer,
+    parser::{forms, CompilerError, SExpression},
+};
+
+// files and modules of an evaluator
+pub(crate) struct Modules {
+    // exported bindings of every module defined so far, imported again without loading the file
+    defined: HashMap<String, Vec<(String, SExpression)>>,
+    // modules being loaded by import, innermost last
+    importing: Vec<String>,
+    // directories import looks for name.scm in
+    search_paths: Vec<PathBuf>,
+    // files being loaded, innermost last. Relative paths are resolved against the directory of the last one
+    loading: Vec<PathBuf>,
+}
+
+impl Default for Modules {
+    fn default() -> Self {
+        Self {
+            defined: HashMap::new(),
+            importing: vec![],
+            search_paths: vec![PathBuf::from(".")],
+            loading: vec![],
+        }
+    }
+}
+
+impl Modules {
+    fn resolve(&self, path: &Path) -> PathBuf {
+        match self.loading.last().and_then(|file| file.parent()) {
+            Some(dir) if path.is_relative() => dir.join(path),
+            _ => path.to_path_buf(),
+        }
+    }
+
+    // name.scm next to the file being loaded, or in the first search path that has it
+    fn find(&self, name: &str) -> Option<PathBuf> {
+        let file = format!("{name}.scm");
+        let current = self.loading.last().and_then(|f| f.parent()).map(|dir| dir.join(&file));
+        current.into_iter()
+            .chain(self.search_paths.iter().map(|dir| dir.join(&file)))
+            .find(|path| path.is_file())
+            .map(|path| fs::canonicalize(&path).unwrap_or(path))
+    }
+}
+
+// (module ...) or (import ...), they are only allowed at the top level of a program or module
+pub(crate) fn is_declaration(e: &SExpression) -> bool {
+    matches!(e, SExpression::List(v) if matches!(v.first(), Some(SExpression::Identifier(id)) if id == "module" || id == "import"))
+}
+
+impl Evaluator {
+    // directories searched by import, after the directory of the file being loaded
+    pub fn add_search_path(&mut self, dir: impl Into<PathBuf>) {
+        self.modules.search_paths.push(dir.into());
+    }
+
+    pub(crate) fn clear_search_paths(&mut self) {
+        self.modules.search_paths.clear();
+    }
+
+    // evaluates every expression of the file at the top level
+    pub fn load(&mut self, path: &Path) -> Result<(), CompilerError> {
+        let path = self.modules.resolve(path);
+        let path = fs::canonicalize(&path).unwrap_or(path);
+        let failed = |reason: String| CompilerError::LoadFailed { path: path.display().to_string(), reason };
+        if self.modules.loading.contains(&path) {
+            let mut cycle = self.modules.loading.iter().map(|p| p.display().to_string()).collect::<Vec<_>>();
+            cycle.push(path.display().to_string());
+            return Err(CompilerError::ImportCycle(cycle));
+        }
+        // every expression runs as soon as it's read, the file is never in memory as a whole
+        let file = File::open(&path).map_err(|e| failed(e.to_string()))?;
+        let mut lexer = Lexer::from_reader(BufReader::new(file));
+
+        self.modules.loading.push(path.clone());
+        let global = self.env.clone();
+        let result = forms(&mut lexer).try_for_each(|parsed| match parsed {
+            Ok(form) => self.eval_declaration(form.expr, &global).map(|_| ()),
+            Err(errors) => Err(match errors[0].span {
+                Some(span) => failed(format!("{}:{}: {}", span.start.line, span.start.col, errors[0].error)),
+                None => failed(errors[0].error.to_string()),
+            }),
+        });
+        self.modules.loading.pop();
+        match lexer.read_error() {
+            Some(error) => result.and(Err(failed(error.to_string()))),
+            None => result,
+        }
+    }
+
+    // top level expression of a program or module body
+    pub(crate) fn eval_declaration(&mut self, e: SExpression, env: &EnvRef) -> Result<SExpression, CompilerError> {
+        if !is_declaration(&e) {
+            return self.eval_in(e, env);
+        }
+        let v = match &e {
+            SExpression::List(v) => v,
+            _ => unreachable!("declarations are lists"),
+        };
+        match &v[0] {
+            SExpression::Identifier(id) if id == "module" => self.define_module(&e),
+            _ => {
+                for name in &v[1..] {
+                    match name {
+                        SExpression::Identifier(name) => self.import(name, env)?,
+                        _ => return Err(CompilerError::InvalidList(e.clone())),
+                    }
+                }
+                Ok(SExpression::Void)
+            },
+        }
+    }
+
+    // (module name (export name...) body...) - the body sees the global definitions, only exported names are visible outside
+    fn define_module(&mut self, e: &SExpression) -> Result<SExpression, CompilerError> {
+        let invalid = || CompilerError::InvalidList(e.clone());
+        let (name, exports, body) = match e {
+            SExpression::List(v) if v.len() >= 3 => match (&v[1], &v[2]) {
+                (SExpression::Identifier(name), SExpression::List(exports)) => match exports.split_first() {
+                    Some((SExpression::Identifier(export), names)) if export == "export" => (name, names, &v[3..]),
+                    _ => return Err(invalid()),
+                },
+                _ => return Err(invalid()),
+            },
+            _ => return Err(invalid()),
+        };
+        let exports = exports.iter()
+            .map(|e| match e {
+                SExpression::Identifier(id) => Ok(id.clone()),
+                _ => Err(invalid()),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let global = self.env.clone();
+        let scope = self.extend(&global);
+        for e in body {
+            self.eval_declaration(e.clone(), &scope)?;
+        }
+        let bindings = exports.into_iter()
+            .map(|export| match scope.borrow().get(&export) {
+                Some(value) => Ok((export, value)),
+                None => Err(CompilerError::UnknownSymbol(export)),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        self.modules.defined.insert(name.clone(), bindings);
+        Ok(SExpression::Void)
+    }
+
+    // defines the exports of the module in env, the module is loaded from name.scm the first time
+    fn import(&mut self, name: &str, env: &EnvRef) -> Result<(), CompilerError> {
+        if !self.modules.defined.contains_key(name) {
+            if self.modules.importing.iter().any(|m| m == name) {
+                let mut cycle = self.modules.importing.clone();
+                cycle.push(name.to_string());
+                return Err(CompilerError::ImportCycle(cycle));
+            }
+            let path = self.modules.find(name).ok_or_else(|| CompilerError::UnknownModule(name.to_string()))?;
+            self.modules.importing.push(name.to_string());
+            let loaded = self.load(&path);
+            self.modules.importing.pop();
+            loaded?;
+        }
+        // the file was loaded but didn't define the module
+        let bindings = self.modules.defined.get(name).ok_or_else(|| CompilerError::UnknownModule(name.to_string()))?;
+        for (name, value) in bindings {
+            env.borrow_mut().define(name.clone(), value.clone());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use crate::{evaluator::Backend, lexer::lex, parser::parse};
+
+    use super::*;
+
+    // directory with the given files, removed and written again for every run
+    fn files(test: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = env::temp_dir().join(format!("lisp-{test}-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        for (name, content) in files {
+            let path = dir.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content).unwrap();
+        }
+        dir
+    }
+
+    fn eval_all(evaluator: &mut Evaluator, input: &str) -> Result<Vec<String>, CompilerError> {
+        parse(lex(input)).unwrap().into_iter().map(|e| evaluator.eval(e).map(|r| r.to_string())).collect()
+    }
+
+    fn run(backend: Backend, dir: Option<&Path>, input: &str) -> Result<Vec<String>, CompilerError> {
+        let mut evaluator = Evaluator::with_backend(backend);
+        evaluator.add_search_path(dir.unwrap_or(Path::new(".")));
+        eval_all(&mut evaluator, input)
+    }
+
+    #[test]
+    fn modules_hide_what_they_dont_export() {
+        for backend in [Backend::TreeWalker, Backend::Vm] {
+            let r = run(backend, None, "
+                (define base 10)
+                (module counter (export next! peek)
+                  (define count base)
+                  (define (next!) (set! count (+ count 1)) count)
+                  (define (peek) count))
+                (import counter)
+                (next!) (next!) (peek)");
+            assert_eq!(r, Ok(vec!["#<void>", "#<void>", "#<void>", "11", "12", "12"].into_iter().map(String::from).collect()));
+            assert_eq!(run(backend, None, "(module m (export) (define hidden 1)) (import m) hidden"), Err(CompilerError::UnknownSymbol(String::from("hidden"))));
+            assert_eq!(run(backend, None, "(module m (export missing))"), Err(CompilerError::UnknownSymbol(String::from("missing"))));
+            assert_eq!(run(backend, None, "(import no-such-module)"), Err(CompilerError::UnknownModule(String::from("no-such-module"))));
+            assert!(matches!(run(backend, None, "(module m (define x 1))"), Err(CompilerError::InvalidList(_))));
+            assert!(matches!(run(backend, None, "(import \"m\")"), Err(CompilerError::InvalidList(_))));
+        }
+    }
+
+    #[test]
+    fn modules_are_loaded_once() {
+        let dir = files("modules-loaded-once", &[
+            ("math.scm", "(set! loads (+ loads 1)) (module math (export square) (import helpers) (define (square x) (times x x)))"),
+            ("lib/helpers.scm", "(module helpers (export times) (define (times a b) (* a b)))"),
+            ("wrong.scm", "(module other (export))"),
+        ]);
+        for backend in [Backend::TreeWalker, Backend::Vm] {
+            let mut evaluator = Evaluator::with_backend(backend);
+            evaluator.add_search_path(&dir);
+            evaluator.add_search_path(dir.join("lib"));
+            let r = eval_all(&mut evaluator, "(define loads 0) (import math) (import math helpers) (list loads (square 3) (times 2 3))");
+            assert_eq!(r.unwrap().last().unwrap(), "(1 9 6)");
+            assert_eq!(eval_all(&mut evaluator, "(import wrong)"), Err(CompilerError::UnknownModule(String::from("wrong"))));
+        }
+    }
+
+    #[test]
+    fn import_cycles_are_detected() {
+        let dir = files("import-cycles", &[
+            ("a.scm", "(module a (export) (import b))"),
+            ("b.scm", "(module b (export) (import c))"),
+            ("c.scm", "(module c (export) (import a))"),
+            ("self.scm", "(load \"self.scm\")"),
+        ]);
+        for backend in [Backend::TreeWalker, Backend::Vm] {
+            let cycle = ["a", "b", "c", "a"].map(String::from).to_vec();
+            assert_eq!(run(backend, Some(&dir), "(import a)"), Err(CompilerError::ImportCycle(cycle)));
+            let path = fs::canonicalize(dir.join("self.scm")).unwrap().display().to_string();
+            let r = run(backend, None, &format!("(load {:?})", dir.join("self.scm").display().to_string()));
+            assert_eq!(r, Err(CompilerError::ImportCycle(vec![path.clone(), path])));
+        }
+    }
+
+    #[test]
+    fn load_files() {
+        let dir = files("load-files", &[
+            ("main.scm", "(define x 1) (load \"more/defs.scm\")"),
+            ("more/defs.scm", "(define y (+ x 1))"),
+            ("broken.scm", "(define z"),
+            ("partial.scm", "(define w 5)\n(define v"),
+        ]);
+        for backend in [Backend::TreeWalker, Backend::Vm] {
+            let r = run(backend, None, &format!("(load {:?}) (list x y)", dir.join("main.scm").display().to_string()));
+            assert_eq!(r.unwrap()[1], "(1 2)");
+            let r = run(backend, None, &format!("(load {:?})", dir.join("broken.scm").display().to_string()));
+            assert!(matches!(&r, Err(CompilerError::LoadFailed { reason, .. }) if reason == "1:1: unexpected end of input"), "{r:?}");
+            // expressions run as they are read, the ones before the error are defined
+            let mut evaluator = Evaluator::with_backend(backend);
+            let r = eval_all(&mut evaluator, &format!("(load {:?})", dir.join("partial.scm").display().to_string()));
+            assert!(matches!(&r, Err(CompilerError::LoadFailed { reason, .. }) if reason == "2:1: unexpected end of input"), "{r:?}");
+            assert_eq!(eval_all(&mut evaluator, "w"), Ok(vec!["5".to_string()]));
+            let r = run(backend, None, "(load \"/does/not/exist.scm\")");
+            assert!(matches!(r, Err(CompilerError::LoadFailed { path, .. }) if path == "/does/not/exist.scm"));
+        }
+    }
+
+    #[test]
+    fn files_are_not_read_without_io() {
+        let dir = files("without-io", &[("m.scm", "(module m (export))")]);
+        let mut evaluator = Evaluator::new();
+        evaluator.add_search_path(&dir);
+        evaluator.disable_io();
+        assert_eq!(eval_all(&mut evaluator, "(import m)"), Err(CompilerError::UnknownModule(String::from("m"))));
+        assert_eq!(eval_all(&mut evaluator, "(load \"m.scm\")"), Err(CompilerError::UnknownSymbol(String::from("load"))));
+        // modules defined in the program itself still work
+        assert!(eval_all(&mut evaluator, "(module n (export)) (import n)").is_ok());
+    }
+}
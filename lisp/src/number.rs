@@ -0,0 +1,393 @@
+use std::{cmp::Ordering, fmt, str::FromStr};
+
+use num::{BigInt, BigRational, FromPrimitive, Integer, One, Signed, ToPrimitive, Zero};
+
+// numeric tower: exact integers grow into bignums, exact division gives rationals, floats are inexact
+#[derive(Debug, PartialEq, Clone)]
+pub enum Number {
+    Int(i64),
+    // only for values that don't fit in i64
+    Big(BigInt),
+    // only for values with a denominator other than 1
+    Rational(BigRational),
+    Float(f64),
+}
+
+impl Number {
+    // integers, 1/3, 3.14, 1e-5, +inf.0, -inf.0 and +nan.0
+    pub fn parse(s: &str) -> Option<Number> {
+        match s {
+            "+inf.0" => return Some(Number::Float(f64::INFINITY)),
+            "-inf.0" => return Some(Number::Float(f64::NEG_INFINITY)),
+            "+nan.0" | "-nan.0" => return Some(Number::Float(f64::NAN)),
+            _ => {},
+        }
+        if let Ok(n) = s.parse::<i64>() {
+            return Some(Number::Int(n));
+        }
+
+        let digits = s.strip_prefix(['+', '-']).unwrap_or(s);
+        if !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit()) {
+            return BigInt::from_str(s).ok().map(Number::Big);
+        }
+        if let Some((n, d)) = s.split_once('/') {
+            if !d.chars().all(|c| c.is_ascii_digit()) {
+                return None;
+            }
+            let (n, d) = (BigInt::from_str(n).ok()?, BigInt::from_str(d).ok()?);
+            if d.is_zero() {
+                return None;
+            }
+            return Some(Number::from(BigRational::new(n, d)));
+        }
+        // rust also accepts words like inf or nan
+        if s.chars().any(|c| c.is_ascii_digit()) && s.chars().all(|c| c.is_ascii_digit() || "+-.eE".contains(c)) {
+            return s.parse::<f64>().ok().map(Number::Float);
+        }
+        None
+    }
+
+    pub fn is_exact(&self) -> bool {
+        !matches!(self, Number::Float(_))
+    }
+
+    pub fn is_integer(&self) -> bool {
+        match self {
+            Number::Int(_) | Number::Big(_) => true,
+            Number::Rational(_) => false,
+            Number::Float(f) => f.is_finite() && f.fract() == 0.0,
+        }
+    }
+
+    pub fn is_zero(&self) -> bool {
+        match self {
+            Number::Int(n) => *n == 0,
+            Number::Big(_) | Number::Rational(_) => false,
+            Number::Float(f) => *f == 0.0,
+        }
+    }
+
+    pub fn to_f64(&self) -> f64 {
+        match self {
+            Number::Int(n) => *n as f64,
+            Number::Big(n) => n.to_f64().unwrap_or(f64::NAN),
+            Number::Rational(r) => r.to_f64().unwrap_or(f64::NAN),
+            Number::Float(f) => *f,
+        }
+    }
+
+    pub fn to_inexact(&self) -> Number {
+        Number::Float(self.to_f64())
+    }
+
+    // infinities and nan have no exact value
+    pub fn to_exact(&self) -> Option<Number> {
+        match self {
+            Number::Float(f) => BigRational::from_float(*f).map(Number::from),
+            exact => Some(exact.clone()),
+        }
+    }
+
+    fn to_rational(&self) -> BigRational {
+        match self {
+            Number::Int(n) => BigRational::from_integer(BigInt::from(*n)),
+            Number::Big(n) => BigRational::from_integer(n.clone()),
+            Number::Rational(r) => r.clone(),
+            Number::Float(f) => BigRational::from_float(*f).unwrap_or_default(),
+        }
+    }
+
+    fn to_bigint(&self) -> Option<BigInt> {
+        match self {
+            Number::Int(n) => Some(BigInt::from(*n)),
+            Number::Big(n) => Some(n.clone()),
+            Number::Rational(_) => None,
+            Number::Float(f) if self.is_integer() => BigInt::from_f64(*f),
+            Number::Float(_) => None,
+        }
+    }
+
+    // applies the operation on the least general representation both numbers fit in
+    fn arithmetic(
+        &self,
+        other: &Number,
+        int: fn(i64, i64) -> Option<i64>,
+        ratio: fn(BigRational, BigRational) -> BigRational,
+        float: fn(f64, f64) -> f64,
+    ) -> Number {
+        match (self, other) {
+            (Number::Float(_), _) | (_, Number::Float(_)) => Number::Float(float(self.to_f64(), other.to_f64())),
+            (Number::Int(a), Number::Int(b)) => match int(*a, *b) {
+                Some(n) => Number::Int(n),
+                None => Number::from(ratio(self.to_rational(), other.to_rational())),
+            },
+            _ => Number::from(ratio(self.to_rational(), other.to_rational())),
+        }
+    }
+
+    pub fn add(&self, other: &Number) -> Number {
+        self.arithmetic(other, i64::checked_add, |a, b| a + b, |a, b| a + b)
+    }
+
+    pub fn sub(&self, other: &Number) -> Number {
+        self.arithmetic(other, i64::checked_sub, |a, b| a - b, |a, b| a - b)
+    }
+
+    pub fn mul(&self, other: &Number) -> Number {
+        self.arithmetic(other, i64::checked_mul, |a, b| a * b, |a, b| a * b)
+    }
+
+    // None when dividing by an exact zero, floats follow IEEE
+    pub fn div(&self, other: &Number) -> Option<Number> {
+        if other.is_exact() && other.is_zero() {
+            return None;
+        }
+        Some(self.arithmetic(
+            other,
+            |a, b| if a % b == 0 { a.checked_div(b) } else { None },
+            |a, b| a / b,
+            |a, b| a / b,
+        ))
+    }
+
+    pub fn neg(&self) -> Number {
+        Number::Int(0).sub(self)
+    }
+
+    pub fn abs(&self) -> Number {
+        match self {
+            Number::Float(f) => Number::Float(f.abs()),
+            n if n.partial_cmp(&Number::Int(0)) == Some(Ordering::Less) => n.neg(),
+            n => n.clone(),
+        }
+    }
+
+    // integer division operations, None if an argument is not an integer or the divisor is zero
+    fn integer_division(&self, other: &Number, op: fn(&BigInt, &BigInt) -> BigInt) -> Option<Number> {
+        let (a, b) = (self.to_bigint()?, other.to_bigint()?);
+        if b.is_zero() {
+            return None;
+        }
+        let result = Number::from(op(&a, &b));
+        if self.is_exact() && other.is_exact() {
+            Some(result)
+        } else {
+            Some(result.to_inexact())
+        }
+    }
+
+    // truncates towards zero
+    pub fn quotient(&self, other: &Number) -> Option<Number> {
+        self.integer_division(other, |a, b| a / b)
+    }
+
+    // takes the sign of the dividend
+    pub fn remainder(&self, other: &Number) -> Option<Number> {
+        self.integer_division(other, |a, b| a % b)
+    }
+
+    // takes the sign of the divisor
+    pub fn modulo(&self, other: &Number) -> Option<Number> {
+        self.integer_division(other, |a, b| a.mod_floor(b))
+    }
+
+    pub fn floor(&self) -> Number {
+        self.round_with(BigRational::floor, f64::floor)
+    }
+
+    pub fn ceiling(&self) -> Number {
+        self.round_with(BigRational::ceil, f64::ceil)
+    }
+
+    pub fn truncate(&self) -> Number {
+        self.round_with(BigRational::trunc, f64::trunc)
+    }
+
+    // halves go to the even neighbour
+    pub fn round(&self) -> Number {
+        self.round_with(
+            |r| {
+                let floor = r.floor();
+                let diff = r - &floor;
+                let half = BigRational::new(BigInt::one(), BigInt::from(2));
+                if diff > half || (diff == half && floor.to_integer().is_odd()) {
+                    floor + BigRational::one()
+                } else {
+                    floor
+                }
+            },
+            f64::round_ties_even,
+        )
+    }
+
+    fn round_with(&self, exact: fn(&BigRational) -> BigRational, float: fn(f64) -> f64) -> Number {
+        match self {
+            Number::Rational(r) => Number::from(exact(r)),
+            Number::Float(f) => Number::Float(float(*f)),
+            integer => integer.clone(),
+        }
+    }
+
+    pub fn numerator(&self) -> Number {
+        match self {
+            Number::Rational(r) => Number::from(r.numer().clone()),
+            Number::Float(_) => self.to_exact().map(|n| n.numerator().to_inexact()).unwrap_or(self.clone()),
+            integer => integer.clone(),
+        }
+    }
+
+    pub fn denominator(&self) -> Number {
+        match self {
+            Number::Rational(r) => Number::from(r.denom().clone()),
+            Number::Float(_) => self.to_exact().map(|n| n.denominator().to_inexact()).unwrap_or(self.clone()),
+            _ => Number::Int(1),
+        }
+    }
+
+    // exact for exact squares
+    pub fn sqrt(&self) -> Number {
+        let exact = match self {
+            Number::Int(_) | Number::Big(_) => self.to_bigint().filter(|n| !n.is_negative()).map(|n| (n.sqrt(), n)),
+            _ => None,
+        };
+        match exact {
+            Some((root, n)) if &root * &root == n => Number::from(root),
+            _ => Number::Float(self.to_f64().sqrt()),
+        }
+    }
+
+    // bits of an exact number, numerator and denominator together, 0 for floats
+    pub(crate) fn bits(&self) -> u64 {
+        match self {
+            Number::Int(n) => u64::from(64 - n.unsigned_abs().leading_zeros()),
+            Number::Big(n) => n.bits(),
+            Number::Rational(r) => r.numer().bits() + r.denom().bits(),
+            Number::Float(_) => 0,
+        }
+    }
+
+    // exact when the base is exact and the exponent an exact integer
+    pub fn expt(&self, exponent: &Number) -> Option<Number> {
+        match exponent {
+            Number::Int(e) if self.is_exact() => {
+                let base = self.to_rational();
+                if base.is_zero() && *e < 0 {
+                    return None;
+                }
+                let power = usize::try_from(e.unsigned_abs()).ok()?;
+                let result = num::pow(base, power);
+                Some(Number::from(if *e < 0 { result.recip() } else { result }))
+            },
+            _ => Some(Number::Float(self.to_f64().powf(exponent.to_f64()))),
+        }
+    }
+}
+
+impl From<i64> for Number {
+    fn from(n: i64) -> Self {
+        Number::Int(n)
+    }
+}
+
+impl From<BigInt> for Number {
+    fn from(n: BigInt) -> Self {
+        match n.to_i64() {
+            Some(n) => Number::Int(n),
+            None => Number::Big(n),
+        }
+    }
+}
+
+impl From<BigRational> for Number {
+    fn from(r: BigRational) -> Self {
+        if r.is_integer() {
+            Number::from(r.to_integer())
+        } else {
+            Number::Rational(r)
+        }
+    }
+}
+
+// numeric order, exact and inexact numbers compare by value. Nan is not comparable
+impl PartialOrd for Number {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        match (self, other) {
+            (Number::Int(a), Number::Int(b)) => a.partial_cmp(b),
+            (Number::Float(_), _) | (_, Number::Float(_)) => self.to_f64().partial_cmp(&other.to_f64()),
+            _ => self.to_rational().partial_cmp(&other.to_rational()),
+        }
+    }
+}
+
+impl fmt::Display for Number {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Number::Int(n) => write!(f, "{n}"),
+            Number::Big(n) => write!(f, "{n}"),
+            Number::Rational(r) => write!(f, "{}/{}", r.numer(), r.denom()),
+            Number::Float(x) if x.is_nan() => write!(f, "+nan.0"),
+            Number::Float(x) if x.is_infinite() => write!(f, "{}inf.0", if *x > 0.0 { "+" } else { "-" }),
+            // debug keeps the .0 of whole numbers and switches to exponents for very small and big ones
+            Number::Float(x) => write!(f, "{x:?}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn n(s: &str) -> Number {
+        Number::parse(s).unwrap()
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(n("42"), Number::Int(42));
+        assert_eq!(n("-7"), Number::Int(-7));
+        assert_eq!(n("2.75"), Number::Float(2.75));
+        assert_eq!(n("1e-5"), Number::Float(1e-5));
+        assert_eq!(n(".5"), Number::Float(0.5));
+        assert_eq!(n("4/6").to_string(), "2/3");
+        assert_eq!(n("4/2"), Number::Int(2));
+        assert_eq!(n("99999999999999999999").to_string(), "99999999999999999999");
+        for s in ["+", "-", "...", "inf", "nan", "1/0", "1/-2", "e5", "1x", "."] {
+            assert_eq!(Number::parse(s), None, "{s}");
+        }
+    }
+
+    #[test]
+    fn overflow_promotes_to_bignum_and_back() {
+        let max = Number::Int(i64::MAX);
+        let big = max.add(&Number::Int(1));
+        assert_eq!(big.to_string(), "9223372036854775808");
+        assert_eq!(big.sub(&Number::Int(1)), max);
+        assert_eq!(max.mul(&max).to_string(), "85070591730234615847396907784232501249");
+    }
+
+    #[test]
+    fn exact_division() {
+        assert_eq!(Number::Int(1).div(&Number::Int(3)).unwrap().to_string(), "1/3");
+        assert_eq!(Number::Int(6).div(&Number::Int(3)), Some(Number::Int(2)));
+        assert_eq!(Number::Int(1).div(&Number::Int(0)), None);
+        assert_eq!(Number::Int(1).div(&Number::Float(0.0)), Some(Number::Float(f64::INFINITY)));
+        assert_eq!(n("1/3").add(&n("2/3")), Number::Int(1));
+    }
+
+    #[test]
+    fn display_floats() {
+        assert_eq!(Number::Float(1.0).to_string(), "1.0");
+        assert_eq!(Number::Float(0.1).to_string(), "0.1");
+        assert_eq!(Number::Float(1e-10).to_string(), "1e-10");
+        assert_eq!(Number::Float(-f64::INFINITY).to_string(), "-inf.0");
+    }
+
+    #[test]
+    fn rounding() {
+        assert_eq!(n("7/2").round(), Number::Int(4));
+        assert_eq!(n("5/2").round(), Number::Int(2));
+        assert_eq!(n("-7/2").floor(), Number::Int(-4));
+        assert_eq!(n("-7/2").truncate(), Number::Int(-3));
+        assert_eq!(n("2.5").round(), Number::Float(2.0));
+    }
+}
@@ -0,0 +1,167 @@
+use std::{fmt, rc::Rc};
+
+use crate::parser::SExpression;
+
+// cell made by cons. Lists built by the program are chains of pairs ending in the empty list, they are
+// shared, so cons, car and cdr don't copy. Lists read from the source stay SExpression::List until quoted
+pub struct Pair {
+    pub car: SExpression,
+    pub cdr: SExpression,
+}
+
+// the chain is taken apart one cell at a time, dropping a long list would overflow the stack otherwise
+impl Drop for Pair {
+    fn drop(&mut self) {
+        let mut next = std::mem::replace(&mut self.cdr, SExpression::Void);
+        while let SExpression::Pair(rc) = next {
+            match Rc::try_unwrap(rc) {
+                Ok(mut pair) => next = std::mem::replace(&mut pair.cdr, SExpression::Void),
+                Err(_) => break,
+            }
+        }
+    }
+}
+
+// the elements in a row, with the tail after a dot if the list is improper
+impl fmt::Debug for Pair {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut rest = iter(&self.cdr);
+        let mut list = f.debug_list();
+        list.entry(&self.car).entries(&mut rest);
+        if let Some(tail) = rest.tail() {
+            list.entry(&format_args!(". {tail:?}"));
+        }
+        list.finish()
+    }
+}
+
+pub fn cons(car: SExpression, cdr: SExpression) -> SExpression {
+    SExpression::Pair(Rc::new(Pair { car, cdr }))
+}
+
+// proper list of the elements
+pub fn list<I: IntoIterator<Item = SExpression>>(elements: I) -> SExpression
+where
+    I::IntoIter: DoubleEndedIterator,
+{
+    elements.into_iter().rev().fold(SExpression::List(vec![]), |tail, e| cons(e, tail))
+}
+
+// pairs, non-empty lists and dotted lists. The empty list is not a pair
+pub fn is_pair(e: &SExpression) -> bool {
+    match e {
+        SExpression::List(v) => !v.is_empty(),
+        SExpression::DottedList(..) | SExpression::Pair(_) => true,
+        _ => false,
+    }
+}
+
+// value of a quoted datum, the lists in it become pairs. Vectors are kept, they are already values
+pub fn datum(e: &SExpression) -> SExpression {
+    match e {
+        SExpression::List(v) => v.iter().rev().fold(SExpression::List(vec![]), |tail, e| cons(datum(e), tail)),
+        SExpression::DottedList(v, tail) => v.iter().rev().fold(datum(tail), |tail, e| cons(datum(e), tail)),
+        _ => e.clone(),
+    }
+}
+
+// first element and the rest of a pair however it's stored
+pub fn split(e: &SExpression) -> Option<(SExpression, SExpression)> {
+    match e {
+        SExpression::Pair(p) => Some((p.car.clone(), p.cdr.clone())),
+        SExpression::List(v) if !v.is_empty() => Some((v[0].clone(), datum(&SExpression::List(v[1..].to_vec())))),
+        SExpression::DottedList(v, tail) if v.len() == 1 => Some((v[0].clone(), datum(tail))),
+        SExpression::DottedList(v, tail) => Some((v[0].clone(), datum(&SExpression::DottedList(v[1..].to_vec(), tail.clone())))),
+        _ => None,
+    }
+}
+
+// elements of a proper list, None for anything else
+pub fn elements(e: &SExpression) -> Option<Vec<SExpression>> {
+    let mut items = iter(e);
+    let v = items.by_ref().cloned().collect();
+    match (e, items.tail()) {
+        (SExpression::List(_) | SExpression::DottedList(..) | SExpression::Pair(_), None) => Some(v),
+        _ => None,
+    }
+}
+
+// the same list as a List or DottedList, for code that looks at the elements side by side
+pub fn flatten(e: &SExpression) -> SExpression {
+    let mut items = iter(e);
+    let v = items.by_ref().cloned().collect();
+    match items.tail() {
+        None => SExpression::List(v),
+        Some(tail) => SExpression::DottedList(v, Box::new(tail.clone())),
+    }
+}
+
+// lists with the same elements and tail are equal, whether they were read or built from pairs
+pub fn equal(a: &SExpression, b: &SExpression, same: fn(&SExpression, &SExpression) -> bool) -> bool {
+    let (mut a, mut b) = (iter(a), iter(b));
+    loop {
+        match (a.next(), b.next()) {
+            (Some(x), Some(y)) if same(x, y) => {},
+            (None, None) => {
+                return match (a.tail(), b.tail()) {
+                    (None, None) => true,
+                    (Some(x), Some(y)) => same(x, y),
+                    _ => false,
+                }
+            },
+            _ => return false,
+        }
+    }
+}
+
+pub fn iter(e: &SExpression) -> Iter<'_> {
+    Iter { rest: e, index: 0 }
+}
+
+// elements of a list however it's stored. After the last one, tail is what ended the list
+pub struct Iter<'a> {
+    rest: &'a SExpression,
+    // position in rest if it's a List or DottedList
+    index: usize,
+}
+
+impl<'a> Iter<'a> {
+    // None for the empty list, the value after the dot otherwise
+    pub fn tail(&self) -> Option<&'a SExpression> {
+        match self.rest {
+            SExpression::List(_) => None,
+            other => Some(other),
+        }
+    }
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = &'a SExpression;
+
+    fn next(&mut self) -> Option<&'a SExpression> {
+        loop {
+            match self.rest {
+                SExpression::Pair(p) => {
+                    self.rest = &p.cdr;
+                    return Some(&p.car);
+                },
+                SExpression::List(v) => {
+                    let e = v.get(self.index);
+                    if e.is_some() {
+                        self.index += 1;
+                    }
+                    return e;
+                },
+                SExpression::DottedList(v, _) if self.index < v.len() => {
+                    self.index += 1;
+                    return Some(&v[self.index - 1]);
+                },
+                SExpression::DottedList(_, tail) => {
+                    self.rest = tail;
+                    self.index = 0;
+                },
+                _ => return None,
+            }
+        }
+    }
+}
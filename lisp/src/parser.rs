@@ -1,89 +1,365 @@
-use std::{iter::Peekable};
+use std::{cell::RefCell, fmt, iter::Peekable, rc::Rc};
 
-use crate::lexer::{Token, self};
+use crate::{lexer::{Token, self, Span, Position}, evaluator::Lambda, builtins::{Arity, Builtin}, condition::Condition, diagnostics::Diagnostic, limits::Limit, number::Number, pair::{self, Pair}, printer, table::HashTable};
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, Clone)]
 pub enum SExpression {
     Void,
-    Number(i32),
+    Number(Number),
     Boolean(bool),
-    String(String),
+    // strings can't be changed, copies share the characters
+    String(Rc<str>),
+    Char(char),
     Identifier(String),
-    List(Vec<SExpression>)
+    List(Vec<SExpression>),
+    // chain of pairs not terminated by an empty list: (1 2 . 3) is DottedList([1, 2], 3)
+    DottedList(Vec<SExpression>, Box<SExpression>),
+    // list cell made at run time, see pair::Pair
+    Pair(Rc<Pair>),
+    // vectors and hash tables are shared, changes are seen through every reference
+    Vector(Rc<RefCell<Vec<SExpression>>>),
+    HashTable(Rc<RefCell<HashTable>>),
+    Lambda(Lambda),
+    Builtin(Builtin),
+    Condition(Rc<Condition>),
+}
+
+// lists are compared by their elements, a list read from the source equals the same list built from pairs
+impl PartialEq for SExpression {
+    fn eq(&self, other: &Self) -> bool {
+        use SExpression::*;
+        match (self, other) {
+            (Void, Void) => true,
+            (Number(a), Number(b)) => a == b,
+            (Boolean(a), Boolean(b)) => a == b,
+            (String(a), String(b)) => a == b,
+            (Identifier(a), Identifier(b)) => a == b,
+            (Char(a), Char(b)) => a == b,
+            (List(_) | DottedList(..) | Pair(_), List(_) | DottedList(..) | Pair(_)) => pair::equal(self, other, PartialEq::eq),
+            (Vector(a), Vector(b)) => a == b,
+            (HashTable(a), HashTable(b)) => a == b,
+            (Lambda(a), Lambda(b)) => a == b,
+            (Builtin(a), Builtin(b)) => a == b,
+            (Condition(a), Condition(b)) => a == b,
+            _ => false,
+        }
+    }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum CompilerError {
-    InvalidToken(lexer::Token),
-    IncompleteExpression(lexer::Token),
+    // tokens are boxed, errors are part of every result in the evaluator and should stay small
+    InvalidToken(Box<lexer::Token>),
+    // ) without a matching (
+    UnmatchedClosing(Box<lexer::Token>),
     UnexpectedEof,
     UnknownSymbol(String),
     InvalidList(SExpression),
+    DivisionByZero,
+    // the code ran into one of the limits set for the evaluator
+    LimitExceeded(Limit),
+    // value raised by the program and not caught
+    Raised(SExpression),
+    LoadFailed { path: String, reason: String },
+    UnknownModule(String),
+    // modules or files that load each other, the first one is repeated at the end
+    ImportCycle(Vec<String>),
+    ArityMismatch { name: String, expected: Arity, got: usize },
+}
+
+// prints values the way they are written in the source, see printer::write
+impl fmt::Display for SExpression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&printer::write(self))
+    }
+}
+
+impl fmt::Display for CompilerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CompilerError::InvalidToken(t) => match &**t {
+                Token::Invalid { reason, .. } => write!(f, "{reason}: {t}"),
+                _ => write!(f, "unexpected {t}"),
+            },
+            CompilerError::UnmatchedClosing(t) => write!(f, "unmatched {t}"),
+            CompilerError::UnexpectedEof => write!(f, "unexpected end of input"),
+            CompilerError::UnknownSymbol(s) => write!(f, "unknown symbol: {s}"),
+            CompilerError::InvalidList(e) => write!(f, "invalid expression: {e}"),
+            CompilerError::DivisionByZero => write!(f, "division by zero"),
+            CompilerError::LimitExceeded(limit) => write!(f, "{limit} limit exceeded"),
+            CompilerError::Raised(SExpression::Condition(c)) => write!(f, "{c}"),
+            CompilerError::Raised(value) => write!(f, "uncaught exception: {value}"),
+            CompilerError::LoadFailed { path, reason } => write!(f, "cannot load {path}: {reason}"),
+            CompilerError::UnknownModule(name) => write!(f, "unknown module: {name}"),
+            CompilerError::ImportCycle(cycle) => write!(f, "import cycle: {}", cycle.join(" -> ")),
+            CompilerError::ArityMismatch { name, expected, got } => write!(f, "{name}: expected {expected} arguments, got {got}"),
+        }
+    }
+}
+
+impl std::error::Error for CompilerError {}
+
+// spans of an expression and, for lists, of every element
+#[derive(Debug, PartialEq, Clone)]
+pub struct SpanTree {
+    pub span: Span,
+    pub children: Vec<SpanTree>,
 }
 
-struct Parser<T: Iterator<Item = Token>> {
-    errors: Vec<CompilerError>,
-    expressions: Vec<SExpression>,
+impl SpanTree {
+    fn leaf(span: Span) -> Self {
+        Self { span, children: vec![] }
+    }
+
+    // span of the first subexpression of `expr` (described by this tree) equal to `target`
+    pub fn find(&self, expr: &SExpression, target: &SExpression) -> Option<Span> {
+        self.find_tree(expr, target).map(|t| t.span)
+    }
+
+    pub fn find_tree(&self, expr: &SExpression, target: &SExpression) -> Option<&SpanTree> {
+        if expr == target {
+            return Some(self);
+        }
+        match expr {
+            SExpression::List(v) => v.iter()
+                .zip(&self.children)
+                .find_map(|(e, tree)| tree.find_tree(e, target)),
+            SExpression::DottedList(v, tail) => v.iter()
+                .chain(std::iter::once(tail.as_ref()))
+                .zip(&self.children)
+                .find_map(|(e, tree)| tree.find_tree(e, target)),
+            _ => None,
+        }
+    }
+}
+
+// top level expression together with its location in the source
+#[derive(Debug, PartialEq, Clone)]
+pub struct Form {
+    pub expr: SExpression,
+    pub spans: SpanTree,
+}
+
+struct Parser<T: Iterator<Item = (Token, Span)>> {
+    // errors of the form being parsed that don't stop it, like invalid tokens in a list
+    errors: Vec<Diagnostic>,
     tokens: Peekable<T>,
+    // end of the last consumed token, reported for unexpected end of input
+    last: Span,
+    // lists opened and not closed yet, used to skip the rest of a broken expression
+    depth: usize,
 }
 
-impl<T: Iterator<Item = Token>> Parser<T> {
+type Parsed = Result<(SExpression, SpanTree), Diagnostic>;
+
+impl<T: Iterator<Item = (Token, Span)>> Parser<T> {
     fn new(tokens: T) -> Self {
+        let start = Position { line: 1, col: 1 };
         Parser { errors: vec![],
-             expressions: vec![],
              tokens: tokens.peekable(),
+             last: Span { start, end: start },
+             depth: 0,
         }
     }
 
-    fn parse(mut self) -> Result<Vec<SExpression>, Vec<CompilerError>> {
-        while let Some(tok) = self.tokens.next() {
-            match tok {
-                Token::Opening { line } => {
-                    match self.parse_exp() {
-                        Ok(v) => self.expressions.push(SExpression::List(v)),
-                        Err(e) => self.errors.push(e),
-                    }
-                }
-                v => self.errors.push(CompilerError::InvalidToken(v)),
+    fn parse(mut self) -> Result<Vec<Form>, Vec<Diagnostic>> {
+        let mut forms = vec![];
+        let mut errors = vec![];
+        while let Some(parsed) = self.next_form() {
+            match parsed {
+                Ok(form) => forms.push(form),
+                Err(e) => errors.extend(e),
             }
         }
 
-        if !self.errors.is_empty() {
-            Err(self.errors)
+        if !errors.is_empty() {
+            Err(errors)
         } else {
-            Ok(self.expressions)
+            Ok(forms)
+        }
+    }
+
+    // the next top level expression, or every error in it
+    fn next_form(&mut self) -> Option<Result<Form, Vec<Diagnostic>>> {
+        let (tok, span) = self.next()?;
+        let parsed = match tok {
+            Token::Closing { .. } => Err(error(CompilerError::UnmatchedClosing(Box::new(tok)), span)),
+            _ => self.parse_datum(tok, span),
+        };
+        match parsed {
+            Ok((expr, spans)) if self.errors.is_empty() => Some(Ok(Form { expr, spans })),
+            Ok(_) => Some(Err(std::mem::take(&mut self.errors))),
+            Err(e) => {
+                self.errors.push(e);
+                self.recover();
+                Some(Err(std::mem::take(&mut self.errors)))
+            },
         }
     }
 
-    fn parse_exp(&mut self) -> Result<Vec<SExpression>, CompilerError> {
+    fn next(&mut self) -> Option<(Token, Span)> {
+        let next = self.tokens.next();
+        if let Some((_, span)) = &next {
+            self.last = *span;
+        }
+        next
+    }
+
+    fn eof(&self) -> Diagnostic {
+        error(CompilerError::UnexpectedEof, Span { start: self.last.end, end: self.last.end })
+    }
+
+    // skips to the end of the top level expression that failed, so the next one is parsed from its start
+    fn recover(&mut self) {
+        while self.depth > 0 {
+            match self.next() {
+                Some((Token::Opening { .. } | Token::VectorOpening { .. }, _)) => self.depth += 1,
+                Some((Token::Closing { .. }, _)) => self.depth -= 1,
+                Some(_) => {},
+                None => break,
+            }
+        }
+        self.depth = 0;
+    }
+
+    fn parse_datum(&mut self, tok: Token, span: Span) -> Parsed {
+        match tok {
+            Token::Closing { .. } => {
+                // the ) still closes the enclosing list
+                self.depth = self.depth.saturating_sub(1);
+                Err(error(CompilerError::InvalidToken(Box::new(tok)), span))
+            },
+            Token::Invalid { .. } => Err(error(CompilerError::InvalidToken(Box::new(tok)), span)),
+            Token::Identifier { v, .. } => Ok((SExpression::Identifier(v), SpanTree::leaf(span))),
+            Token::Literal { v, .. } => match v {
+                lexer::Literal::Number(n) => Ok((SExpression::Number(n), SpanTree::leaf(span))),
+                lexer::Literal::String(s) => Ok((SExpression::String(s.into()), SpanTree::leaf(span))),
+                lexer::Literal::Boolean(b) => Ok((SExpression::Boolean(b), SpanTree::leaf(span))),
+                lexer::Literal::Char(c) => Ok((SExpression::Char(c), SpanTree::leaf(span))),
+            },
+            // #(1 2 3) - the elements are not evaluated, like a quoted list, and lists in them are made of pairs
+            Token::VectorOpening { .. } => {
+                self.depth += 1;
+                let (list, spans) = self.parse_exp(span)?;
+                self.depth -= 1;
+                match list {
+                    SExpression::List(v) => Ok((SExpression::Vector(Rc::new(RefCell::new(v.iter().map(pair::datum).collect()))), spans)),
+                    dotted => Err(error(CompilerError::InvalidList(dotted), spans.span)),
+                }
+            },
+            Token::Opening { .. } => {
+                self.depth += 1;
+                let parsed = self.parse_exp(span)?;
+                self.depth -= 1;
+                Ok(parsed)
+            },
+            // 'x is a shorthand for (quote x)
+            Token::Quote { .. } => match self.next() {
+                Some((next, next_span)) => {
+                    let (datum, tree) = self.parse_datum(next, next_span)?;
+                    Ok((
+                        SExpression::List(vec![SExpression::Identifier("quote".to_string()), datum]),
+                        SpanTree { span: span.join(&tree.span), children: vec![SpanTree::leaf(span), tree] },
+                    ))
+                },
+                None => Err(self.eof()),
+            },
+        }
+    }
+
+    fn parse_exp(&mut self, opening: Span) -> Parsed {
         let mut elems = vec![];
-        while let Some(next) = self.tokens.next() {
+        let mut children = vec![];
+        while let Some((next, span)) = self.next() {
             match next {
-                Token::Closing { line } => break,
-                Token::Invalid { line, ref v } => return Err(CompilerError::InvalidToken(next)), // string is ref type, so I couldn't just move here. ref is required to not partially move `next`` to `v`
-                Token::Identifier { line, v } => elems.push(SExpression::Identifier(v)),
-                Token::Literal { line, v } => match v {
-                    lexer::Literal::Number(n) => elems.push(SExpression::Number(n)),
-                    lexer::Literal::String(s) => elems.push(SExpression::String(s)),
-                    lexer::Literal::Boolean(b) => elems.push(SExpression::Boolean(b)),
+                Token::Closing { .. } => return Ok((SExpression::List(elems), SpanTree { span: opening.join(&span), children })),
+                Token::Identifier { ref v, .. } if v == "." && !elems.is_empty() => return self.parse_dotted_tail(elems, children, opening),
+                // an invalid token doesn't break the structure, keep going to find more errors
+                Token::Invalid { .. } => self.errors.push(error(CompilerError::InvalidToken(Box::new(next)), span)),
+                _ => {
+                    let (e, tree) = self.parse_datum(next, span)?;
+                    elems.push(e);
+                    children.push(tree);
                 },
-                Token::Opening { line } => elems.push(SExpression::List(self.parse_exp()?)),
             }
         }
-        Ok(elems)
+        // the list is never closed, point at where it starts
+        Err(error(CompilerError::UnexpectedEof, opening))
     }
 
+    // (a b . tail) - exactly one datum and a closing paren are expected after the dot
+    fn parse_dotted_tail(&mut self, mut elems: Vec<SExpression>, mut children: Vec<SpanTree>, opening: Span) -> Parsed {
+        let (tail, tail_tree) = match self.next() {
+            Some((tok, span)) => self.parse_datum(tok, span)?,
+            None => return Err(error(CompilerError::UnexpectedEof, opening)),
+        };
+        let span = match self.next() {
+            Some((Token::Closing { .. }, span)) => opening.join(&span),
+            Some((tok, span)) => {
+                if let Token::Opening { .. } | Token::VectorOpening { .. } = tok {
+                    self.depth += 1;
+                }
+                return Err(error(CompilerError::InvalidToken(Box::new(tok)), span));
+            },
+            None => return Err(error(CompilerError::UnexpectedEof, opening)),
+        };
+
+        match tail {
+            SExpression::List(rest) => {
+                elems.extend(rest);
+                children.extend(tail_tree.children);
+                Ok((SExpression::List(elems), SpanTree { span, children }))
+            },
+            SExpression::DottedList(rest, tail) => {
+                elems.extend(rest);
+                children.extend(tail_tree.children);
+                Ok((SExpression::DottedList(elems, tail), SpanTree { span, children }))
+            },
+            tail => {
+                children.push(tail_tree);
+                Ok((SExpression::DottedList(elems, Box::new(tail)), SpanTree { span, children }))
+            },
+        }
+    }
+}
+
+fn error(error: CompilerError, span: Span) -> Diagnostic {
+    Diagnostic { error, span: Some(span) }
 }
 
 pub fn parse(tokens: Vec<Token>) -> Result<Vec<SExpression>, Vec<CompilerError>> {
-    let mut p = Parser::new(tokens.into_iter());
-    p.parse()
+    // without the source only where tokens start is known
+    let tokens = tokens.into_iter().map(|t| {
+        let position = t.position();
+        (t, Span { start: position, end: position })
+    });
+
+    match Parser::new(tokens).parse() {
+        Ok(forms) => Ok(forms.into_iter().map(|f| f.expr).collect()),
+        Err(diagnostics) => Err(diagnostics.into_iter().map(|d| d.error).collect()),
+    }
+}
+
+// takes a Vec or a Lexer, tokens are read as they are parsed
+pub fn parse_with_spans(tokens: impl IntoIterator<Item = (Token, Span)>) -> Result<Vec<Form>, Vec<Diagnostic>> {
+    Parser::new(tokens.into_iter()).parse()
 }
 
-pub fn eval(ast: Vec<SExpression>) {
-    todo!()
+// top level expressions of a stream of tokens, each one is parsed when it's taken. Tokens are read only as far
+// as the expression goes, so a program can be evaluated while the rest of it is still being read
+pub struct Forms<T: Iterator<Item = (Token, Span)>>(Parser<T>);
+
+impl<T: Iterator<Item = (Token, Span)>> Iterator for Forms<T> {
+    type Item = Result<Form, Vec<Diagnostic>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next_form()
+    }
 }
 
+pub fn forms<T: IntoIterator<Item = (Token, Span)>>(tokens: T) -> Forms<T::IntoIter> {
+    Forms(Parser::new(tokens.into_iter()))
+}
 
 #[cfg(test)]
 mod tests {
@@ -105,8 +381,8 @@ mod tests {
         assert_eq!(ast, vec![
             SExpression::List(vec![
                 SExpression::Identifier(s("+")),
-                SExpression::Number(1),
-                SExpression::Number(2),
+                SExpression::Number(Number::Int(1)),
+                SExpression::Number(Number::Int(2)),
             ])
         ]);
     }
@@ -119,13 +395,13 @@ mod tests {
         assert_eq!(ast, vec![
             SExpression::List(vec![
                 SExpression::Identifier(s("+")),
-                SExpression::Number(1),
-                SExpression::Number(2),
+                SExpression::Number(Number::Int(1)),
+                SExpression::Number(Number::Int(2)),
             ]),
             SExpression::List(vec![
                 SExpression::Identifier(s("-")),
-                SExpression::Number(3),
-                SExpression::Number(4),
+                SExpression::Number(Number::Int(3)),
+                SExpression::Number(Number::Int(4)),
             ])
         ]);
     }
@@ -137,13 +413,195 @@ mod tests {
         assert_eq!(ast, vec![
             SExpression::List(vec![
             SExpression::Identifier(s("+")),
-                SExpression::Number(1),
+                SExpression::Number(Number::Int(1)),
                 SExpression::List(vec![
                     SExpression::Identifier(s("*")),
-                    SExpression::Number(2),
-                    SExpression::Number(4),
+                    SExpression::Number(Number::Int(2)),
+                    SExpression::Number(Number::Int(4)),
                 ]),
             ])
         ]);   
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn quote_shorthand() {
+        let input = "(car '(1 2)) 'x";
+        let ast = compile(input).unwrap();
+        assert_eq!(ast, vec![
+            SExpression::List(vec![
+                SExpression::Identifier(s("car")),
+                SExpression::List(vec![
+                    SExpression::Identifier(s("quote")),
+                    SExpression::List(vec![SExpression::Number(Number::Int(1)), SExpression::Number(Number::Int(2))]),
+                ]),
+            ]),
+            SExpression::List(vec![
+                SExpression::Identifier(s("quote")),
+                SExpression::Identifier(s("x")),
+            ]),
+        ]);
+    }
+
+    #[test]
+    fn nested_quote() {
+        let ast = compile("''a").unwrap();
+        assert_eq!(ast, vec![
+            SExpression::List(vec![
+                SExpression::Identifier(s("quote")),
+                SExpression::List(vec![
+                    SExpression::Identifier(s("quote")),
+                    SExpression::Identifier(s("a")),
+                ]),
+            ])
+        ]);
+    }
+
+    #[test]
+    fn quote_without_datum() {
+        assert_eq!(compile("(list ')"), Err(vec![CompilerError::InvalidToken(Box::new(Token::Closing { line: 1, col: 8 }))]));
+        assert_eq!(compile("'"), Err(vec![CompilerError::UnexpectedEof]));
+    }
+
+    #[test]
+    fn dotted_list() {
+        let ast = compile("'(1 2 . 3) '(1 . (2 3)) '(1 . (2 . 3))").unwrap();
+        let quote = |e| SExpression::List(vec![SExpression::Identifier(s("quote")), e]);
+        assert_eq!(ast, vec![
+            quote(SExpression::DottedList(vec![SExpression::Number(Number::Int(1)), SExpression::Number(Number::Int(2))], Box::new(SExpression::Number(Number::Int(3))))),
+            quote(SExpression::List(vec![SExpression::Number(Number::Int(1)), SExpression::Number(Number::Int(2)), SExpression::Number(Number::Int(3))])),
+            quote(SExpression::DottedList(vec![SExpression::Number(Number::Int(1)), SExpression::Number(Number::Int(2))], Box::new(SExpression::Number(Number::Int(3))))),
+        ]);
+    }
+
+    #[test]
+    fn invalid_dotted_list() {
+        let errors = compile("'(1 . 2 3)").unwrap_err();
+        assert_eq!(errors, vec![CompilerError::InvalidToken(Box::new(Token::Literal { line: 1, col: 9, v: lexer::Literal::Number(Number::Int(3)) }))]);
+    }
+
+    #[test]
+    fn vector_and_char_literals() {
+        let ast = compile("#(1 #\\a (b)) #() #\\space").unwrap();
+        let vector = |v| SExpression::Vector(Rc::new(RefCell::new(v)));
+        assert_eq!(ast, vec![
+            vector(vec![
+                SExpression::Number(Number::Int(1)),
+                SExpression::Char('a'),
+                SExpression::List(vec![SExpression::Identifier(s("b"))]),
+            ]),
+            vector(vec![]),
+            SExpression::Char(' '),
+        ]);
+        assert_eq!(ast[0].to_string(), "#(1 #\\a (b))");
+        assert!(matches!(compile("#(1 . 2) 3").unwrap_err()[..], [CompilerError::InvalidList(_)]));
+        assert_eq!(compile("#(1 2"), Err(vec![CompilerError::UnexpectedEof]));
+    }
+
+    #[test]
+    fn display() {
+        let ast = compile("(define (f x) '(1 \"two\" (true . x)))").unwrap();
+        assert_eq!(ast[0].to_string(), "(define (f x) (quote (1 \"two\" (true . x))))");
+    }
+
+    #[test]
+    fn spans_of_nested_list() {
+        let forms = parse_with_spans(lexer::lex_with_spans("(+ 1\n  (f 'x))")).unwrap();
+        let span = |line, col, end_line, end_col| Span {
+            start: Position { line, col },
+            end: Position { line: end_line, col: end_col },
+        };
+        let leaf = |line, col, end_col| SpanTree::leaf(span(line, col, line, end_col));
+
+        assert_eq!(forms[0].spans, SpanTree {
+            span: span(1, 1, 2, 10),
+            children: vec![
+                leaf(1, 2, 3),
+                leaf(1, 4, 5),
+                SpanTree {
+                    span: span(2, 3, 2, 9),
+                    children: vec![
+                        leaf(2, 4, 5),
+                        SpanTree { span: span(2, 6, 2, 8), children: vec![leaf(2, 6, 7), leaf(2, 7, 8)] },
+                    ],
+                },
+            ],
+        });
+    }
+
+    #[test]
+    fn find_span_of_subexpression() {
+        let forms = parse_with_spans(lexer::lex_with_spans("(define (f x)\n  (car x))")).unwrap();
+        let target = SExpression::List(vec![SExpression::Identifier(s("car")), SExpression::Identifier(s("x"))]);
+        let found = forms[0].spans.find(&forms[0].expr, &target).unwrap();
+        assert_eq!(found, Span { start: Position { line: 2, col: 3 }, end: Position { line: 2, col: 10 } });
+    }
+
+    #[test]
+    fn error_spans() {
+        let errors = parse_with_spans(lexer::lex_with_spans("(+ 1 2)\n  )")).unwrap_err();
+        assert_eq!(errors, vec![Diagnostic {
+            error: CompilerError::UnmatchedClosing(Box::new(Token::Closing { line: 2, col: 3 })),
+            span: Some(Span { start: Position { line: 2, col: 3 }, end: Position { line: 2, col: 4 } }),
+        }]);
+    }
+
+    #[test]
+    fn forms_one_at_a_time() {
+        let taken = std::cell::Cell::new(0);
+        let tokens = lexer::lex_with_spans("(a b) (c #\\bad) 'd (e").into_iter().inspect(|_| taken.set(taken.get() + 1));
+        let mut forms = forms(tokens);
+        assert_eq!(forms.next().map(|f| f.unwrap().expr.to_string()), Some(s("(a b)")));
+        assert_eq!(taken.get(), 4);
+        let errors = forms.next().unwrap().unwrap_err();
+        assert!(matches!(errors[..], [Diagnostic { error: CompilerError::InvalidToken(_), .. }]), "{errors:?}");
+        assert_eq!(forms.next().map(|f| f.unwrap().expr.to_string()), Some(s("(quote d)")));
+        assert_eq!(forms.next().unwrap().unwrap_err()[0].error, CompilerError::UnexpectedEof);
+        assert!(forms.next().is_none());
+    }
+
+    #[test]
+    fn unclosed_list_points_at_opening() {
+        let errors = parse_with_spans(lexer::lex_with_spans("(+ 1 2)
+(define (f x)
+  (+ x 1)")).unwrap_err();
+        assert_eq!(errors, vec![Diagnostic {
+            error: CompilerError::UnexpectedEof,
+            span: Some(Span { start: Position { line: 2, col: 1 }, end: Position { line: 2, col: 2 } }),
+        }]);
+        assert_eq!(compile("'(1 ."), Err(vec![CompilerError::UnexpectedEof]));
+    }
+
+    #[test]
+    fn top_level_atoms() {
+        let ast = compile("42 x \"str\" true").unwrap();
+        assert_eq!(ast, vec![
+            SExpression::Number(Number::Int(42)),
+            SExpression::Identifier(s("x")),
+            SExpression::String("str".into()),
+            SExpression::Boolean(true),
+        ]);
+    }
+
+    #[test]
+    fn reports_every_error() {
+        let errors = compile("(a . b c) (+ 1 2)) (f (g . ) 1)\n(list ') (h").unwrap_err();
+        assert_eq!(errors, vec![
+            CompilerError::InvalidToken(Box::new(Token::Identifier { line: 1, col: 8, v: s("c") })),
+            CompilerError::UnmatchedClosing(Box::new(Token::Closing { line: 1, col: 18 })),
+            CompilerError::InvalidToken(Box::new(Token::Closing { line: 1, col: 28 })),
+            CompilerError::InvalidToken(Box::new(Token::Closing { line: 2, col: 8 })),
+            CompilerError::UnexpectedEof,
+        ]);
+    }
+
+    #[test]
+    fn invalid_tokens_inside_list_do_not_stop_parsing() {
+        let errors = compile("(a b \"oops)").unwrap_err();
+        assert_eq!(errors, vec![
+            CompilerError::InvalidToken(Box::new(Token::Invalid { line: 1, col: 6, v: s("\"oops)"), reason: lexer::InvalidReason::UnterminatedString })),
+            CompilerError::UnexpectedEof,
+        ]);
+        assert_eq!(errors[0].to_string(), "unterminated string: \"oops)");
+        assert_eq!(compile("(+ 1.2.3)").unwrap_err()[0].to_string(), "bad number: 1.2.3");
+    }
+}
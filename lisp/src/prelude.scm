@@ -0,0 +1,24 @@
+; derived forms, loaded into every evaluator before the program runs
+
+(define-syntax when
+  (syntax-rules ()
+    ((_ test body1 body ...) (if test (begin body1 body ...) (begin)))))
+
+(define-syntax unless
+  (syntax-rules ()
+    ((_ test body1 body ...) (if test (begin) (begin body1 body ...)))))
+
+(define-syntax let*
+  (syntax-rules ()
+    ((_ () body1 body ...) (let () body1 body ...))
+    ((_ ((name value) rest ...) body1 body ...) (let ((name value)) (let* (rest ...) body1 body ...)))))
+
+; (guard (e clause...) body...) - if the body raises, the clauses are tried like cond with e bound
+; to the raised value. When none of them matches, the value is raised again. catch and raise are the
+; builtins, a program defining its own doesn't change guard
+(define-syntax guard
+  (syntax-rules (else)
+    ((_ (var clause ... (else result1 result ...)) body1 body ...)
+     (catch (lambda () body1 body ...) (lambda (var) (cond clause ... (else result1 result ...)))))
+    ((_ (var clause ...) body1 body ...)
+     (catch (lambda () body1 body ...) (lambda (var) (cond clause ... (else (raise var))))))))
@@ -0,0 +1,409 @@
+use std::rc::Rc;
+
+use crate::{
+    diagnostics::Diagnostic,
+    lexer::{self, lex_with_spans, Position},
+    pair,
+    parser::{parse_with_spans, SExpression},
+};
+
+// width the REPL and lisp fmt print to
+pub const WIDTH: usize = 80;
+
+// how strings and characters are printed
+#[derive(Clone, Copy, PartialEq)]
+enum Style {
+    // the way they are written in the source, so the output reads back as the same value
+    Write,
+    // their contents, for output meant for people
+    Display,
+}
+
+// prints values on a single line. Vectors that contain themselves are printed as #<cycle> the second time
+struct Writer {
+    style: Style,
+    // (quote x) is printed as 'x
+    abbreviate: bool,
+    // vectors being printed, innermost last
+    open: Vec<usize>,
+}
+
+impl Writer {
+    fn new(style: Style, abbreviate: bool) -> Self {
+        Self { style, abbreviate, open: vec![] }
+    }
+
+    fn write(&mut self, e: &SExpression, out: &mut String) {
+        match e {
+            SExpression::Void => out.push_str("#<void>"),
+            SExpression::Number(n) => out.push_str(&n.to_string()),
+            SExpression::Boolean(b) => out.push_str(&b.to_string()),
+            SExpression::String(s) if self.style == Style::Display => out.push_str(s),
+            SExpression::String(s) => out.push_str(&lexer::escape(s)),
+            SExpression::Char(c) if self.style == Style::Display => out.push(*c),
+            SExpression::Char(c) => out.push_str(&lexer::write_char(*c)),
+            SExpression::Identifier(id) => out.push_str(id),
+            SExpression::List(v) if self.abbreviate && is_quote(v) => {
+                out.push('\'');
+                self.write(&v[1], out);
+            },
+            SExpression::List(v) => {
+                out.push('(');
+                self.join(v, out);
+                out.push(')');
+            },
+            SExpression::DottedList(v, tail) => {
+                out.push('(');
+                self.join(v, out);
+                out.push_str(" . ");
+                self.write(tail, out);
+                out.push(')');
+            },
+            SExpression::Pair(_) => self.write(&pair::flatten(e), out),
+            SExpression::Vector(v) if self.open.contains(&address(v)) => out.push_str("#<cycle>"),
+            SExpression::Vector(v) => {
+                self.open.push(address(v));
+                out.push_str("#(");
+                self.join(&v.borrow(), out);
+                out.push(')');
+                self.open.pop();
+            },
+            SExpression::HashTable(t) => out.push_str(&format!("#<hash-table {}>", t.borrow().len())),
+            SExpression::Lambda(l) => out.push_str(&format!("#<lambda ({})>", l.params().join(" "))),
+            SExpression::Builtin(b) => out.push_str(&format!("#<builtin {}>", b.name)),
+            SExpression::Condition(c) => {
+                out.push_str("#<error ");
+                out.push_str(&lexer::escape(&c.message));
+                for irritant in &c.irritants {
+                    out.push(' ');
+                    self.write(irritant, out);
+                }
+                out.push('>');
+            },
+        }
+    }
+
+    fn join(&mut self, v: &[SExpression], out: &mut String) {
+        for (i, e) in v.iter().enumerate() {
+            if i > 0 {
+                out.push(' ');
+            }
+            self.write(e, out);
+        }
+    }
+
+    fn flat(&mut self, e: &SExpression) -> String {
+        let mut out = String::new();
+        self.write(e, &mut out);
+        out
+    }
+}
+
+fn address<T>(rc: &Rc<T>) -> usize {
+    Rc::as_ptr(rc) as *const () as usize
+}
+
+fn is_quote(v: &[SExpression]) -> bool {
+    matches!(v, [SExpression::Identifier(id), _] if id == "quote")
+}
+
+// the value as it's written in the source, strings with quotes and escapes
+pub fn write(e: &SExpression) -> String {
+    Writer::new(Style::Write, false).flat(e)
+}
+
+// the value for people to read, strings and characters without quotes, at any depth
+pub fn display(e: &SExpression) -> String {
+    Writer::new(Style::Display, false).flat(e)
+}
+
+// writes the value over several lines if it's longer than width. Forms with a body like define
+// and let keep their first arguments on the first line and indent the body by two, the arguments
+// of other calls are lined up under the first one
+pub fn pretty(e: &SExpression, width: usize) -> String {
+    let mut out = String::new();
+    Pretty { width, writer: Writer::new(Style::Write, true) }.print(e, &mut out);
+    out
+}
+
+struct Pretty {
+    width: usize,
+    writer: Writer,
+}
+
+impl Pretty {
+    fn print(&mut self, e: &SExpression, out: &mut String) {
+        let flat = self.writer.flat(e);
+        if column(out) + flat.chars().count() <= self.width {
+            out.push_str(&flat);
+            return;
+        }
+        match e {
+            SExpression::List(v) if is_quote(v) => {
+                out.push('\'');
+                self.print(&v[1], out);
+            },
+            SExpression::List(v) if !v.is_empty() => self.list("(", v, None, out),
+            SExpression::DottedList(v, tail) => self.list("(", v, Some(tail), out),
+            SExpression::Pair(_) => self.print(&pair::flatten(e), out),
+            SExpression::Vector(rc) if !rc.borrow().is_empty() && !self.writer.open.contains(&address(rc)) => {
+                self.writer.open.push(address(rc));
+                self.list("#(", &rc.borrow(), None, out);
+                self.writer.open.pop();
+            },
+            _ => out.push_str(&flat),
+        }
+    }
+
+    fn list(&mut self, open: &str, v: &[SExpression], tail: Option<&SExpression>, out: &mut String) {
+        let start = column(out);
+        out.push_str(open);
+        let inner = start + open.len();
+        let call = match (open, &v[0]) {
+            ("(", SExpression::Identifier(name)) => Some(name.as_str()),
+            _ => None,
+        };
+
+        match (call, call.and_then(|name| head_arguments(name, v))) {
+            // (let ((x 1))
+            //   body)
+            (_, Some(n)) => {
+                let n = n.min(v.len() - 1);
+                self.print(&v[0], out);
+                for e in &v[1..=n] {
+                    out.push(' ');
+                    self.print(e, out);
+                }
+                for e in &v[n + 1..] {
+                    newline(out, start + 2);
+                    self.print(e, out);
+                }
+            },
+            // (f a
+            //    b)
+            (Some(name), None) if v.len() > 1 && inner + name.len() < self.width / 2 => {
+                self.print(&v[0], out);
+                out.push(' ');
+                let align = column(out);
+                self.lines(&v[1..], align, out);
+            },
+            // elements that are all atoms fill the lines, lists get a line each
+            _ if v.iter().all(is_atom) => self.fill(v, inner, out),
+            _ => self.lines(v, inner, out),
+        }
+        if let Some(tail) = tail {
+            newline(out, inner);
+            out.push_str(". ");
+            self.print(tail, out);
+        }
+        out.push(')');
+    }
+
+    // one element per line, all starting at column align
+    fn lines(&mut self, v: &[SExpression], align: usize, out: &mut String) {
+        for (i, e) in v.iter().enumerate() {
+            if i > 0 {
+                newline(out, align);
+            }
+            self.print(e, out);
+        }
+    }
+
+    fn fill(&mut self, v: &[SExpression], align: usize, out: &mut String) {
+        for (i, e) in v.iter().enumerate() {
+            let flat = self.writer.flat(e);
+            if i > 0 {
+                // the closing paren has to fit too
+                if column(out) + 1 + flat.chars().count() + 1 > self.width {
+                    newline(out, align);
+                } else {
+                    out.push(' ');
+                }
+            }
+            out.push_str(&flat);
+        }
+    }
+}
+
+// special forms that keep their first n arguments on the line of their name, the rest is a body
+fn head_arguments(name: &str, v: &[SExpression]) -> Option<usize> {
+    match name {
+        "begin" => Some(0),
+        "define" | "lambda" | "let*" | "letrec" | "when" | "unless" | "case" | "define-syntax" | "syntax-rules" | "guard" => Some(1),
+        // named let
+        "let" if matches!(v.get(1), Some(SExpression::Identifier(_))) => Some(2),
+        "let" => Some(1),
+        "do" | "module" => Some(2),
+        _ => None,
+    }
+}
+
+fn is_atom(e: &SExpression) -> bool {
+    !matches!(e, SExpression::List(_) | SExpression::DottedList(..) | SExpression::Pair(_) | SExpression::Vector(_))
+}
+
+fn column(out: &str) -> usize {
+    out.chars().rev().take_while(|&c| c != '\n').count()
+}
+
+fn newline(out: &mut String, indent: usize) {
+    out.push('\n');
+    out.push_str(&" ".repeat(indent));
+}
+
+// formats a program with pretty. Comments between top level forms and single blank lines are
+// kept, a form that contains a comment is left as it was
+pub fn format_source(source: &str, width: usize) -> Result<String, Vec<Diagnostic>> {
+    let tokens = lex_with_spans(source);
+    let forms = parse_with_spans(tokens.clone())?;
+    let text = Text::new(source);
+
+    let mut out = String::new();
+    let mut last = 0;
+    for form in &forms {
+        let (start, end) = (text.offset(form.spans.span.start), text.offset(form.spans.span.end));
+        gap(&mut out, &text.slice(last, start), true);
+
+        // comments inside the form don't show up as tokens, only as text between them
+        let inside = tokens.iter()
+            .map(|(_, span)| (text.offset(span.start), text.offset(span.end)))
+            .filter(|&(s, e)| s >= start && e <= end)
+            .collect::<Vec<_>>();
+        let commented = inside.windows(2).any(|w| !text.slice(w[0].1, w[1].0).trim().is_empty());
+        if commented {
+            out.push_str(&text.slice(start, end));
+        } else {
+            out.push_str(&pretty(&form.expr, width));
+        }
+        last = end;
+    }
+    gap(&mut out, &text.slice(last, text.chars.len()), false);
+    if !out.is_empty() {
+        out.push('\n');
+    }
+    Ok(out)
+}
+
+// text between two forms: a comment on the line of the previous form stays there, other comments
+// get a line of their own and blank lines are collapsed to one. Ends with the separator for the next form
+fn gap(out: &mut String, text: &str, next_form: bool) {
+    let mut lines = text.split('\n').collect::<Vec<_>>();
+    // the line the next form starts on
+    let last = if lines.len() > 1 { lines.pop() } else { None };
+    let first = lines.remove(0).trim();
+    if !first.is_empty() {
+        if !out.is_empty() {
+            out.push(' ');
+        }
+        out.push_str(first);
+    }
+    let mut blank = false;
+    for line in lines.into_iter().chain(last.filter(|l| !l.trim().is_empty())) {
+        if line.trim().is_empty() {
+            blank = true;
+            continue;
+        }
+        separate(out, blank);
+        out.push_str(line.trim_end());
+        blank = false;
+    }
+    if next_form {
+        separate(out, blank);
+    }
+}
+
+fn separate(out: &mut String, blank: bool) {
+    if !out.is_empty() {
+        out.push('\n');
+        if blank {
+            out.push('\n');
+        }
+    }
+}
+
+// source as characters, positions are counted in characters
+struct Text {
+    chars: Vec<char>,
+    // offset of the first character of every line
+    lines: Vec<usize>,
+}
+
+impl Text {
+    fn new(source: &str) -> Self {
+        let chars = source.chars().collect::<Vec<_>>();
+        let lines = std::iter::once(0)
+            .chain(chars.iter().enumerate().filter(|(_, &c)| c == '\n').map(|(i, _)| i + 1))
+            .collect();
+        Self { chars, lines }
+    }
+
+    fn offset(&self, position: Position) -> usize {
+        (self.lines[position.line - 1] + position.col - 1).min(self.chars.len())
+    }
+
+    fn slice(&self, start: usize, end: usize) -> String {
+        self.chars[start..end].iter().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{lexer::lex, parser::parse};
+
+    use super::*;
+
+    fn read(input: &str) -> SExpression {
+        parse(lex(input)).unwrap().remove(0)
+    }
+
+    #[test]
+    fn write_and_display() {
+        let e = read(r#"("a\n" #\b (c . "d") #(#\space "e"))"#);
+        assert_eq!(write(&e), r#"("a\n" #\b (c . "d") #(#\space "e"))"#);
+        assert_eq!(display(&e), "(a\n b (c . d) #(  e))");
+        assert_eq!(write(&read("'(quote x)")), "(quote (quote x))");
+    }
+
+    #[test]
+    fn cycles_are_printed_once() {
+        let v = SExpression::Vector(Default::default());
+        if let SExpression::Vector(rc) = &v {
+            rc.borrow_mut().extend([SExpression::from(1), v.clone()]);
+        }
+        assert_eq!(write(&v), "#(1 #<cycle>)");
+        assert_eq!(pretty(&v, 5), "#(1\n  #<cycle>)");
+        // break the cycle so the test doesn't leak
+        if let SExpression::Vector(rc) = &v {
+            rc.borrow_mut().clear();
+        }
+    }
+
+    #[test]
+    fn short_values_stay_on_one_line() {
+        assert_eq!(pretty(&read("(define (f x) (* x 'x))"), 80), "(define (f x) (* x 'x))");
+    }
+
+    #[test]
+    fn long_values_are_indented() {
+        let e = read("(define (fact n) (if (= n 0) 1 (* n (fact (- n 1)))))");
+        assert_eq!(pretty(&e, 30), "(define (fact n)\n  (if (= n 0)\n      1\n      (* n (fact (- n 1)))))");
+        let e = read("(let loop ((i 0) (acc '())) (if (= i 3) acc (loop (+ i 1) (cons i acc))))");
+        assert_eq!(pretty(&e, 40), "(let loop ((i 0) (acc '()))\n  (if (= i 3)\n      acc\n      (loop (+ i 1) (cons i acc))))");
+        let e = read("'(1 2 3 4 5 6 7 8 9 10 11 12)");
+        assert_eq!(pretty(&e, 16), "'(1 2 3 4 5 6 7\n  8 9 10 11 12)");
+        let e = read("#((a b c) (d e f))");
+        assert_eq!(pretty(&e, 12), "#((a b c)\n  (d e f))");
+    }
+
+    #[test]
+    fn format_programs() {
+        let source = "; counts down\n(define (count n) (if (= n 0) 'done (count (- n 1)))) ; recursive\n\n\n\n(count 3)\n(define (g) ; keeps\n  1)\n";
+        let formatted = format_source(source, 30).unwrap();
+        assert_eq!(formatted, "; counts down\n(define (count n)\n  (if (= n 0)\n      'done\n      (count (- n 1)))) ; recursive\n\n(count 3)\n(define (g) ; keeps\n  1)\n");
+        // the same program, formatted only once
+        assert_eq!(parse(lex(&formatted)), parse(lex(source)));
+        assert_eq!(format_source(&formatted, 30).unwrap(), formatted);
+        assert_eq!(format_source("", 80).unwrap(), "");
+        assert!(format_source("(define x", 80).is_err());
+    }
+}
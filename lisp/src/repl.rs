@@ -0,0 +1,404 @@
+use std::{cell::RefCell, env, path::{Path, PathBuf}, rc::Rc};
+
+use rustyline::{error::ReadlineError, DefaultEditor};
+
+use lisp::{diagnostics::{run_file, run_source}, lexer::{Incremental, Token}, limits, printer, Backend, Evaluator, SExpression};
+
+use crate::debugger::{Debugger, Hook};
+
+const HISTORY_FILE: &str = ".lisp_history";
+
+pub fn run(backend: Backend, trace: bool) {
+    println!("Welcome to Lisp interpreter");
+    println!("Type 'quit' to exit, ':env' to list definitions, ':load <file>' to run a file, ':reset' to start over");
+    println!("':break <name>' and ':step <expression>' start the debugger, ':trace' logs every evaluation");
+
+    let mut editor = match DefaultEditor::new() {
+        Ok(e) => e,
+        Err(error) => {
+            println!("could not start the line editor: {error}");
+            return;
+        }
+    };
+    let history = history_path();
+    // there is no history on the first run
+    let _ = editor.load_history(&history);
+
+    let mut repl = Repl::new(backend);
+    repl.set_stack_size(limits::STACK_SIZE);
+    if trace {
+        repl.debugger.borrow_mut().toggle_trace();
+    }
+    loop {
+        let prompt = if repl.buffer.is_empty() { "> " } else { "... " };
+        match editor.readline(prompt) {
+            Ok(line) => {
+                if repl.buffer.is_empty() && line.trim() == "quit" {
+                    break;
+                }
+                if let Some(input) = repl.feed(&line) {
+                    let _ = editor.add_history_entry(input.as_str());
+                    for out in repl.execute(&input) {
+                        println!("{out}");
+                    }
+                }
+            },
+            // ctrl+c drops the expression being typed
+            Err(ReadlineError::Interrupted) => repl.discard(),
+            Err(ReadlineError::Eof) => break,
+            Err(error) => {
+                println!("error reading input: {error}");
+                break;
+            }
+        }
+    }
+
+    if let Err(error) = editor.save_history(&history) {
+        println!("could not save history to {}: {error}", history.display());
+    }
+    println!("Goodbye")
+}
+
+fn history_path() -> PathBuf {
+    match env::var_os("HOME") {
+        Some(home) => PathBuf::from(home).join(HISTORY_FILE),
+        None => PathBuf::from(HISTORY_FILE),
+    }
+}
+
+struct Repl {
+    evaluator: Evaluator,
+    // lines of an expression that is not complete yet
+    buffer: String,
+    input: Input,
+    // kept when the environment is reset
+    debugger: Rc<RefCell<Debugger>>,
+    // stack of the thread the repl runs on, every evaluator it makes is told about it
+    stack_size: Option<usize>,
+}
+
+impl Repl {
+    fn new(backend: Backend) -> Self {
+        Self::with_debugger(backend, Debugger::new())
+    }
+
+    fn with_debugger(backend: Backend, debugger: Debugger) -> Self {
+        Self { evaluator: Evaluator::with_backend(backend), buffer: String::new(), input: Input::default(), debugger: Rc::new(RefCell::new(debugger)), stack_size: None }
+    }
+
+    fn set_stack_size(&mut self, bytes: usize) {
+        self.stack_size = Some(bytes);
+        self.evaluator.set_stack_size(bytes);
+    }
+
+    // collects lines until parentheses are balanced, then returns the whole input
+    fn feed(&mut self, line: &str) -> Option<String> {
+        if !self.buffer.is_empty() {
+            self.buffer.push('\n');
+        }
+        self.buffer.push_str(line);
+        self.input.add_line(line);
+
+        if self.buffer.trim().is_empty() {
+            self.discard();
+            return None;
+        }
+        if self.input.is_incomplete() {
+            return None;
+        }
+        self.input = Input::default();
+        Some(std::mem::take(&mut self.buffer))
+    }
+
+    // drops the expression being typed
+    fn discard(&mut self) {
+        self.buffer.clear();
+        self.input = Input::default();
+    }
+
+    // returns lines to print
+    fn execute(&mut self, input: &str) -> Vec<String> {
+        let trimmed = input.trim();
+        if let Some(command) = trimmed.strip_prefix(':') {
+            return self.meta_command(command);
+        }
+
+        self.attach_debugger();
+        let report = run_source(&mut self.evaluator, "<repl>", input);
+        // stepping ends with the input it was started for
+        self.debugger.borrow_mut().resume();
+        report.results
+            .into_iter()
+            .filter(|r| *r != SExpression::Void)
+            .map(|r| printer::pretty(&r, printer::WIDTH))
+            .chain(report.errors)
+            .collect()
+    }
+
+    fn meta_command(&mut self, command: &str) -> Vec<String> {
+        let (name, arg) = match command.split_once(char::is_whitespace) {
+            Some((name, arg)) => (name, arg.trim()),
+            None => (command, ""),
+        };
+
+        match (name, arg) {
+            ("env", "") => self.evaluator.globals()
+                .into_iter()
+                .filter_map(|name| self.evaluator.lookup(&name).map(|v| format!("{name} = {v}")))
+                .collect(),
+            ("reset", "") => {
+                self.evaluator = Evaluator::with_backend(self.evaluator.backend());
+                if let Some(bytes) = self.stack_size {
+                    self.evaluator.set_stack_size(bytes);
+                }
+                vec!["environment reset".to_string()]
+            },
+            ("load", "") => vec!["usage: :load <file>".to_string()],
+            ("load", file) => self.load(file),
+            ("break" | "step" | "trace", _) if self.evaluator.backend() == Backend::Vm => {
+                vec!["the debugger needs the tree walker, start without --vm".to_string()]
+            },
+            ("break", "") => {
+                let debugger = self.debugger.borrow();
+                let breakpoints = debugger.breakpoints().cloned().collect::<Vec<_>>();
+                if breakpoints.is_empty() {
+                    vec!["no breakpoints".to_string()]
+                } else {
+                    vec![format!("breakpoints: {}", breakpoints.join(" "))]
+                }
+            },
+            ("break", name) => {
+                self.debugger.borrow_mut().add_breakpoint(name);
+                vec![format!("breakpoint on {name}")]
+            },
+            ("delete", "") => vec!["usage: :delete <name>".to_string()],
+            ("delete", name) => if self.debugger.borrow_mut().remove_breakpoint(name) {
+                vec![format!("removed breakpoint on {name}")]
+            } else {
+                vec![format!("no breakpoint on {name}")]
+            },
+            ("step", "") => vec!["usage: :step <expression>".to_string()],
+            ("step", input) => {
+                self.debugger.borrow_mut().step();
+                self.execute(input)
+            },
+            ("trace", "") => if self.debugger.borrow_mut().toggle_trace() {
+                vec!["tracing on".to_string()]
+            } else {
+                vec!["tracing off".to_string()]
+            },
+            _ => vec![format!("unknown command :{command}")],
+        }
+    }
+
+    // the debugger only sees expressions while it has something to do, everything runs slower with it
+    fn attach_debugger(&mut self) {
+        if self.evaluator.backend() == Backend::TreeWalker && self.debugger.borrow().is_active() {
+            self.evaluator.set_observer(Hook(self.debugger.clone()));
+        } else {
+            self.evaluator.clear_observer();
+        }
+    }
+
+    fn load(&mut self, file_name: &str) -> Vec<String> {
+        self.attach_debugger();
+        match run_file(&mut self.evaluator, Path::new(file_name)) {
+            Ok(errors) if errors.is_empty() => vec![format!("loaded {file_name}")],
+            Ok(errors) => errors,
+            Err(error) => vec![format!("error opening file {file_name}: {error}")],
+        }
+    }
+}
+
+// tells whether the lines typed so far are complete, every line is lexed once
+#[derive(Default)]
+struct Input {
+    lexer: Incremental,
+    // opening minus closing parens
+    depth: isize,
+    // the last token is a quote
+    quoted: bool,
+}
+
+impl Input {
+    fn add_line(&mut self, line: &str) {
+        // the newline ends a word at the end of the line
+        for (t, _) in self.lexer.feed(&format!("{line}\n")) {
+            match t {
+                Token::Opening { .. } | Token::VectorOpening { .. } => self.depth += 1,
+                Token::Closing { .. } => self.depth -= 1,
+                _ => {},
+            }
+            self.quoted = matches!(t, Token::Quote { .. });
+        }
+    }
+
+    // more opening than closing parens, an unterminated string or block comment or a dangling quote
+    fn is_incomplete(&self) -> bool {
+        self.depth > 0 || self.quoted || self.lexer.is_pending()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::debugger::tests::scripted;
+
+    use super::*;
+
+    fn run(repl: &mut Repl, lines: &[&str]) -> Vec<String> {
+        let mut out = vec![];
+        for line in lines {
+            if let Some(input) = repl.feed(line) {
+                out.extend(repl.execute(&input));
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn long_results_are_pretty_printed() {
+        let mut repl = Repl::new(Backend::TreeWalker);
+        let out = run(&mut repl, &["'(define (f x) (if (zero? x) \"a string that is long enough to wrap around\" (f (- x 1))))"]);
+        assert_eq!(out, vec!["(define (f x)\n  (if (zero? x) \"a string that is long enough to wrap around\" (f (- x 1))))"]);
+    }
+
+    #[test]
+    fn keeps_definitions_between_lines() {
+        let mut repl = Repl::new(Backend::TreeWalker);
+        let out = run(&mut repl, &["(define x 5)", "(define (sq a) (* a a))", "(sq x)"]);
+        assert_eq!(out, vec!["25"]);
+    }
+
+    #[test]
+    fn multi_line_input() {
+        let mut repl = Repl::new(Backend::TreeWalker);
+        assert_eq!(repl.feed("(define (f x)"), None);
+        assert_eq!(repl.feed("  (+ x"), None);
+        let input = repl.feed("1))").unwrap();
+        assert_eq!(input, "(define (f x)\n  (+ x\n1))");
+        assert!(repl.execute(&input).is_empty());
+
+        let out = run(&mut repl, &["(list (f 1)", "'(a b))", "(list \"multi", "line\")"]);
+        assert_eq!(out, vec!["(2 (a b))", "(\"multi\\nline\")"]);
+    }
+
+    #[test]
+    fn bad_escape_is_not_incomplete() {
+        let mut repl = Repl::new(Backend::TreeWalker);
+        assert_eq!(repl.feed(r#"(display "\q")"#), Some(r#"(display "\q")"#.to_string()));
+        assert_eq!(repl.feed(r#"(display "ab"#), None);
+    }
+
+    #[test]
+    fn block_comment_continues_input() {
+        let mut repl = Repl::new(Backend::TreeWalker);
+        assert_eq!(repl.feed("(+ 1 #| comment"), None);
+        assert_eq!(repl.feed("(not code |# 2)"), Some("(+ 1 #| comment\n(not code |# 2)".to_string()));
+        assert_eq!(repl.feed("; just a comment"), Some("; just a comment".to_string()));
+    }
+
+    #[test]
+    fn interrupt_drops_partial_input() {
+        let mut repl = Repl::new(Backend::TreeWalker);
+        assert_eq!(repl.feed("(list \"a"), None);
+        repl.discard();
+        assert_eq!(repl.feed("(list 1)"), Some("(list 1)".to_string()));
+    }
+
+    #[test]
+    fn vector_continues_input() {
+        let mut repl = Repl::new(Backend::TreeWalker);
+        assert_eq!(repl.feed("(vector-length #(1"), None);
+        assert_eq!(repl.feed("2))"), Some("(vector-length #(1\n2))".to_string()));
+    }
+
+    #[test]
+    fn several_expressions_on_one_line() {
+        let mut repl = Repl::new(Backend::TreeWalker);
+        let out = run(&mut repl, &["(+ 1 2) (define y 3) (* y y)"]);
+        assert_eq!(out, vec!["3", "9"]);
+    }
+
+    #[test]
+    fn errors_do_not_end_session() {
+        let mut repl = Repl::new(Backend::TreeWalker);
+        let out = run(&mut repl, &["(define x 1)", "(+ x y)", "(/ 1 0)", "(+ x 1)"]);
+        assert_eq!(out, vec![
+            "error: unknown symbol: y\n --> <repl>:1:6\n  |\n1 | (+ x y)\n  |      ^",
+            "error: division by zero\n --> <repl>:1:1\n  |\n1 | (/ 1 0)\n  | ^^^^^^^",
+            "2",
+        ]);
+    }
+
+    #[test]
+    fn env_command() {
+        let mut repl = Repl::new(Backend::TreeWalker);
+        let out = run(&mut repl, &["(define answer 42)", ":env"]);
+        assert!(out.contains(&"answer = 42".to_string()));
+        assert!(out.contains(&"car = #<builtin car>".to_string()));
+    }
+
+    #[test]
+    fn reset_command() {
+        let mut repl = Repl::new(Backend::TreeWalker);
+        let out = run(&mut repl, &["(define x 1)", ":reset", "(+ x 1)"]);
+        assert_eq!(out[0], "environment reset");
+        assert!(out[1].starts_with("error: unknown symbol: x"));
+    }
+
+    #[test]
+    fn reset_keeps_backend() {
+        let mut repl = Repl::new(Backend::Vm);
+        let out = run(&mut repl, &[":reset", "(define (f x) (* x 2))", "(f 4)"]);
+        assert_eq!(out, vec!["environment reset", "8"]);
+        assert_eq!(repl.evaluator.backend(), Backend::Vm);
+    }
+
+    #[test]
+    fn load_command() {
+        let path = env::temp_dir().join("lisp_repl_load_test.scm");
+        fs::write(&path, "(define (triple x) (* 3 x))\n(define base 2)").unwrap();
+
+        let mut repl = Repl::new(Backend::TreeWalker);
+        let out = run(&mut repl, &[&format!(":load {}", path.display()), "(triple base)"]);
+        assert_eq!(out, vec![format!("loaded {}", path.display()), "6".to_string()]);
+
+        let out = run(&mut repl, &[":load /does/not/exist.scm"]);
+        assert!(out[0].starts_with("error opening file /does/not/exist.scm"));
+    }
+
+    #[test]
+    fn debugger_commands() {
+        let (debugger, out) = scripted(&["f", "c"]);
+        let mut repl = Repl::with_debugger(Backend::TreeWalker, debugger);
+        let lines = run(&mut repl, &[":break", ":break sq", ":break", "(define (sq x) (* x x))", "(sq 4)", ":delete sq", ":delete sq", "(sq 5)"]);
+        assert_eq!(lines, vec![
+            "no breakpoints", "breakpoint on sq", "breakpoints: sq", "16", "removed breakpoint on sq", "no breakpoint on sq", "25",
+        ]);
+        assert_eq!(out.text(), "[1] (sq 4)\nx = 4\n");
+    }
+
+    #[test]
+    fn step_command() {
+        let (debugger, out) = scripted(&["s", "n", "n"]);
+        let mut repl = Repl::with_debugger(Backend::TreeWalker, debugger);
+        let lines = run(&mut repl, &[":step (+ 1 (* 2 3))", "(+ 1 1)"]);
+        assert_eq!(lines, vec!["7", "2"]);
+        // stepping ends with the expression
+        assert_eq!(out.text(), "[1] (+ 1 (* 2 3))\n[2] +\n[2] 1\n[2] (* 2 3)\n");
+    }
+
+    #[test]
+    fn debugger_needs_the_tree_walker() {
+        let mut repl = Repl::new(Backend::Vm);
+        assert_eq!(run(&mut repl, &[":break f"]), vec!["the debugger needs the tree walker, start without --vm"]);
+    }
+
+    #[test]
+    fn unknown_command() {
+        let mut repl = Repl::new(Backend::TreeWalker);
+        assert_eq!(run(&mut repl, &[":foo"]), vec!["unknown command :foo"]);
+    }
+}
@@ -0,0 +1,177 @@
+use std::{collections::HashMap, fmt, hash::{Hash, Hasher}, mem};
+
+use crate::{number::Number, pair, parser::{CompilerError, SExpression}};
+
+// keys are compared by value like equal?. Entries are kept in insertion order, so iterating
+// gives the same order every run. Removing an entry moves the last one into its place
+#[derive(Default, PartialEq)]
+pub struct HashTable {
+    index: HashMap<Key, usize>,
+    entries: Vec<(SExpression, SExpression)>,
+}
+
+impl HashTable {
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn get(&self, key: &SExpression) -> Result<Option<&SExpression>, CompilerError> {
+        let key = Key::new(key.clone())?;
+        Ok(self.index.get(&key).map(|&i| &self.entries[i].1))
+    }
+
+    // returns whether the key is new
+    pub fn insert(&mut self, key: SExpression, value: SExpression) -> Result<bool, CompilerError> {
+        let hashed = Key::new(key.clone())?;
+        match self.index.get(&hashed) {
+            Some(&i) => {
+                self.entries[i].1 = value;
+                Ok(false)
+            },
+            None => {
+                self.index.insert(hashed, self.entries.len());
+                self.entries.push((key, value));
+                Ok(true)
+            },
+        }
+    }
+
+    pub fn remove(&mut self, key: &SExpression) -> Result<Option<SExpression>, CompilerError> {
+        let i = match self.index.remove(&Key::new(key.clone())?) {
+            Some(i) => i,
+            None => return Ok(None),
+        };
+        let (_, value) = self.entries.swap_remove(i);
+        if let Some((moved, _)) = self.entries.get(i) {
+            self.index.insert(Key(moved.clone()), i);
+        }
+        Ok(Some(value))
+    }
+
+    pub fn entries(&self) -> &[(SExpression, SExpression)] {
+        &self.entries
+    }
+}
+
+// the index is left out, its order changes from run to run
+impl fmt::Debug for HashTable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map().entries(self.entries.iter().map(|(k, v)| (k, v))).finish()
+    }
+}
+
+// value that can be hashed. Mutable values and procedures can't be keys
+#[derive(Debug)]
+struct Key(SExpression);
+
+impl Key {
+    fn new(e: SExpression) -> Result<Self, CompilerError> {
+        if is_hashable(&e) {
+            Ok(Key(e))
+        } else {
+            Err(CompilerError::InvalidList(e))
+        }
+    }
+}
+
+fn is_hashable(e: &SExpression) -> bool {
+    match e {
+        SExpression::Void | SExpression::Number(_) | SExpression::Boolean(_) | SExpression::String(_) | SExpression::Char(_) | SExpression::Identifier(_) => true,
+        SExpression::List(_) | SExpression::DottedList(..) | SExpression::Pair(_) => {
+            let mut items = pair::iter(e);
+            items.by_ref().all(is_hashable) && items.tail().is_none_or(is_hashable)
+        },
+        _ => false,
+    }
+}
+
+// equal values have to hash the same, so 0.0 and -0.0 do. NaN is never equal to itself and can't be found
+fn hash<H: Hasher>(e: &SExpression, state: &mut H) {
+    match e {
+        // a list hashes the same whether it was read or built from pairs
+        SExpression::List(_) | SExpression::DottedList(..) | SExpression::Pair(_) => {
+            let mut items = pair::iter(e);
+            items.by_ref().for_each(|e| hash(e, state));
+            if let Some(tail) = items.tail() {
+                hash(tail, state);
+            }
+            return;
+        },
+        _ => mem::discriminant(e).hash(state),
+    }
+    match e {
+        SExpression::Number(n) => {
+            mem::discriminant(n).hash(state);
+            match n {
+                Number::Int(i) => i.hash(state),
+                Number::Big(b) => b.hash(state),
+                Number::Rational(r) => r.hash(state),
+                Number::Float(f) => (if *f == 0.0 { 0.0 } else { *f }).to_bits().hash(state),
+            }
+        },
+        SExpression::Boolean(b) => b.hash(state),
+        SExpression::String(s) => s.hash(state),
+        SExpression::Identifier(s) => s.hash(state),
+        SExpression::Char(c) => c.hash(state),
+        _ => {},
+    }
+}
+
+impl Hash for Key {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        hash(&self.0, state);
+    }
+}
+
+impl PartialEq for Key {
+    fn eq(&self, other: &Self) -> bool {
+        self.0 == other.0
+    }
+}
+
+impl Eq for Key {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn int(i: i64) -> SExpression {
+        SExpression::Number(Number::Int(i))
+    }
+
+    #[test]
+    fn keys_are_compared_by_value() {
+        let mut table = HashTable::default();
+        let key = SExpression::List(vec![int(1), SExpression::String("a".into())]);
+        assert_eq!(table.insert(key.clone(), int(1)), Ok(true));
+        assert_eq!(table.insert(key.clone(), int(2)), Ok(false));
+        assert_eq!(table.get(&key), Ok(Some(&int(2))));
+        // the same list built from pairs
+        assert_eq!(table.get(&pair::list(vec![int(1), SExpression::String("a".into())])), Ok(Some(&int(2))));
+        // exact and inexact numbers are different keys, the zeros of floats are not
+        table.insert(SExpression::Number(Number::Float(0.0)), int(3)).unwrap();
+        assert_eq!(table.get(&SExpression::Number(Number::Float(-0.0))), Ok(Some(&int(3))));
+        assert_eq!(table.get(&int(0)), Ok(None));
+
+        let vector = SExpression::Vector(Default::default());
+        assert_eq!(table.insert(vector.clone(), int(1)), Err(CompilerError::InvalidList(vector)));
+    }
+
+    #[test]
+    fn removing_keeps_the_index_in_sync() {
+        let mut table = HashTable::default();
+        for i in 0..4 {
+            table.insert(int(i), int(i * 10)).unwrap();
+        }
+        assert_eq!(table.remove(&int(1)), Ok(Some(int(10))));
+        assert_eq!(table.remove(&int(1)), Ok(None));
+        let keys = table.entries().iter().map(|(k, _)| k.to_string()).collect::<Vec<_>>();
+        assert_eq!(keys, vec!["0", "3", "2"]);
+        assert_eq!(table.get(&int(3)), Ok(Some(&int(30))));
+        assert_eq!(table.len(), 3);
+    }
+}
@@ -0,0 +1,192 @@
+use std::rc::Rc;
+
+use crate::{
+    builtins::is_truthy,
+    compiler::{compile, compile_body, Chunk, Op},
+    evaluator::{EnvRef, Evaluator, Lambda},
+    parser::{CompilerError, SExpression},
+};
+
+struct Frame {
+    chunk: Rc<Chunk>,
+    pc: usize,
+    env: EnvRef,
+    // scopes to go back to when the let family is done
+    scopes: Vec<EnvRef>,
+    // call that started this frame, reported when an error has no better context
+    site: Option<(Rc<Chunk>, usize)>,
+}
+
+impl Frame {
+    fn new(chunk: Rc<Chunk>, env: EnvRef, site: Option<(Rc<Chunk>, usize)>) -> Self {
+        Self { chunk, pc: 0, env, scopes: vec![], site }
+    }
+
+    fn source(&self, pc: usize) -> Option<&SExpression> {
+        self.chunk.source(pc).or_else(|| self.site.as_ref().and_then(|(chunk, pc)| chunk.source(*pc)))
+    }
+}
+
+// evaluates a top level expression
+pub fn run(evaluator: &mut Evaluator, e: &SExpression, env: &EnvRef) -> Result<SExpression, CompilerError> {
+    let frame = Frame::new(Rc::new(compile(e)), env.clone(), None);
+    evaluator.enter()?;
+    Vm { evaluator, stack: vec![], frames: vec![frame] }.run()
+}
+
+// calls a lambda from native code
+pub fn call(evaluator: &mut Evaluator, lambda: &Lambda, args: Vec<SExpression>) -> Result<SExpression, CompilerError> {
+    let frame = Frame::new(code(lambda), evaluator.bind(lambda, args)?, None);
+    evaluator.enter()?;
+    Vm { evaluator, stack: vec![], frames: vec![frame] }.run()
+}
+
+fn code(lambda: &Lambda) -> Rc<Chunk> {
+    lambda.code.get_or_init(|| Rc::new(compile_body(&lambda.body))).clone()
+}
+
+struct Vm<'a> {
+    evaluator: &'a mut Evaluator,
+    stack: Vec<SExpression>,
+    frames: Vec<Frame>,
+}
+
+impl Vm<'_> {
+    fn run(mut self) -> Result<SExpression, CompilerError> {
+        loop {
+            let frame = self.frames.last_mut().expect("vm without frames");
+            let pc = frame.pc;
+            let op = frame.chunk.ops[pc];
+            frame.pc += 1;
+
+            match self.evaluator.step().and_then(|_| self.step(op, pc)) {
+                Ok(Some(result)) => return Ok(result),
+                Ok(None) => {},
+                Err(error) => {
+                    for _ in &self.frames {
+                        self.evaluator.leave();
+                    }
+                    // the innermost list that failed, so the error can be pointed at in the source
+                    if self.evaluator.error_context.is_none() {
+                        let frame = self.frames.last().expect("vm without frames");
+                        self.evaluator.error_context = frame.source(pc).cloned();
+                    }
+                    return Err(error);
+                },
+            }
+        }
+    }
+
+    // executes a single op, returns the result once the outermost frame returns
+    fn step(&mut self, op: Op, pc: usize) -> Result<Option<SExpression>, CompilerError> {
+        let frame = self.frames.last_mut().expect("vm without frames");
+        match op {
+            Op::Const(i) => self.stack.push(frame.chunk.constants[i].clone()),
+            Op::Get(i) => {
+                let name = &frame.chunk.names[i];
+                let value = frame.env.borrow().get(name);
+                match value {
+                    Some(v) => self.stack.push(v),
+                    None => return Err(CompilerError::UnknownSymbol(name.clone())),
+                }
+            },
+            Op::Define(i) => {
+                let value = self.stack.pop().expect("vm stack underflow");
+                frame.env.borrow_mut().define(frame.chunk.names[i].clone(), value);
+            },
+            Op::Set(i) => {
+                let value = self.stack.pop().expect("vm stack underflow");
+                let name = &frame.chunk.names[i];
+                if !frame.env.borrow_mut().set(name, value) {
+                    return Err(CompilerError::UnknownSymbol(name.clone()));
+                }
+            },
+            Op::Pop => {
+                self.stack.pop();
+            },
+            Op::Jump(target) => frame.pc = target,
+            Op::JumpIfFalse(target) => {
+                if !is_truthy(&self.stack.pop().expect("vm stack underflow")) {
+                    frame.pc = target;
+                }
+            },
+            Op::JumpIfFalseOrPop(target) | Op::JumpIfTrueOrPop(target) => {
+                let top = self.stack.last().expect("vm stack underflow");
+                if is_truthy(top) == matches!(op, Op::JumpIfTrueOrPop(_)) {
+                    frame.pc = target;
+                } else {
+                    self.stack.pop();
+                }
+            },
+            Op::Match(i) => {
+                let key = self.stack.last().expect("vm stack underflow");
+                let found = matches!(&frame.chunk.constants[i], SExpression::List(datums) if datums.contains(key));
+                self.stack.push(SExpression::Boolean(found));
+            },
+            Op::Closure(i) => {
+                let lambda = Lambda::new(&frame.chunk.lambdas[i], &frame.env);
+                self.stack.push(SExpression::Lambda(lambda));
+            },
+            Op::CheckCallable => {
+                if !matches!(self.stack.last(), Some(SExpression::Lambda(_) | SExpression::Builtin(_))) {
+                    let call = frame.source(pc).cloned().unwrap_or(SExpression::Void);
+                    return Err(CompilerError::InvalidList(call));
+                }
+            },
+            Op::Call(argc) => return self.call(argc, pc, false),
+            Op::TailCall(argc) => return self.call(argc, pc, true),
+            Op::Return => return Ok(self.ret()),
+            Op::EnterScope => {
+                let scope = self.evaluator.extend(&frame.env);
+                frame.scopes.push(std::mem::replace(&mut frame.env, scope));
+            },
+            Op::LeaveScope(n) => {
+                let depth = frame.scopes.len() - n;
+                frame.env = frame.scopes.drain(depth..).next().expect("scope was not entered");
+            },
+            Op::Invalid(i) => return Err(CompilerError::InvalidList(frame.chunk.constants[i].clone())),
+        }
+        Ok(None)
+    }
+
+    fn pop(&mut self) -> SExpression {
+        self.stack.pop().expect("vm stack underflow")
+    }
+
+    fn call(&mut self, argc: usize, pc: usize, tail: bool) -> Result<Option<SExpression>, CompilerError> {
+        let args = self.stack.split_off(self.stack.len() - argc);
+        let procedure = self.pop();
+        let caller = self.frames.last().expect("vm without frames");
+        let site = Some((caller.chunk.clone(), pc));
+
+        match procedure {
+            SExpression::Lambda(lambda) => {
+                let frame = Frame::new(code(&lambda), self.evaluator.bind(&lambda, args)?, site);
+                if tail {
+                    *self.frames.last_mut().expect("vm without frames") = frame;
+                } else {
+                    self.evaluator.enter()?;
+                    self.frames.push(frame);
+                }
+                Ok(None)
+            },
+            SExpression::Builtin(b) => {
+                let result = b.call(self.evaluator, args)?;
+                self.stack.push(result);
+                // a builtin in tail position returns from the current frame right away
+                Ok(if tail { self.ret() } else { None })
+            },
+            other => Err(CompilerError::InvalidList(other)),
+        }
+    }
+
+    // the value on top of the stack is the result of the current frame
+    fn ret(&mut self) -> Option<SExpression> {
+        self.frames.pop();
+        self.evaluator.leave();
+        if self.frames.is_empty() {
+            return Some(self.pop());
+        }
+        None
+    }
+}
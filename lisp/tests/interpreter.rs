@@ -0,0 +1,156 @@
+use lisp::{Arity, Backend, CompilerError, Interpreter, Limit, Limits, Number, SExpression};
+
+#[test]
+fn eval_str_returns_last_value() {
+    let mut lisp = Interpreter::new();
+    assert_eq!(lisp.eval_str("(define (sq x) (* x x)) (sq 4)"), Ok(SExpression::from(16)));
+    assert_eq!(lisp.eval_str("(sq 5)"), Ok(SExpression::from(25)));
+    assert_eq!(lisp.eval_str(""), Ok(SExpression::Void));
+}
+
+#[test]
+fn errors_point_into_the_source() {
+    let mut lisp = Interpreter::new();
+    let error = lisp.eval_str("(define x 1)\n(+ x y)").unwrap_err();
+    assert_eq!(error.error(), &CompilerError::UnknownSymbol("y".to_string()));
+    assert_eq!(error.to_string(), "2:6: unknown symbol: y");
+    // definitions made before the error are kept
+    assert_eq!(lisp.get_global("x"), Some(SExpression::from(1)));
+
+    let error = lisp.eval_str("(+ 1 2))\n)").unwrap_err();
+    assert_eq!(error.to_string(), "1:8: unmatched )\n2:1: unmatched )");
+    assert_eq!(error.render("main.scm", "(+ 1 2))\n)").lines().next(), Some("error: unmatched )"));
+}
+
+#[test]
+fn call_procedures() {
+    let mut lisp = Interpreter::new();
+    lisp.eval_str("(define (greet name times) (string-append \"hi \" name (number->string times)))").unwrap();
+    let greeting = lisp.call("greet", vec!["bob".into(), 3.into()]).unwrap();
+    assert_eq!(String::try_from(greeting), Ok("hi bob3".to_string()));
+
+    assert_eq!(lisp.call("+", vec![1.into(), 2.5.into()]), Ok(SExpression::from(3.5)));
+    assert_eq!(lisp.call("nope", vec![]).unwrap_err().error(), &CompilerError::UnknownSymbol("nope".to_string()));
+    assert!(matches!(lisp.call("greet", vec![]).unwrap_err().error(), CompilerError::ArityMismatch { .. }));
+}
+
+#[test]
+fn globals() {
+    let mut lisp = Interpreter::new();
+    lisp.set_global("limit", 10);
+    lisp.set_global("names", vec!["a", "b"]);
+    assert_eq!(lisp.eval_str("(* limit 2)"), Ok(SExpression::from(20)));
+    assert_eq!(lisp.eval_str("(car (cdr names))"), Ok(SExpression::from("b")));
+
+    lisp.eval_str("(define total (+ limit 0.5))").unwrap();
+    assert_eq!(lisp.get_global("total").map(f64::try_from), Some(Ok(10.5)));
+    assert_eq!(lisp.get_global("missing"), None);
+}
+
+#[test]
+fn conversions() {
+    assert_eq!(i64::try_from(SExpression::from(7)), Ok(7));
+    assert_eq!(f64::try_from(SExpression::from(7)), Ok(7.0));
+    assert_eq!(bool::try_from(SExpression::from(true)), Ok(true));
+    assert_eq!(Vec::<i64>::try_from(SExpression::from(vec![1, 2, 3])), Ok(vec![1, 2, 3]));
+    assert_eq!(SExpression::from(()), SExpression::Void);
+    assert_eq!(SExpression::from(Number::Int(1)), SExpression::from(1i64));
+
+    assert_eq!(i64::try_from(SExpression::from("1")), Err(CompilerError::InvalidList(SExpression::from("1"))));
+    assert!(Vec::<String>::try_from(SExpression::from(vec![1])).is_err());
+    let big = Interpreter::new().eval_str("(* 9223372036854775807 2)").unwrap();
+    assert!(i64::try_from(big).is_err());
+}
+
+#[test]
+fn capture_output() {
+    let mut lisp = Interpreter::new();
+    assert_eq!(lisp.take_output(), "");
+    lisp.capture_output();
+    lisp.eval_str("(display \"hello\") (newline) (display 42)").unwrap();
+    assert_eq!(lisp.take_output(), "hello\n42");
+    assert_eq!(lisp.take_output(), "");
+}
+
+#[test]
+fn register_host_functions() {
+    let mut lisp = Interpreter::new();
+    lisp.register("twice", Arity::Exact(1), |evaluator, mut args| {
+        let f = args.remove(0);
+        let once = evaluator.apply_procedure(f.clone(), vec![0.into()])?;
+        evaluator.apply_procedure(f, vec![once])
+    });
+    assert_eq!(lisp.eval_str("(twice (lambda (x) (+ x 5)))"), Ok(SExpression::from(10)));
+}
+
+#[test]
+fn backends_agree() {
+    let program = "(define (fact n) (if (= n 0) 1 (* n (fact (- n 1))))) (when true (fact 20))";
+    let tree = Interpreter::with_backend(Backend::TreeWalker).eval_str(program);
+    let vm = Interpreter::with_backend(Backend::Vm).eval_str(program);
+    assert_eq!(tree, Ok(SExpression::from(2432902008176640000i64)));
+    assert_eq!(vm, tree);
+}
+
+#[test]
+fn sandboxing() {
+    for backend in [Backend::TreeWalker, Backend::Vm] {
+        let mut lisp = Interpreter::with_backend(backend);
+        lisp.set_limits(Limits { steps: Some(100_000), depth: Some(200), allocations: Some(10_000) });
+        lisp.disable_io();
+        let error = lisp.eval_str("(define (spin) (spin))\n(spin)").unwrap_err();
+        assert_eq!(error.error(), &CompilerError::LimitExceeded(Limit::Steps));
+        assert_eq!(error.to_string(), "2:1: step limit exceeded");
+        lisp.eval_str("(define (deep n) (if (= n 0) 0 (+ 1 (deep (- n 1)))))").unwrap();
+        assert_eq!(lisp.call("deep", vec![1000.into()]).unwrap_err().error(), &CompilerError::LimitExceeded(Limit::Depth));
+        let error = lisp.eval_str("(define (grow s) (grow (string-append s s))) (grow \"x\")").unwrap_err();
+        assert_eq!(error.error(), &CompilerError::LimitExceeded(Limit::Allocations));
+        assert_eq!(lisp.eval_str("(display 1)").unwrap_err().error(), &CompilerError::UnknownSymbol("display".to_string()));
+        // every call starts with a fresh budget
+        assert_eq!(lisp.call("deep", vec![100.into()]), Ok(SExpression::from(100)));
+    }
+}
+
+#[test]
+fn raised_errors() {
+    let mut lisp = Interpreter::new();
+    let error = lisp.eval_str("(define x 1)\n(error \"bad value:\" x)").unwrap_err();
+    assert_eq!(error.to_string(), "2:1: bad value: 1");
+    match error.error() {
+        CompilerError::Raised(SExpression::Condition(c)) => {
+            assert_eq!(c.message, "bad value:");
+            assert_eq!(c.irritants, vec![SExpression::from(1)]);
+        },
+        other => panic!("unexpected error {other:?}"),
+    }
+    assert_eq!(lisp.eval_str("(guard (e (true (error-object-message e))) (error \"oops\"))"), Ok(SExpression::from("oops")));
+}
+
+#[test]
+fn import_modules_from_search_paths() {
+    let dir = std::env::temp_dir().join(format!("lisp-interpreter-modules-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("greetings.scm"), "(module greetings (export greet) (define (greet name) (string-append \"hi \" name)))").unwrap();
+
+    let mut lisp = Interpreter::new();
+    assert_eq!(lisp.eval_str("(import greetings)").unwrap_err().to_string(), "1:1: unknown module: greetings");
+    lisp.add_search_path(&dir);
+    assert_eq!(lisp.eval_str("(import greetings) (greet \"bob\")"), Ok(SExpression::from("hi bob")));
+}
+
+#[test]
+fn cyclic_garbage_is_collected() {
+    let mut lisp = Interpreter::with_backend(Backend::Vm);
+    // every call leaves a vector that contains itself and a frame that contains a closure over itself
+    lisp.eval_str("
+        (define (cycles n)
+          (define v (vector n))
+          (vector-set! v 0 v)
+          (define (again) (cycles (- n 1)))
+          (if (> n 0) (again) (hash-ref (gc-stats) 'live)))").unwrap();
+    let mut live = |n: i64| i64::try_from(lisp.call("cycles", vec![n.into()]).unwrap()).unwrap();
+    let small = live(1_000);
+    let large = live(1_000_000);
+    // only the cycles made since the last collection are still around
+    assert!(large < small + 20_000, "{small} {large}");
+}